@@ -0,0 +1,135 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Build script helper generating the file table for `resource_proxy_pingora::embedded`.
+//!
+//! This crate has no dependencies, so that build scripts don’t need to compile Pingora:
+//!
+//! ```no_run
+//! // main() of build.rs
+//! resource_proxy_pingora_embed::generate("assets", "assets.rs").unwrap();
+//! ```
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Generates a file table for all files in the `dir` directory (recursively), meant to be called
+/// from a build script. The table will be written to file `name` in the `OUT_DIR` directory and
+/// can be included via `resource_proxy_pingora::include_embedded!` macro.
+///
+/// Pre-compressed variants of the files like `file.txt.gz` are regular files and will be embedded
+/// as well if present.
+pub fn generate(dir: impl AsRef<Path>, name: &str) -> Result<(), Error> {
+    let out_dir = std::env::var_os("OUT_DIR").ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "OUT_DIR environment variable not set, not running in a build script?",
+        )
+    })?;
+
+    let dir = dir.as_ref().canonicalize()?;
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let code = generate_table(&dir)?;
+    fs::write(Path::new(&out_dir).join(name), code)
+}
+
+fn generate_table(dir: &Path) -> Result<String, Error> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    let mut code = String::from("&[\n");
+    for file in files {
+        let rel_path = file.strip_prefix(dir).map_err(|_| ErrorKind::InvalidData)?;
+        let (Some(path), Some(file_str)) = (rel_path.to_str(), file.to_str()) else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("cannot embed file with non-Unicode name {file:?}"),
+            ));
+        };
+        let path = path.replace(std::path::MAIN_SEPARATOR, "/");
+
+        let meta = file.metadata()?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+        let hash = content_hash(&fs::read(&file)?);
+
+        println!("cargo:rerun-if-changed={file_str}");
+        let _ = writeln!(
+            code,
+            "    ::resource_proxy_pingora::embedded::EmbeddedFile {{ path: {:?}, \
+             data: include_bytes!({:?}), modified: {modified}, hash: {:?} }},",
+            format!("/{path}"),
+            file_str,
+            format!("{hash:016x}"),
+        );
+    }
+    code.push(']');
+
+    Ok(code)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    println!("cargo:rerun-if-changed={}", dir.display());
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let meta = path.metadata()?;
+        if meta.is_dir() {
+            collect_files(&path, files)?;
+        } else if meta.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash, stable across builds and platforms
+fn content_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_generation() {
+        let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        dir.push("..");
+        dir.push("testdata");
+        dir.push("root");
+        let dir = dir.canonicalize().unwrap();
+
+        let code = generate_table(&dir).unwrap();
+        assert!(code.starts_with("&[\n"));
+        assert!(code.contains(&format!(
+            "path: \"/file.txt\", data: include_bytes!({:?}), ",
+            dir.join("file.txt").to_str().unwrap()
+        )));
+        assert!(code.contains("path: \"/large_precompressed.txt.gz\""));
+        assert!(code.contains(&format!("hash: \"{:016x}\"", content_hash(b"Hi!\n"))));
+    }
+}
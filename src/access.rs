@@ -0,0 +1,118 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access rules for hidden files and deny patterns, checked before accessing the storage
+
+use http::status::StatusCode;
+use std::path::{Component, Path};
+
+use crate::configuration::HiddenPolicy;
+use crate::path::normalize_uri;
+use crate::path_rules::PathRules;
+
+/// Access rules applying to URI paths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AccessRules {
+    hidden: HiddenPolicy,
+    deny: PathRules<String>,
+}
+
+impl AccessRules {
+    /// Creates the rules from the configured policy and deny patterns, invalid patterns are an
+    /// error.
+    pub(crate) fn new(
+        hidden: HiddenPolicy,
+        deny: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Self, String> {
+        let deny = deny
+            .into_iter()
+            .map(|pattern| {
+                let pattern = pattern.as_ref();
+                if pattern.starts_with('/') {
+                    Ok((Some(pattern.to_owned()), pattern.to_owned()))
+                } else {
+                    Err(format!("Deny pattern has to start with /: {pattern}"))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let deny = PathRules::new(deny).map_err(|err| err.to_string())?;
+        Ok(Self { hidden, deny })
+    }
+
+    /// Checks a request path. If access is denied, the response status and a description of the
+    /// rule that matched are returned.
+    pub(crate) fn check(&self, uri_path: &str) -> Option<(StatusCode, String)> {
+        if self.hidden != HiddenPolicy::Serve {
+            let rel_path = normalize_uri(uri_path, Path::new("")).unwrap_or_default();
+            let hidden = rel_path.components().any(|component| {
+                matches!(component, Component::Normal(name)
+                    if name.as_encoded_bytes().starts_with(b"."))
+            });
+            if hidden {
+                let status = if self.hidden == HiddenPolicy::NotFound {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::FORBIDDEN
+                };
+                return Some((status, format!("hidden: {}", status.as_u16())));
+            }
+        }
+
+        self.deny
+            .find(uri_path)
+            .map(|pattern| (StatusCode::FORBIDDEN, format!("deny: {pattern}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn rules() {
+        let rules = AccessRules::new(HiddenPolicy::Serve, ["/**/*.bak", "/**/.git/**"]).unwrap();
+        assert_eq!(rules.check("/file.txt"), None);
+        assert_eq!(rules.check("/.env"), None);
+        assert_eq!(
+            rules.check("/file.bak"),
+            Some((StatusCode::FORBIDDEN, "deny: /**/*.bak".to_owned()))
+        );
+        assert!(rules.check("/dir/sub/file.bak").is_some());
+        assert!(rules.check("/dir/sub/file%2ebak").is_some());
+        assert!(rules.check("/.git/config").is_some());
+        assert!(rules.check("/.git").is_some());
+        assert!(rules.check("/dir/.git/objects/ab").is_some());
+        assert!(rules.check("/dir/../.git/config").is_some());
+        assert_eq!(rules.check("/dir/.gitignore"), None);
+
+        let rules = AccessRules::new(HiddenPolicy::NotFound, [] as [&str; 0]).unwrap();
+        assert_eq!(
+            rules.check("/dir/.env"),
+            Some((StatusCode::NOT_FOUND, "hidden: 404".to_owned()))
+        );
+        assert_eq!(
+            rules
+                .check("/%2Ewell-known/security.txt")
+                .map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(rules.check("/file.txt"), None);
+        assert_eq!(rules.check("/"), None);
+
+        assert!(AccessRules::new(HiddenPolicy::Serve, ["/[invalid"]).is_err());
+        assert!(AccessRules::new(HiddenPolicy::Serve, ["**/*.bak"]).is_err());
+    }
+}
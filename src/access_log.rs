@@ -0,0 +1,350 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access log written during the `logging` phase
+//!
+//! The log file is opened in append mode. On Unix systems, it is reopened after a `SIGHUP` signal
+//! so that it can be rotated by tools like logrotate.
+
+use http::header;
+use log::warn;
+use pingora::proxy::Session;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::configuration::AccessLogFormat;
+use crate::handler::StaticFilesCtx;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Information logged about a request
+#[derive(Debug)]
+struct Entry<'a> {
+    client: Option<String>,
+    user: Option<&'a str>,
+    time: SystemTime,
+    request: String,
+    status: Option<u16>,
+    bytes: usize,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    path: Option<&'a Path>,
+    encoding: Option<&'static str>,
+    range: Option<(u64, u64)>,
+    duration: f64,
+}
+
+impl Entry<'_> {
+    /// Produces a log line in the common or combined log format. The additional fields are
+    /// appended: resolved path, encoding, range and duration in seconds.
+    fn to_clf(&self, combined: bool) -> String {
+        let mut line = format!(
+            "{} - {} [{}] \"{}\" {} ",
+            self.client.as_deref().unwrap_or("-"),
+            escape(self.user.unwrap_or("-")),
+            clf_time(self.time),
+            escape(&self.request),
+            self.status.map_or("-".to_owned(), |status| status.to_string()),
+        );
+        if self.bytes > 0 {
+            let _ = write!(line, "{}", self.bytes);
+        } else {
+            line.push('-');
+        }
+        if combined {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\"",
+                escape(self.referer.unwrap_or("-")),
+                escape(self.user_agent.unwrap_or("-"))
+            );
+        }
+
+        let path = self.path.map(|path| path.to_string_lossy());
+        let _ = write!(
+            line,
+            " \"{}\" {} {} {:.6}",
+            escape(path.as_deref().unwrap_or("-")),
+            self.encoding.unwrap_or("-"),
+            self.range.map_or("-".to_owned(), |(start, end)| format!("{start}-{end}")),
+            self.duration
+        );
+        line
+    }
+
+    /// Produces a log line as JSON object.
+    fn to_json(&self) -> String {
+        let string = |value: Option<&str>| match value {
+            Some(value) => format!("\"{}\"", escape_json(value)),
+            None => "null".to_owned(),
+        };
+        let time = self.time.duration_since(UNIX_EPOCH).map_or(0.0, |time| time.as_secs_f64());
+        let path = self.path.map(|path| path.to_string_lossy());
+        format!(
+            "{{\"time\":{time:.3},\"client\":{},\"user\":{},\"request\":{},\"status\":{},\
+             \"bytes\":{},\"referer\":{},\"user_agent\":{},\"path\":{},\"encoding\":{},\
+             \"range\":{},\"duration\":{:.6}}}",
+            string(self.client.as_deref()),
+            string(self.user),
+            string(Some(&self.request)),
+            self.status.map_or("null".to_owned(), |status| status.to_string()),
+            self.bytes,
+            string(self.referer),
+            string(self.user_agent),
+            string(path.as_deref()),
+            string(self.encoding),
+            self.range.map_or("null".to_owned(), |(start, end)| format!("[{start},{end}]")),
+            self.duration
+        )
+    }
+}
+
+/// Formats the time as `10/Oct/2000:13:55:36 +0000`, always using UTC.
+fn clf_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since epoch, see https://howardhinnant.github.io/date_algorithms.html
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[(month - 1) as usize],
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Escapes quotes, backslashes and non-printable characters for quoted log fields.
+fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(result, "\\x{:02X}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Escapes a string for use in JSON.
+fn escape_json(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// An access log file
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    file: Mutex<File>,
+    reopen: Arc<AtomicBool>,
+}
+
+impl AccessLog {
+    /// Opens the log file for appending. On Unix systems, a `SIGHUP` handler is registered to
+    /// reopen the file.
+    pub(crate) fn open(path: &Path, format: AccessLogFormat) -> Result<Self, Error> {
+        let reopen = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen.clone())?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            file: Mutex::new(Self::open_file(path)?),
+            reopen,
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<File, Error> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Writes an entry for a request processed by the handler. `client` is the client address as
+    /// determined by the handler, taking trusted proxies into account.
+    pub(crate) fn log(&self, session: &Session, client: Option<IpAddr>, ctx: &StaticFilesCtx) {
+        let req = session.req_header();
+        let header_value = |name| req.headers.get(name).and_then(|value| value.to_str().ok());
+        let entry = Entry {
+            client: client.map(|ip| ip.to_string()),
+            user: ctx.user.as_deref(),
+            time: SystemTime::now(),
+            request: format!("{} {} {:?}", req.method, req.uri, req.version),
+            status: session.response_written().map(|header| header.status.as_u16()),
+            bytes: session.body_bytes_sent(),
+            referer: header_value(header::REFERER),
+            user_agent: header_value(header::USER_AGENT),
+            path: ctx.path.as_deref(),
+            encoding: ctx.encoding.map(|algorithm| algorithm.name()),
+            range: ctx.range,
+            duration: ctx.received.elapsed().as_secs_f64(),
+        };
+
+        let mut line = match self.format {
+            AccessLogFormat::Common => entry.to_clf(false),
+            AccessLogFormat::Combined => entry.to_clf(true),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+        line.push('\n');
+        self.write_line(&line);
+    }
+
+    /// Writes a line to the log file, reopening it first if requested.
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            match Self::open_file(&self.path) {
+                Ok(new_file) => *file = new_file,
+                Err(err) => warn!("failed reopening access log {:?}: {err}", self.path),
+            }
+        }
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("failed writing to access log {:?}: {err}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::time::Duration;
+    use test_log::test;
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            client: Some("192.0.2.1".to_owned()),
+            user: Some("alice"),
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            request: "GET /file.txt HTTP/1.1".to_owned(),
+            status: Some(206),
+            bytes: 100,
+            referer: Some("https://example.com/"),
+            user_agent: Some("Agent \"quoted\""),
+            path: Some(Path::new("/srv/www/file.txt.br")),
+            encoding: Some("br"),
+            range: Some((0, 99)),
+            duration: 0.0015,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            entry().to_clf(false),
+            "192.0.2.1 - alice [10/Oct/2000:13:55:36 +0000] \"GET /file.txt HTTP/1.1\" 206 100 \
+             \"/srv/www/file.txt.br\" br 0-99 0.001500"
+        );
+        assert_eq!(
+            entry().to_clf(true),
+            "192.0.2.1 - alice [10/Oct/2000:13:55:36 +0000] \"GET /file.txt HTTP/1.1\" 206 100 \
+             \"https://example.com/\" \"Agent \\\"quoted\\\"\" \
+             \"/srv/www/file.txt.br\" br 0-99 0.001500"
+        );
+        assert_eq!(
+            entry().to_json(),
+            "{\"time\":971186136.000,\"client\":\"192.0.2.1\",\"user\":\"alice\",\
+             \"request\":\"GET /file.txt HTTP/1.1\",\"status\":206,\"bytes\":100,\
+             \"referer\":\"https://example.com/\",\"user_agent\":\"Agent \\\"quoted\\\"\",\
+             \"path\":\"/srv/www/file.txt.br\",\"encoding\":\"br\",\"range\":[0,99],\
+             \"duration\":0.001500}"
+        );
+
+        let empty = Entry {
+            client: None,
+            user: None,
+            status: None,
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            path: None,
+            encoding: None,
+            range: None,
+            ..entry()
+        };
+        assert_eq!(
+            empty.to_clf(true),
+            "- - - [10/Oct/2000:13:55:36 +0000] \"GET /file.txt HTTP/1.1\" - - \"-\" \"-\" \
+             \"-\" - - 0.001500"
+        );
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(
+            clf_time(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "29/Feb/2000:00:00:00 +0000"
+        );
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = dir.path().join("access.log.1");
+        let log = AccessLog::open(&path, AccessLogFormat::Common).unwrap();
+
+        log.write_line("first\n");
+        fs::rename(&path, &rotated).unwrap();
+        log.write_line("second\n");
+
+        // Writing continues to the renamed file until reopening is requested
+        log.reopen.store(true, Ordering::Relaxed);
+        log.write_line("third\n");
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "first\nsecond\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+    }
+}
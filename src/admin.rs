@@ -0,0 +1,302 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Admin API operating a static files handler
+//!
+//! The admin handler shares the caches of the [`StaticFilesHandler`] it was created for. It can
+//! be chained before that handler under a path prefix or bound to a separate listener. All
+//! endpoints require the configured token in an `Authorization: Bearer <token>` header:
+//!
+//! * `GET <prefix>/config`: effective configuration as YAML, `?format=json` for JSON
+//! * `POST <prefix>/purge`: removes cache entries for `?path=/file`, `?prefix=/dir/` or all
+//! * `POST <prefix>/reload`: reads redirect, MIME types and JWT key files again, drops all
+//!   cached state
+//! * `GET <prefix>/stats`: cache size, last reload time and counters as JSON
+
+use async_trait::async_trait;
+use http::{header, status::StatusCode, Method};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use pingora::http::RequestHeader;
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType};
+use serde::Serialize;
+use serde_json::json;
+use std::borrow::Cow;
+use std::time::UNIX_EPOCH;
+
+use crate::configuration::AdminConf;
+use crate::handler::StaticFilesHandler;
+use crate::metrics::counters;
+use crate::request_filter::{RequestFilter, RequestFilterResult};
+use crate::secret::constant_time_eq;
+use crate::standard_response::{data_response, error_response, unauthorized_response};
+
+/// Admin API handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminHandler {
+    prefix: String,
+    token: String,
+    handler: StaticFilesHandler,
+}
+
+impl StaticFilesHandler {
+    /// Creates an admin API handler operating on this handler.
+    pub fn admin(&self, conf: AdminConf) -> Result<AdminHandler, Box<Error>> {
+        if conf.token.is_empty() {
+            return Error::e_explain(ErrorType::InternalError, "Admin API requires a token");
+        }
+
+        Ok(AdminHandler {
+            prefix: conf.prefix.trim_end_matches('/').to_owned(),
+            token: conf.token,
+            handler: self.clone(),
+        })
+    }
+}
+
+impl AdminHandler {
+    /// Checks the bearer token of the request.
+    fn is_authorized(&self, req: &RequestHeader) -> bool {
+        req.headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    /// Processes a request to an endpoint, produces content type and response body.
+    fn process(
+        &self,
+        endpoint: &str,
+        method: &Method,
+        query: Option<&str>,
+    ) -> Result<Result<(&'static str, String), StatusCode>, Box<Error>> {
+        Ok(Ok(match (endpoint, method) {
+            ("/config", &Method::GET | &Method::HEAD) => {
+                let conf = self.handler.conf();
+                if query_param(query, "format").as_deref() == Some("json") {
+                    ("application/json", to_json(conf)?)
+                } else {
+                    let text = serde_yaml::to_string(conf).map_err(|err| {
+                        Error::because(
+                            ErrorType::InternalError,
+                            "Failed serializing configuration",
+                            err,
+                        )
+                    })?;
+                    ("application/yaml", text)
+                }
+            }
+            ("/purge", &Method::POST) => {
+                let purged = if let Some(path) = query_param(query, "path") {
+                    self.handler.purge(|uri| uri == path)
+                } else if let Some(prefix) = query_param(query, "prefix") {
+                    self.handler.purge(|uri| uri.starts_with(prefix.as_ref()))
+                } else {
+                    self.handler.purge(|_| true)
+                };
+                info!("admin API purged {purged} cache entries");
+                ("application/json", to_json(&json!({ "purged": purged }))?)
+            }
+            ("/reload", &Method::POST) => {
+                let purged = self.handler.reload()?;
+                ("application/json", to_json(&json!({ "purged": purged }))?)
+            }
+            ("/stats", &Method::GET | &Method::HEAD) => {
+                let last_reload = self
+                    .handler
+                    .last_reload()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs());
+                let stats = json!({
+                    "last_reload": last_reload,
+                    "cache_entries": self.handler.cache_entries(),
+                    "counters": counters(),
+                });
+                ("application/json", to_json(&stats)?)
+            }
+            ("/config" | "/purge" | "/reload" | "/stats", _) => {
+                return Ok(Err(StatusCode::METHOD_NOT_ALLOWED))
+            }
+            _ => return Ok(Err(StatusCode::NOT_FOUND)),
+        }))
+    }
+}
+
+#[async_trait]
+impl RequestFilter for AdminHandler {
+    type Conf = AdminConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let req = session.req_header();
+        let Some(endpoint) = req
+            .uri
+            .path()
+            .strip_prefix(self.prefix.as_str())
+            .filter(|endpoint| endpoint.is_empty() || endpoint.starts_with('/'))
+        else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let result = if self.is_authorized(req) {
+            Some(self.process(endpoint, &req.method, req.uri.query())?)
+        } else {
+            warn!("rejecting unauthorized admin API request to {}", req.uri.path());
+            None
+        };
+
+        let (content_type, body) = match result {
+            Some(Ok(response)) => response,
+            Some(Err(status)) => {
+                error_response(session, status).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            None => {
+                unauthorized_response(session, "Bearer").await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        };
+
+        data_response(session, StatusCode::OK, content_type, body).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+/// Serializes a response as JSON.
+fn to_json(value: &impl Serialize) -> Result<String, Box<Error>> {
+    serde_json::to_string_pretty(value)
+        .map_err(|err| Error::because(ErrorType::InternalError, "Failed serializing response", err))
+}
+
+/// Extracts a percent-decoded query parameter.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<Cow<'a, str>> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        (key == name).then(|| percent_decode_str(value).decode_utf8_lossy())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use test_log::test;
+
+    use crate::configuration::StaticFilesConf;
+
+    #[test]
+    fn helpers() {
+        assert_eq!(query_param(Some("a=1&path=/x%20y"), "path").as_deref(), Some("/x y"));
+        assert_eq!(query_param(Some("prefix"), "prefix").as_deref(), Some(""));
+        assert_eq!(query_param(Some("prefixes=1"), "prefix"), None);
+        assert_eq!(query_param(None, "path"), None);
+    }
+
+    #[test]
+    fn endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = StaticFilesConf {
+            root: vec![dir.path().to_path_buf()].into(),
+            precompressed: vec![crate::CompressionAlgorithm::Brotli].into(),
+            hidden: crate::HiddenPolicy::NotFound,
+            ..Default::default()
+        };
+        let handler = StaticFilesHandler::try_from(conf).unwrap();
+        assert!(handler.admin(AdminConf::default()).is_err());
+
+        let admin = handler
+            .admin(AdminConf {
+                token: "secret".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let (content_type, yaml) = admin.process("/config", &Method::GET, None).unwrap().unwrap();
+        assert_eq!(content_type, "application/yaml");
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            serde_yaml::from_value::<Vec<PathBuf>>(value["root"].clone()).unwrap(),
+            [dir.path()]
+        );
+        assert_eq!(value["precompressed"][0].as_str(), Some("br"));
+        assert_eq!(value["hidden"].as_str(), Some("404"));
+
+        let (content_type, json) = admin
+            .process("/config", &Method::GET, Some("format=json"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(content_type, "application/json");
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["canonicalize_uri"], json!(true));
+
+        let before = admin.handler.last_reload();
+        let (_, json) = admin.process("/reload", &Method::POST, None).unwrap().unwrap();
+        assert_eq!(json, "{\n  \"purged\": 0\n}");
+        assert!(admin.handler.last_reload() >= before);
+        assert!(handler.last_reload() >= before);
+
+        let (_, json) = admin.process("/stats", &Method::GET, None).unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["cache_entries"], json!(0));
+        assert!(value["counters"].is_object());
+
+        assert_eq!(
+            admin.process("/purge", &Method::GET, None).unwrap(),
+            Err(StatusCode::METHOD_NOT_ALLOWED)
+        );
+        assert_eq!(
+            admin.process("/unknown", &Method::GET, None).unwrap(),
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mime_types = dir.path().join("mime.types");
+        std::fs::write(&mime_types, "types { text/x-custom custom; }").unwrap();
+        let conf = StaticFilesConf {
+            root: vec![dir.path().to_path_buf()].into(),
+            mime_types_file: Some(mime_types.clone()),
+            ..Default::default()
+        };
+        let handler = StaticFilesHandler::try_from(conf).unwrap();
+        let admin = handler
+            .admin(AdminConf {
+                token: "secret".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let before = handler.last_reload();
+        admin.process("/reload", &Method::POST, None).unwrap().unwrap();
+        let reloaded = handler.last_reload();
+        assert!(reloaded >= before);
+
+        // A broken file fails the reload, the previous state is kept
+        std::fs::write(&mime_types, "types { invalid custom; }").unwrap();
+        assert!(admin.process("/reload", &Method::POST, None).is_err());
+        assert_eq!(handler.last_reload(), reloaded);
+    }
+}
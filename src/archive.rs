@@ -0,0 +1,683 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backend serving files directly from ZIP and tar archives
+
+use async_trait::async_trait;
+use flate2::read::DeflateDecoder;
+use log::warn;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::path::normalize_uri;
+use crate::storage::{blocking_reader, EntryKind, EntryStat, RangeReader, Storage};
+
+/// Size of the gzip header produced for deflate-compressed ZIP entries
+const GZIP_HEADER_SIZE: u64 = 10;
+
+/// Size of the gzip trailer produced for deflate-compressed ZIP entries
+const GZIP_TRAILER_SIZE: u64 = 8;
+
+/// Supported archive formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// ZIP archive, entries can be stored or deflate-compressed
+    Zip,
+    /// Uncompressed tar archive
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// Determines the archive format from the file extension if supported.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        if ext.eq_ignore_ascii_case("zip") {
+            Some(Self::Zip)
+        } else if ext.eq_ignore_ascii_case("tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Location of an entry’s data within the archive file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryData {
+    /// Uncompressed data stored at the given offset
+    Stored { offset: u64 },
+    /// Raw deflate data stored at the given offset, produces decompressed data. Ranges are
+    /// produced by decompressing from the start of the data.
+    Deflated { offset: u64, compressed_size: u64 },
+    /// Raw deflate data stored at the given offset, produces data wrapped into gzip format
+    Gzip {
+        offset: u64,
+        compressed_size: u64,
+        crc: u32,
+        size: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ArchiveEntry {
+    stat: EntryStat,
+    data: Option<EntryData>,
+}
+
+/// A part of an entry’s data, used to produce byte ranges
+enum Segment {
+    Bytes(Vec<u8>),
+    File { offset: u64, len: u64 },
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File { len, .. } => *len,
+        }
+    }
+}
+
+/// Storage backend serving files from a ZIP or tar archive without unpacking it.
+///
+/// The archive is indexed once when the storage is created. Stored entries are read directly from
+/// the archive file. Deflate-compressed ZIP entries are decompressed on the fly and additionally
+/// exposed as a `.gz` sibling, so that clients accepting gzip encoding receive the compressed data
+/// as is.
+///
+/// *Note*: Deflate streams cannot be entered in the middle, so a range request for a decompressed
+/// entry inflates everything before the range as well. Large entries that are expected to receive
+/// range requests should be stored uncompressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveStorage {
+    archive: PathBuf,
+    root: PathBuf,
+    entries: BTreeMap<PathBuf, ArchiveEntry>,
+}
+
+impl ArchiveStorage {
+    /// Creates a new storage for the given archive file, building the index of its entries.
+    pub fn new(archive: impl AsRef<Path>, format: ArchiveFormat) -> Result<Self, Error> {
+        let archive = archive.as_ref().canonicalize()?;
+        let mut storage = Self {
+            archive,
+            root: PathBuf::from("/"),
+            entries: BTreeMap::new(),
+        };
+        storage.add_directory(storage.root.clone(), None);
+
+        match format {
+            ArchiveFormat::Zip => storage.index_zip()?,
+            ArchiveFormat::Tar => storage.index_tar()?,
+        }
+        Ok(storage)
+    }
+
+    /// Creates a new storage for the given archive file, determining the format from the file
+    /// extension.
+    pub fn from_path(archive: impl AsRef<Path>) -> Result<Self, Error> {
+        let format = ArchiveFormat::from_path(archive.as_ref()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported archive format: {:?}", archive.as_ref()),
+            )
+        })?;
+        Self::new(archive, format)
+    }
+
+    /// Path to the archive file
+    pub fn archive(&self) -> &Path {
+        &self.archive
+    }
+
+    fn entry_path(&self, name: &Path) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in name.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+
+    fn add_directory(&mut self, path: PathBuf, modified: Option<SystemTime>) {
+        let mut parent = path.parent().map(Path::to_path_buf);
+        self.entries.insert(
+            path,
+            ArchiveEntry {
+                stat: EntryStat {
+                    kind: EntryKind::Directory,
+                    size: 0,
+                    modified,
+                    etag: None,
+                },
+                data: None,
+            },
+        );
+
+        while let Some(path) = parent {
+            if self.entries.contains_key(&path) {
+                break;
+            }
+            parent = path.parent().map(Path::to_path_buf);
+            self.entries.insert(
+                path,
+                ArchiveEntry {
+                    stat: EntryStat {
+                        kind: EntryKind::Directory,
+                        size: 0,
+                        modified: None,
+                        etag: None,
+                    },
+                    data: None,
+                },
+            );
+        }
+    }
+
+    fn add_file(&mut self, path: PathBuf, stat: EntryStat, data: EntryData) {
+        if let Some(parent) = path.parent() {
+            if !self.entries.contains_key(parent) {
+                self.add_directory(parent.to_path_buf(), None);
+            }
+        }
+        self.entries.insert(
+            path,
+            ArchiveEntry {
+                stat,
+                data: Some(data),
+            },
+        );
+    }
+
+    fn index_zip(&mut self) -> Result<(), Error> {
+        let mut zip = ZipArchive::new(File::open(&self.archive)?).map_err(zip_error)?;
+        let mut gzip_variants = Vec::new();
+        for index in 0..zip.len() {
+            let entry = zip.by_index_raw(index).map_err(zip_error)?;
+            let path = match entry.enclosed_name().and_then(|name| self.entry_path(&name)) {
+                Some(path) => path,
+                None => {
+                    warn!("ignoring archive entry with unsafe name {}", entry.name());
+                    continue;
+                }
+            };
+
+            let modified = entry.last_modified().and_then(|datetime| {
+                system_time(
+                    datetime.year(),
+                    datetime.month(),
+                    datetime.day(),
+                    datetime.hour(),
+                    datetime.minute(),
+                    datetime.second(),
+                )
+            });
+
+            if entry.is_dir() {
+                self.add_directory(path, modified);
+                continue;
+            }
+            if !entry.is_file() {
+                warn!("ignoring archive entry {path:?}, not a regular file");
+                continue;
+            }
+            if entry.encrypted() {
+                warn!("ignoring encrypted archive entry {path:?}");
+                continue;
+            }
+
+            let offset = entry.data_start();
+            let crc = entry.crc32();
+            let size = entry.size();
+            let stat = EntryStat {
+                kind: EntryKind::File,
+                size,
+                modified,
+                etag: Some(entry_etag(modified, crc)),
+            };
+            match entry.compression() {
+                CompressionMethod::Stored => {
+                    self.add_file(path, stat, EntryData::Stored { offset });
+                }
+                CompressionMethod::Deflated => {
+                    let compressed_size = entry.compressed_size();
+                    let data = EntryData::Gzip {
+                        offset,
+                        compressed_size,
+                        crc,
+                        size,
+                    };
+                    let gzip_stat = EntryStat {
+                        kind: EntryKind::File,
+                        size: GZIP_HEADER_SIZE + compressed_size + GZIP_TRAILER_SIZE,
+                        modified,
+                        etag: Some(format!("{}-gz", entry_etag(modified, crc))),
+                    };
+                    let mut gzip_name = path.file_name().unwrap_or_default().to_os_string();
+                    gzip_name.push(".gz");
+                    gzip_variants.push((path.with_file_name(gzip_name), gzip_stat, data));
+
+                    self.add_file(
+                        path,
+                        stat,
+                        EntryData::Deflated {
+                            offset,
+                            compressed_size,
+                        },
+                    );
+                }
+                method => {
+                    warn!("ignoring archive entry {path:?}, unsupported compression {method}");
+                }
+            }
+        }
+
+        // Real .gz entries take precedence over the generated ones
+        for (path, stat, data) in gzip_variants {
+            if !self.entries.contains_key(&path) {
+                self.add_file(path, stat, data);
+            }
+        }
+        Ok(())
+    }
+
+    fn index_tar(&mut self) -> Result<(), Error> {
+        let mut tar = tar::Archive::new(File::open(&self.archive)?);
+        for entry in tar.entries()? {
+            let entry = entry?;
+            let name = entry.path()?.into_owned();
+            let path = match self.entry_path(&name) {
+                Some(path) => path,
+                None => {
+                    warn!("ignoring archive entry with unsafe name {name:?}");
+                    continue;
+                }
+            };
+
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let mtime = header.mtime().ok();
+            let modified = mtime.map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+
+            if entry_type.is_dir() {
+                self.add_directory(path, modified);
+                continue;
+            }
+            if !entry_type.is_file() {
+                warn!("ignoring archive entry {path:?}, not a regular file");
+                continue;
+            }
+
+            let offset = entry.raw_file_position();
+            let size = entry.size();
+
+            // tar doesn’t store checksums of the contents, the position within the archive
+            // distinguishes entries with identical timestamp and size
+            let stat = EntryStat {
+                kind: EntryKind::File,
+                size,
+                modified,
+                etag: Some(format!("{:x}-{size:x}-{offset:x}", mtime.unwrap_or(0))),
+            };
+            self.add_file(path, stat, EntryData::Stored { offset });
+        }
+        Ok(())
+    }
+
+    fn entry(&self, path: &Path) -> Result<&ArchiveEntry, Error> {
+        self.entries
+            .get(path)
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    async fn open_segments(
+        &self,
+        segments: Vec<Segment>,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        let mut reader: RangeReader = Box::new(tokio::io::empty());
+        let mut position = 0;
+        for segment in segments {
+            let segment_start = position;
+            let segment_end = position + segment.len();
+            position = segment_end;
+            if segment_end <= start || segment_start > end {
+                continue;
+            }
+
+            let from = start.saturating_sub(segment_start);
+            let to = (end + 1).min(segment_end) - segment_start;
+            let part: RangeReader = match segment {
+                Segment::Bytes(bytes) => {
+                    Box::new(Cursor::new(bytes[from as usize..to as usize].to_vec()))
+                }
+                Segment::File { offset, .. } => {
+                    let mut file = tokio::fs::File::open(&self.archive).await?;
+                    file.seek(SeekFrom::Start(offset + from)).await?;
+                    Box::new(file.take(to - from))
+                }
+            };
+            reader = Box::new(reader.chain(part));
+        }
+        Ok(reader)
+    }
+}
+
+#[async_trait]
+impl Storage for ArchiveStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let path = normalize_uri(uri_path, &self.root)?;
+        self.entry(&path)?;
+        Ok(path)
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        Ok(self.entry(path)?.stat.clone())
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        let entry = self.entry(path)?;
+        if end < start || end >= entry.stat.size {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        match entry.data.ok_or(ErrorKind::InvalidInput)? {
+            EntryData::Stored { offset } => self.open_segments(
+                vec![Segment::File {
+                    offset,
+                    len: entry.stat.size,
+                }],
+                start,
+                end,
+            )
+            .await,
+            EntryData::Deflated {
+                offset,
+                compressed_size,
+            } => {
+                let mut file = File::open(&self.archive)?;
+                Ok(blocking_reader(move || {
+                    file.seek(SeekFrom::Start(offset))?;
+                    let mut decoder = DeflateDecoder::new(file.take(compressed_size));
+                    io::copy(&mut (&mut decoder).take(start), &mut io::sink())?;
+                    Ok(decoder.take(end - start + 1))
+                }))
+            }
+            EntryData::Gzip {
+                offset,
+                compressed_size,
+                crc,
+                size,
+            } => {
+                let header = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+                let mut trailer = Vec::with_capacity(GZIP_TRAILER_SIZE as usize);
+                trailer.extend_from_slice(&crc.to_le_bytes());
+                trailer.extend_from_slice(&(size as u32).to_le_bytes());
+                self.open_segments(
+                    vec![
+                        Segment::Bytes(header),
+                        Segment::File {
+                            offset,
+                            len: compressed_size,
+                        },
+                        Segment::Bytes(trailer),
+                    ],
+                    start,
+                    end,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        if self.entry(path)?.stat.kind != EntryKind::Directory {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        Ok(self
+            .entries
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(|name| name.to_os_string()))
+            .collect())
+    }
+}
+
+fn zip_error(err: zip::result::ZipError) -> Error {
+    match err {
+        zip::result::ZipError::Io(err) => err,
+        err => Error::new(ErrorKind::InvalidData, err),
+    }
+}
+
+fn entry_etag(modified: Option<SystemTime>, crc: u32) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+    format!("{modified:x}-{crc:08x}")
+}
+
+/// Converts a ZIP timestamp (no time zone, assumed to be UTC) into system time.
+fn system_time(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> Option<SystemTime> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds =
+        days * 86400 + i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::read::GzDecoder;
+    use std::io::Write;
+    use test_log::test;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const TEXT: &[u8] = b"Hello, archive! Hello, archive! Hello, archive!";
+
+    async fn read(storage: &ArchiveStorage, path: &Path, start: u64, end: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage
+            .open_range(path, start, end)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    #[test(tokio::test)]
+    async fn zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("bundle.zip");
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("stored.txt", options).unwrap();
+        zip.write_all(TEXT).unwrap();
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("dir/deflated.txt", options).unwrap();
+        zip.write_all(TEXT).unwrap();
+        zip.finish().unwrap();
+
+        let storage = ArchiveStorage::from_path(&archive).unwrap();
+        let len = TEXT.len() as u64;
+
+        let path = storage.resolve("/stored.txt").await.unwrap();
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, len);
+        assert_eq!(read(&storage, &path, 0, len - 1).await, TEXT);
+        assert_eq!(read(&storage, &path, 7, 13).await, b"archive");
+        assert_eq!(
+            storage
+                .open_range(&path, 0, len)
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // Deflated entries are decompressed
+        let path = storage.resolve("/dir/deflated.txt").await.unwrap();
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.size, len);
+        assert_eq!(read(&storage, &path, 0, len - 1).await, TEXT);
+        assert_eq!(read(&storage, &path, 23, 29).await, b"archive");
+
+        // The virtual .gz sibling is a valid gzip file with the compressed data
+        let gz_path = storage.resolve("/dir/deflated.txt.gz").await.unwrap();
+        let gz_stat = storage.stat(&gz_path).await.unwrap();
+        assert_ne!(gz_stat.etag, stat.etag);
+        let gz_data = read(&storage, &gz_path, 0, gz_stat.size - 1).await;
+        assert_eq!(gz_data.len() as u64, gz_stat.size);
+        let mut decoded = Vec::new();
+        GzDecoder::new(gz_data.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, TEXT);
+
+        // Ranges spanning the generated header, the archive data and the generated trailer
+        assert_eq!(read(&storage, &gz_path, 5, 15).await, &gz_data[5..=15]);
+        let end = gz_stat.size - 1;
+        assert_eq!(
+            read(&storage, &gz_path, end - 10, end).await,
+            &gz_data[gz_data.len() - 11..]
+        );
+
+        assert_eq!(
+            storage.list_dir(Path::new("/dir")).await.unwrap(),
+            vec![
+                OsString::from("deflated.txt"),
+                OsString::from("deflated.txt.gz")
+            ]
+        );
+        assert_eq!(
+            storage.resolve("/missing.txt").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            storage.resolve("/../stored.txt").await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("bundle.tar");
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(TEXT.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1700000000);
+        header.set_cksum();
+        tar.append_data(&mut header, "docs/file.txt", TEXT).unwrap();
+        tar.into_inner().unwrap();
+
+        let storage = ArchiveStorage::from_path(&archive).unwrap();
+        let len = TEXT.len() as u64;
+
+        let path = storage.resolve("/docs/file.txt").await.unwrap();
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.size, len);
+        assert_eq!(
+            stat.modified,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        // Data follows the 512 bytes header
+        assert_eq!(stat.etag.as_deref(), Some("6553f100-2f-200"));
+        assert_eq!(read(&storage, &path, 0, len - 1).await, TEXT);
+        assert_eq!(read(&storage, &path, 39, 46).await, b"archive!");
+
+        // No compressed variants for tar entries
+        assert_eq!(
+            storage
+                .resolve("/docs/file.txt.gz")
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+
+        let path = storage.resolve("/docs").await.unwrap();
+        assert_eq!(
+            storage.stat(&path).await.unwrap().kind,
+            EntryKind::Directory
+        );
+        assert_eq!(
+            storage.list_dir(Path::new("/")).await.unwrap(),
+            vec![OsString::from("docs")]
+        );
+    }
+
+    #[test]
+    fn zip_timestamps() {
+        let to_secs = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+
+        assert_eq!(
+            to_secs(system_time(1980, 1, 1, 0, 0, 0).unwrap()),
+            315532800
+        );
+        assert_eq!(
+            to_secs(system_time(2024, 2, 29, 12, 30, 15).unwrap()),
+            1709209815
+        );
+        assert_eq!(system_time(2024, 13, 1, 0, 0, 0), None);
+    }
+}
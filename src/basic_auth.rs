@@ -0,0 +1,448 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP Basic authentication backed by Apache htpasswd files
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use glob::PatternError;
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::spawn_blocking;
+
+use crate::configuration::BasicAuthRule;
+use crate::path_rules::PathRules;
+use crate::secret::constant_time_eq;
+
+/// Hash prefixes of the supported password hash formats, `$apr1$` is handled separately
+const CRYPT_PREFIXES: &[&str] = &["$2a$", "$2b$", "$2y$", "$5$", "$6$", "$1$"];
+
+/// Number of verified credentials to remember, verifying bcrypt hashes is slow by design
+const MAX_VERIFIED: usize = 1000;
+
+/// Minimal time between checks whether an htpasswd file changed
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Characters used by the crypt base64 variant
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Basic authentication rules with patterns compiled
+#[derive(Debug, Default)]
+pub(crate) struct BasicAuthRules {
+    rules: PathRules<BasicAuth>,
+}
+
+impl BasicAuthRules {
+    /// Compiles the rules from the configuration.
+    pub(crate) fn new(
+        rules: impl IntoIterator<Item = BasicAuthRule>,
+    ) -> Result<Self, PatternError> {
+        let rules = PathRules::new(rules.into_iter().map(|rule| {
+            let auth = BasicAuth {
+                realm: rule.realm,
+                htpasswd: Htpasswd::new(rule.htpasswd),
+            };
+            (rule.pattern, auth)
+        }))?;
+        Ok(Self { rules })
+    }
+
+    /// Finds the rule applying to a request path, `None` if the path isn’t protected.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&BasicAuth> {
+        self.rules.find(uri_path)
+    }
+}
+
+/// A path protected by Basic authentication
+#[derive(Debug)]
+pub(crate) struct BasicAuth {
+    realm: String,
+    htpasswd: Htpasswd,
+}
+
+impl BasicAuth {
+    /// Produces the `WWW-Authenticate` header value for `401 Unauthorized` responses.
+    pub(crate) fn challenge(&self) -> String {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("Basic realm=\"{realm}\", charset=\"UTF-8\"")
+    }
+
+    /// Checks the credentials from the `Authorization` header, returns the user name if these
+    /// are valid.
+    pub(crate) async fn authenticate(&self, authorization: Option<&str>) -> Option<String> {
+        let Some((scheme, credentials)) = authorization.and_then(|value| value.split_once(' '))
+        else {
+            debug!("no credentials for realm {}", self.realm);
+            return None;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            debug!("unsupported authorization scheme {scheme}");
+            return None;
+        }
+        let credentials = credentials.trim();
+
+        let decoded = STANDARD.decode(credentials).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+
+        let (hash, stamp) = {
+            let state = self.htpasswd.state();
+            if let Some(user) = state.verified.get(credentials) {
+                return Some(user.clone());
+            }
+            (state.users.get(user).cloned(), state.stamp)
+        };
+
+        // Verify without holding the lock and off the async worker, this can take a while
+        let valid = match hash {
+            Some(hash) => {
+                let password = password.to_owned();
+                spawn_blocking(move || verify_password(&password, &hash))
+                    .await
+                    .unwrap_or(false)
+            }
+            None => false,
+        };
+        if valid {
+            let mut state = self.htpasswd.state();
+            // Don't remember the credentials if the file changed in between
+            if state.stamp == stamp {
+                if state.verified.len() >= MAX_VERIFIED {
+                    state.verified.clear();
+                }
+                state
+                    .verified
+                    .insert(credentials.to_owned(), user.to_owned());
+            }
+            Some(user.to_owned())
+        } else {
+            info!(
+                "authentication failed for user {user} in realm {}",
+                self.realm
+            );
+            None
+        }
+    }
+}
+
+/// Users and password hashes from an htpasswd file
+#[derive(Debug, Default)]
+struct HtpasswdState {
+    /// Time of the last check whether the file changed
+    checked: Option<Instant>,
+    /// Modification time and size of the file when it was read
+    stamp: Option<(Option<SystemTime>, u64)>,
+    /// Password hashes by user name
+    users: HashMap<String, String>,
+    /// Encoded credentials already verified, mapped to the user name
+    verified: HashMap<String, String>,
+}
+
+/// An htpasswd file, reloaded when it changes
+#[derive(Debug)]
+struct Htpasswd {
+    path: PathBuf,
+    state: Mutex<HtpasswdState>,
+}
+
+impl Htpasswd {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns the current state, reloading the file first if it changed. The file is checked
+    /// at most once per [`CHECK_INTERVAL`].
+    fn state(&self) -> MutexGuard<'_, HtpasswdState> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if state
+            .checked
+            .is_some_and(|checked| now.duration_since(checked) < CHECK_INTERVAL)
+        {
+            return state;
+        }
+        state.checked = Some(now);
+
+        let stamp = fs::metadata(&self.path).map(|meta| (meta.modified().ok(), meta.len()));
+        let result = match stamp {
+            Ok(stamp) if state.stamp == Some(stamp) => return state,
+            Ok(stamp) => fs::read_to_string(&self.path).map(|text| (stamp, text)),
+            Err(err) => Err(err),
+        };
+
+        *state = match result {
+            Ok((stamp, text)) => {
+                info!("loading htpasswd file {:?}", self.path);
+                HtpasswdState {
+                    checked: Some(now),
+                    stamp: Some(stamp),
+                    users: parse_htpasswd(&text, &self.path),
+                    verified: HashMap::new(),
+                }
+            }
+            Err(err) => {
+                // Deny everybody rather than keeping outdated credentials
+                warn!("failed reading htpasswd file {:?}: {err}", self.path);
+                HtpasswdState {
+                    checked: Some(now),
+                    ..Default::default()
+                }
+            }
+        };
+        state
+    }
+}
+
+/// Parses the `user:hash` lines of an htpasswd file.
+fn parse_htpasswd(text: &str, path: &Path) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let Some((user, hash)) = line.split_once(':') else {
+                warn!("ignoring invalid line in htpasswd file {path:?}");
+                return None;
+            };
+            if !hash.starts_with("$apr1$")
+                && !CRYPT_PREFIXES.iter().any(|prefix| hash.starts_with(prefix))
+            {
+                warn!("ignoring user {user} in htpasswd file {path:?}, unsupported hash format");
+                return None;
+            }
+            Some((user.to_owned(), hash.to_owned()))
+        })
+        .collect()
+}
+
+/// Verifies a password against a bcrypt, SHA-crypt, MD5-crypt or apr1 hash.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or_default();
+        let expected = md5_crypt(password.as_bytes(), "$apr1$", salt.as_bytes());
+        constant_time_eq(expected.as_bytes(), hash.as_bytes())
+    } else if CRYPT_PREFIXES.iter().any(|prefix| hash.starts_with(prefix)) {
+        pwhash::unix::verify(password, hash)
+    } else {
+        false
+    }
+}
+
+/// Produces an MD5-crypt hash with the given magic prefix, Apache uses `$apr1$` rather than
+/// `$1$`. See <https://httpd.apache.org/docs/2.4/misc/password_encryptions.html>.
+fn md5_crypt(password: &[u8], magic: &str, salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut context = Md5::new()
+        .chain_update(password)
+        .chain_update(magic)
+        .chain_update(salt);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 != 0 {
+            context.update([0u8]);
+        } else {
+            context.update(&password[..1]);
+        }
+        len >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 != 0 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 != 0 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    let mut result = format!("{magic}{}$", String::from_utf8_lossy(salt));
+    let mut encode = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            result.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    let group = |a: usize, b: usize, c: usize| {
+        (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c])
+    };
+    encode(group(0, 6, 12), 4);
+    encode(group(1, 7, 13), 4);
+    encode(group(2, 8, 14), 4);
+    encode(group(3, 9, 15), 4);
+    encode(group(4, 10, 5), 4);
+    encode(u32::from(digest[11]), 2);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    const HTPASSWD: &str = "\
+        # Test users\n\
+        apr:$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0\n\
+        md5:$1$saltsalt$9xy1btjgzLYfb7hivXtC//\n\
+        sha256:$5$saltsalt$0IyaXrmV7.sGNS6tirgqHLqX/G.FBvgkYA.lpPdS5sA\n\
+        sha512:$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77\
+        vwPZN.Pq.H91p5hVO1\n\
+        bcrypt:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n\
+        sha1:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+    ";
+
+    fn credentials(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(
+            md5_crypt(b"secret", "$apr1$", b"saltsalt"),
+            "$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0"
+        );
+        assert_eq!(
+            md5_crypt(b"secret", "$1$", b"saltsalt"),
+            "$1$saltsalt$9xy1btjgzLYfb7hivXtC//"
+        );
+
+        let users = parse_htpasswd(HTPASSWD, Path::new("htpasswd"));
+        assert_eq!(users.len(), 5);
+        assert!(!users.contains_key("sha1"));
+        for user in ["apr", "md5", "sha256", "sha512"] {
+            assert!(verify_password("secret", &users[user]), "{user}");
+            assert!(!verify_password("Secret", &users[user]), "{user}");
+        }
+        assert!(verify_password("U*U", &users["bcrypt"]));
+        assert!(!verify_password("U*V", &users["bcrypt"]));
+    }
+
+    /// Makes the next request check the htpasswd file for changes.
+    fn expire_check(auth: &BasicAuth) {
+        auth.htpasswd.state.lock().unwrap().checked = None;
+    }
+
+    #[test(tokio::test)]
+    async fn authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("htpasswd");
+        fs::write(&path, HTPASSWD).unwrap();
+
+        let rules = BasicAuthRules::new([BasicAuthRule {
+            pattern: Some("/private/**".to_owned()),
+            htpasswd: path.clone(),
+            realm: "Private \"area\"".to_owned(),
+        }])
+        .unwrap();
+        assert!(rules.find("/public/file.txt").is_none());
+        assert!(rules.find("/private").is_some());
+        assert!(rules.find("/public/../private/file.txt").is_some());
+        assert!(rules.find("/%70rivate/file.txt").is_some());
+
+        let auth = rules.find("/private/file.txt").unwrap();
+        assert_eq!(
+            auth.challenge(),
+            "Basic realm=\"Private \\\"area\\\"\", charset=\"UTF-8\""
+        );
+        assert_eq!(auth.authenticate(None).await, None);
+        assert_eq!(auth.authenticate(Some("Bearer token")).await, None);
+        assert_eq!(auth.authenticate(Some("Basic !!!")).await, None);
+        assert_eq!(
+            auth.authenticate(Some(&credentials("apr", "wrong"))).await,
+            None
+        );
+        assert_eq!(
+            auth.authenticate(Some(&credentials("nobody", "secret")))
+                .await,
+            None
+        );
+        assert_eq!(
+            auth.authenticate(Some(&credentials("apr", "secret")))
+                .await
+                .as_deref(),
+            Some("apr")
+        );
+        // Served from the verified credentials now
+        assert_eq!(
+            auth.authenticate(Some(&credentials("apr", "secret")))
+                .await
+                .as_deref(),
+            Some("apr")
+        );
+
+        // Changed file is reloaded, but not checked again right away
+        fs::write(
+            &path,
+            "sha256:$5$saltsalt$0IyaXrmV7.sGNS6tirgqHLqX/G.FBvgkYA.lpPdS5sA\n",
+        )
+        .unwrap();
+        assert_eq!(
+            auth.authenticate(Some(&credentials("apr", "secret")))
+                .await
+                .as_deref(),
+            Some("apr")
+        );
+        expire_check(auth);
+        assert_eq!(
+            auth.authenticate(Some(&credentials("apr", "secret"))).await,
+            None
+        );
+        assert_eq!(
+            auth.authenticate(Some(&credentials("sha256", "secret")))
+                .await
+                .as_deref(),
+            Some("sha256")
+        );
+
+        // Nobody gets in if the file disappears
+        fs::remove_file(&path).unwrap();
+        expire_check(auth);
+        assert_eq!(
+            auth.authenticate(Some(&credentials("sha256", "secret")))
+                .await,
+            None
+        );
+    }
+}
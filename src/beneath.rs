@@ -0,0 +1,112 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Race-free opening of files below a root directory via Linux `openat2()` system call
+
+use log::debug;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Handle of a root directory, files are opened relative to it with the kernel making sure that
+/// path resolution never leaves the directory. Unlike checking canonicalized paths, this cannot be
+/// circumvented by swapping a path component for a symbolic link after the check.
+#[derive(Debug)]
+pub(crate) struct RootDir {
+    dir: File,
+}
+
+impl RootDir {
+    /// Opens the root directory. Returns `None` if `openat2()` isn’t available, either because
+    /// the kernel is older than 5.6 or because a seccomp filter blocks it.
+    pub(crate) fn open(root: &Path) -> Result<Option<Self>, Error> {
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(root)?;
+        let result = Self { dir };
+
+        match result.openat2(Path::new(""), libc::O_PATH, true) {
+            Ok(_) => Ok(Some(result)),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                debug!("openat2() unavailable, falling back to path checks: {err}");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Opens a file or directory relative to the root for metadata access only. Unless
+    /// `follow_symlinks` is `true`, encountering a symbolic link results in an `ELOOP` error.
+    pub(crate) fn open_path(&self, rel_path: &Path, follow_symlinks: bool) -> Result<File, Error> {
+        self.openat2(rel_path, libc::O_PATH, follow_symlinks)
+    }
+
+    /// Opens a file or directory relative to the root for reading. Unless `follow_symlinks` is
+    /// `true`, encountering a symbolic link results in an `ELOOP` error.
+    pub(crate) fn open_read(&self, rel_path: &Path, follow_symlinks: bool) -> Result<File, Error> {
+        // O_NONBLOCK prevents hanging if the entry has been replaced by a named pipe
+        self.openat2(rel_path, libc::O_RDONLY | libc::O_NONBLOCK, follow_symlinks)
+    }
+
+    fn openat2(
+        &self,
+        rel_path: &Path,
+        flags: libc::c_int,
+        follow_symlinks: bool,
+    ) -> Result<File, Error> {
+        let rel_path = if rel_path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            rel_path
+        };
+        let c_path =
+            CString::new(rel_path.as_os_str().as_bytes()).map_err(|_| ErrorKind::InvalidInput)?;
+
+        // SAFETY: open_how is a plain C struct, all zeroes is a valid value
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        how.flags = (flags | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+        if !follow_symlinks {
+            how.resolve |= libc::RESOLVE_NO_SYMLINKS;
+        }
+
+        // SAFETY: all pointers are valid for the duration of the call, struct size is passed
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                self.dir.as_raw_fd(),
+                c_path.as_ptr(),
+                &how as *const libc::open_how,
+                std::mem::size_of::<libc::open_how>(),
+            )
+        };
+        if fd < 0 {
+            let err = Error::last_os_error();
+            return Err(if err.raw_os_error() == Some(libc::EXDEV) {
+                // Path resolution attempted to leave the root directory
+                Error::new(ErrorKind::InvalidData, err)
+            } else {
+                err
+            });
+        }
+
+        // SAFETY: the system call returned a new file descriptor that nothing else owns
+        Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+}
@@ -22,6 +22,7 @@ use pingora::http::ResponseHeader;
 use std::path::{Path, PathBuf};
 use pingora::proxy::Session;
 use crate::compression_algorithm::{find_matches, CompressionAlgorithm};
+use crate::storage::Storage;
 
 /// Encapsulates the compression state for the current session.
 pub(crate) struct Compression<'a> {
@@ -49,9 +50,10 @@ impl<'a> Compression<'a> {
     }
 
     /// Checks whether the given path should be rewritten to a pre-compressed version of the file.
-    pub(crate) fn rewrite_path(
+    pub(crate) async fn rewrite_path(
         &mut self,
         session: &Session,
+        storage: &dyn Storage,
         path: &Path,
     ) -> Option<PathBuf> {
         if self.precompressed.is_empty() {
@@ -69,7 +71,7 @@ impl<'a> Compression<'a> {
 
             let mut candidate_path = path.to_path_buf();
             candidate_path.set_file_name(candidate_name);
-            if candidate_path.is_file() {
+            if storage.is_file(&candidate_path).await {
                 self.precompressed_active = Some(algorithm);
                 return Some(candidate_path);
             }
@@ -78,6 +80,11 @@ impl<'a> Compression<'a> {
         None
     }
 
+    /// Returns the compression algorithm of the pre-compressed file being served if any.
+    pub(crate) fn precompressed_active(&self) -> Option<CompressionAlgorithm> {
+        self.precompressed_active
+    }
+
     /// Applies the necessary modification to the HTTP response if compression is active. This will
     /// add `Content-Encoding` HTTP header among other thins.
     pub(crate) fn transform_header(
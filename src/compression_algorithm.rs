@@ -15,12 +15,12 @@
 //! Handles various compression algorithms allowed in `Accept-Encoding` and `Content-Encoding` HTTP
 //! headers.
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::str::FromStr;
 
 /// Represents a compression algorithm choice.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum CompressionAlgorithm {
     /// gzip compression
     #[serde(rename = "gz")]
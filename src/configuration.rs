@@ -18,9 +18,13 @@ use clap::Parser;
 use mime_guess::mime::FromStrError;
 use mime_guess::Mime;
 use crate::deserialize::{DeserializeMap, OneOrMany};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::ffi::OsString;
+use std::fmt::Display;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::compression_algorithm::CompressionAlgorithm;
 
@@ -58,12 +62,618 @@ impl TryFrom<String> for MimeMatch {
     }
 }
 
+impl Display for MimeMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(mime) => write!(f, "{mime}"),
+            Self::Type(type_) => write!(f, "{type_}/*"),
+            Self::Prefix(prefix) => write!(f, "{prefix}*"),
+            Self::Suffix(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+impl Serialize for MimeMatch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Determines which symbolic links within the root directory are followed. Symbolic links never
+/// lead outside the root directory regardless of the policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "SymlinkPolicyRepr")]
+pub enum SymlinkPolicy {
+    /// Follow all symbolic links
+    #[default]
+    Follow,
+    /// Follow symbolic links only if the link and its target belong to the same user
+    OwnerMatch,
+    /// Never follow symbolic links
+    Deny,
+    /// Follow symbolic links only if the target is located within one of these directories
+    AllowTargets(Vec<PathBuf>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SymlinkPolicyRepr {
+    Name(String),
+    Targets(Vec<PathBuf>),
+}
+
+impl TryFrom<SymlinkPolicyRepr> for SymlinkPolicy {
+    type Error = String;
+
+    fn try_from(value: SymlinkPolicyRepr) -> Result<Self, Self::Error> {
+        match value {
+            SymlinkPolicyRepr::Name(name) => name.parse(),
+            SymlinkPolicyRepr::Targets(targets) => Ok(Self::AllowTargets(targets)),
+        }
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    /// Parses `follow`, `owner-match`, `deny` or a comma-separated list of allowed targets
+    /// prefixed with `allow:`, e.g. `allow:/srv/shared,/srv/media`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "follow" => Ok(Self::Follow),
+            "owner-match" => Ok(Self::OwnerMatch),
+            "deny" => Ok(Self::Deny),
+            _ => {
+                if let Some(targets) = value.strip_prefix("allow:") {
+                    Ok(Self::AllowTargets(
+                        targets.split(',').map(PathBuf::from).collect(),
+                    ))
+                } else {
+                    Err(format!("Unsupported symbolic link policy: {value}"))
+                }
+            }
+        }
+    }
+}
+
+impl Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Follow => write!(f, "follow"),
+            Self::OwnerMatch => write!(f, "owner-match"),
+            Self::Deny => write!(f, "deny"),
+            Self::AllowTargets(targets) => write!(f, "allow-list {targets:?}"),
+        }
+    }
+}
+
+impl Serialize for SymlinkPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::AllowTargets(targets) => targets.serialize(serializer),
+            policy => serializer.collect_str(policy),
+        }
+    }
+}
+
+/// Determines how requests to hidden files and directories (names starting with a dot) are
+/// handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HiddenPolicyRepr")]
+pub enum HiddenPolicy {
+    /// Serve hidden files like any other files
+    #[default]
+    Serve,
+    /// Respond with `404 Not Found`
+    NotFound,
+    /// Respond with `403 Forbidden`
+    Forbidden,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HiddenPolicyRepr {
+    Name(String),
+    Status(u16),
+}
+
+impl TryFrom<HiddenPolicyRepr> for HiddenPolicy {
+    type Error = String;
+
+    fn try_from(value: HiddenPolicyRepr) -> Result<Self, Self::Error> {
+        match value {
+            HiddenPolicyRepr::Name(name) => name.parse(),
+            HiddenPolicyRepr::Status(status) => status.to_string().parse(),
+        }
+    }
+}
+
+impl FromStr for HiddenPolicy {
+    type Err = String;
+
+    /// Parses `serve`, `404` or `403`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "serve" => Ok(Self::Serve),
+            "404" => Ok(Self::NotFound),
+            "403" => Ok(Self::Forbidden),
+            _ => Err(format!("Unsupported hidden files policy: {value}")),
+        }
+    }
+}
+
+impl Display for HiddenPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Serve => write!(f, "serve"),
+            Self::NotFound => write!(f, "404"),
+            Self::Forbidden => write!(f, "403"),
+        }
+    }
+}
+
+impl Serialize for HiddenPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A candidate in a `try_files` list
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TryFilesEntry {
+    /// URI path to try, `$uri` is replaced by the request path. A trailing slash means that the
+    /// candidate has to be a directory, otherwise it has to be a regular file.
+    Path(String),
+    /// Respond with the given status code, e.g. `=404`
+    Status(u16),
+}
+
+impl TryFilesEntry {
+    /// Checks whether this candidate is the request path itself, with or without trailing slash.
+    pub fn is_request_uri(&self) -> bool {
+        matches!(self, Self::Path(path) if path == "$uri" || path == "$uri/")
+    }
+}
+
+impl FromStr for TryFilesEntry {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(status) = value.strip_prefix('=') {
+            status
+                .parse()
+                .ok()
+                .filter(|status| (100..1000).contains(status))
+                .map(Self::Status)
+                .ok_or_else(|| format!("Invalid status code in try_files entry: {value}"))
+        } else if value.starts_with('/') || value.starts_with("$uri") {
+            Ok(Self::Path(value.to_owned()))
+        } else {
+            Err(format!("try_files entry has to start with /, $uri or =: {value}"))
+        }
+    }
+}
+
+impl TryFrom<String> for TryFilesEntry {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for TryFilesEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{path}"),
+            Self::Status(status) => write!(f, "={status}"),
+        }
+    }
+}
+
+impl Serialize for TryFilesEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A list of candidates to try for requests matching a path pattern
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TryFilesRule {
+    /// Glob pattern that the request path has to match, e.g. `/docs/**`. If missing, the rule
+    /// applies to all requests.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// Candidates to try in order
+    #[serde(rename = "try")]
+    pub candidates: OneOrMany<TryFilesEntry>,
+}
+
+/// A redirect rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RedirectRule {
+    /// Source path. This can be an exact path like `/old.html`, a path with placeholders like
+    /// `/blog/:year/:slug` and/or a trailing splat like `/docs/*`, a glob pattern like
+    /// `/**/*.php` or a regular expression prefixed with `~` like `~^/post/(\d+)$`.
+    pub from: String,
+
+    /// Redirect target. Placeholders and the splat can be used as `:year` and `:splat`, regular
+    /// expression captures as `$1` or `${name}`.
+    pub to: String,
+
+    /// Redirect status code: 301, 302, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+
+    /// If `true`, the query string of the request is appended to the redirect target.
+    #[serde(default = "default_true")]
+    pub preserve_query: bool,
+
+    /// If `false`, the redirect only applies if the requested file doesn’t exist.
+    #[serde(default = "default_true")]
+    pub force: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Disposition type of a `Content-Disposition` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DispositionType {
+    /// Display the file in the browser if possible
+    #[default]
+    Inline,
+    /// Download the file
+    Attachment,
+}
+
+impl FromStr for DispositionType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "inline" => Ok(Self::Inline),
+            "attachment" => Ok(Self::Attachment),
+            _ => Err(format!("Unsupported disposition type: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for DispositionType {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for DispositionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inline => write!(f, "inline"),
+            Self::Attachment => write!(f, "attachment"),
+        }
+    }
+}
+
+impl Serialize for DispositionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A rule determining the `Content-Disposition` header for matching files
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DispositionRule {
+    /// Glob pattern that the request path has to match, e.g. `/downloads/**`. If missing, the
+    /// rule applies to all paths.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// MIME types the rule applies to, e.g. `application/pdf` or `image/*`. If empty, the rule
+    /// applies to all MIME types.
+    #[serde(default)]
+    pub mime: OneOrMany<MimeMatch>,
+
+    /// Disposition type to declare
+    pub disposition: DispositionType,
+}
+
+/// A path protected by HTTP Basic authentication
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BasicAuthRule {
+    /// Glob pattern that the normalized request path has to match, e.g. `/internal/**`. If
+    /// missing, the rule applies to all paths.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// Apache htpasswd file with bcrypt, SHA-crypt or apr1 password hashes. The file is reloaded
+    /// when it changes.
+    pub htpasswd: PathBuf,
+
+    /// Realm to display in the browser’s login prompt
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+fn default_realm() -> String {
+    "Restricted".to_owned()
+}
+
+/// A path requiring a JSON Web Token for access
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JwtAuthRule {
+    /// Glob pattern that the normalized request path has to match, e.g. `/api/**`. If missing,
+    /// the rule applies to all paths.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// JWKS or PEM files with the keys tokens are signed with. The files are read when the
+    /// handler is created.
+    pub keys: OneOrMany<PathBuf>,
+
+    /// Accepted values of the `iss` claim. If empty, the issuer isn’t checked.
+    #[serde(default)]
+    pub issuer: OneOrMany<String>,
+
+    /// Accepted values of the `aud` claim. If empty, the audience isn’t checked.
+    #[serde(default)]
+    pub audience: OneOrMany<String>,
+
+    /// Cookie to take the token from if there is no `Authorization` header
+    #[serde(default)]
+    pub cookie: Option<String>,
+
+    /// Claims that have to match for access to be granted. Values `$1`, `$2` etc. refer to the
+    /// segments of the request path, e.g. `tenant: $1` requires the `tenant` claim to match the
+    /// first path segment.
+    #[serde(default)]
+    pub claims: BTreeMap<String, String>,
+}
+
+/// A path requiring signed, expiring URLs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SignedUrlRule {
+    /// Glob pattern that the normalized request path has to match, e.g. `/exports/**`. If
+    /// missing, the rule applies to all paths.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// Secret keys signatures are accepted for. Adding a new key before removing the old one
+    /// allows rotating keys without invalidating links already handed out.
+    #[serde(serialize_with = "serialize_redacted")]
+    pub keys: OneOrMany<String>,
+}
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A single
+/// address is accepted as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Checks whether an address belongs to this network. IPv4-mapped IPv6 addresses are
+    /// matched against IPv4 networks.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                (u32::from(net) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                (u128::from(net) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    /// Parses `address/prefix_len` or a single address
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (value, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("Invalid IP network {value}: {err}"))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in IP network {value}"))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Client networks allowed or denied access to a path
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IpAccessRule {
+    /// Glob pattern that the normalized request path has to match, e.g. `/internal/**`. If
+    /// missing, the rule applies to all paths.
+    #[serde(rename = "match", default)]
+    pub pattern: Option<String>,
+
+    /// Networks allowed access. If empty, all clients that aren’t denied are allowed.
+    #[serde(default)]
+    pub allow: OneOrMany<IpNetwork>,
+
+    /// Networks denied access, this takes precedence over `allow`.
+    #[serde(default)]
+    pub deny: OneOrMany<IpNetwork>,
+}
+
+/// Serializes secrets without revealing them, e.g. in the admin API configuration dump.
+fn serialize_redacted<S: Serializer>(
+    secrets: &OneOrMany<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(secrets.iter().map(|_| "<redacted>"))
+}
+
+/// Format of the access log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AccessLogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format, adds referrer and user agent to the common format
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unsupported access log format: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for AccessLogFormat {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for AccessLogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Common => write!(f, "common"),
+            Self::Combined => write!(f, "combined"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl Serialize for AccessLogFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Header that trusted proxies pass the client address in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ForwardedHeader {
+    /// Standard `Forwarded` header (RFC 7239)
+    Forwarded,
+    /// `X-Forwarded-For` header
+    #[default]
+    XForwardedFor,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "forwarded" => Ok(Self::Forwarded),
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            _ => Err(format!("Unsupported forwarded header: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for ForwardedHeader {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for ForwardedHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forwarded => write!(f, "forwarded"),
+            Self::XForwardedFor => write!(f, "x-forwarded-for"),
+        }
+    }
+}
+
+impl Serialize for ForwardedHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A mapping of a file extension or glob pattern to a MIME type, given as `pattern=type` on the
+/// command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeMapping(pub String, pub String);
+
+impl FromStr for MimeMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (pattern, mime) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Expected extension=type: {value}"))?;
+        Ok(Self(pattern.to_owned(), mime.to_owned()))
+    }
+}
+
 /// Command line options of the static files module
 #[derive(Debug, Default, Parser)]
 pub struct StaticFilesOpt {
-    /// The root directory.
+    /// The root directory or a ZIP/tar archive to serve files from. This command line flag can
+    /// be specified multiple times, the first root containing a file wins then.
     #[clap(short, long, value_parser = clap::value_parser!(OsString))]
-    pub root: Option<PathBuf>,
+    pub root: Option<Vec<PathBuf>>,
 
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     #[clap(long)]
@@ -92,13 +702,120 @@ pub struct StaticFilesOpt {
     /// specified multiple times.
     #[clap(long, value_parser = clap::value_parser!(String))]
     pub declare_charset_types: Option<Vec<MimeMatch>>,
+
+    /// Git revision (branch, tag or commit) to serve, the root is a git repository then.
+    #[clap(long)]
+    pub git_ref: Option<String>,
+
+    /// Request header that can select a different git branch, tag or commit to serve.
+    #[clap(long)]
+    pub git_ref_header: Option<String>,
+
+    /// Domain whose subdomains select the git branch or tag to serve, e.g. docs.example.com
+    /// for feature-x.docs.example.com.
+    #[clap(long)]
+    pub git_ref_domain: Option<String>,
+
+    /// Re-resolve symbolic links in the root path at most this often (in milliseconds), 0 means
+    /// on every request.
+    #[clap(long)]
+    pub root_refresh_ms: Option<u64>,
+
+    /// Symbolic links to follow: follow, owner-match, deny or allow:<dir>,<dir>,... to follow
+    /// only links pointing into the listed directories.
+    #[clap(long)]
+    pub symlinks: Option<SymlinkPolicy>,
+
+    /// Handling of hidden files (names starting with a dot): serve, 404 or 403.
+    #[clap(long)]
+    pub hidden: Option<HiddenPolicy>,
+
+    /// Glob pattern of paths to deny access to, e.g. /**/*.bak. This command line flag can be
+    /// specified multiple times.
+    #[clap(long)]
+    pub deny: Option<Vec<String>>,
+
+    /// Candidate to try for all requests: $uri, $uri.html, $uri/, /fallback.html, =404 etc. This
+    /// command line flag can be specified multiple times.
+    #[clap(long)]
+    pub try_files: Option<Vec<TryFilesEntry>>,
+
+    /// Load redirect rules from the Netlify-style _redirects file in the root directory.
+    #[clap(long)]
+    pub netlify_redirects: Option<bool>,
+
+    /// Honor per-directory .static.yaml files overriding some settings.
+    #[clap(long)]
+    pub directory_overrides: Option<bool>,
+
+    /// MIME type for a file extension or glob pattern, e.g. wasm=application/wasm. This command
+    /// line flag can be specified multiple times.
+    #[clap(long)]
+    pub mime_types: Option<Vec<MimeMapping>>,
+
+    /// File in Apache mime.types or nginx types format to load MIME types from.
+    #[clap(long)]
+    pub mime_types_file: Option<PathBuf>,
+
+    /// MIME type for files where no other type applies.
+    #[clap(long)]
+    pub default_type: Option<String>,
+
+    /// Determine the MIME type from file contents if the file name doesn't indicate one.
+    #[clap(long)]
+    pub sniff_content: Option<bool>,
+
+    /// Glob pattern of request paths to serve as downloads, e.g. /downloads/**. This command
+    /// line flag can be specified multiple times.
+    #[clap(long)]
+    pub attachment: Option<Vec<String>>,
+
+    /// Serve files as downloads if the query string contains a download parameter.
+    #[clap(long)]
+    pub download_query: Option<bool>,
+
+    /// Request path to respond to with metrics in Prometheus text format, e.g. /metrics.
+    #[clap(long)]
+    pub metrics_path: Option<String>,
+
+    /// File to write the access log to.
+    #[clap(long)]
+    pub access_log: Option<PathBuf>,
+
+    /// Access log format: common, combined or json.
+    #[clap(long)]
+    pub access_log_format: Option<AccessLogFormat>,
+
+    /// Apache htpasswd file to require HTTP Basic authentication for all requests with.
+    #[clap(long)]
+    pub htpasswd: Option<PathBuf>,
+
+    /// Client network to allow access to, e.g. 10.0.0.0/8. Other clients are denied access then.
+    /// This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub allow_ip: Option<Vec<IpNetwork>>,
+
+    /// Client network to deny access to. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub deny_ip: Option<Vec<IpNetwork>>,
+
+    /// Proxy network to accept X-Forwarded-For and Forwarded headers from. This command line flag
+    /// can be specified multiple times.
+    #[clap(long)]
+    pub trusted_proxies: Option<Vec<IpNetwork>>,
+
+    /// Header to take the client address from for requests by trusted proxies: forwarded or
+    /// x-forwarded-for.
+    #[clap(long)]
+    pub forwarded_header: Option<ForwardedHeader>,
 }
 
 /// Configuration file settings of the static files module
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct StaticFilesConf {
-    /// The root directory.
-    pub root: Option<PathBuf>,
+    /// The root directories or ZIP/tar archives to serve files from. If multiple roots are given,
+    /// these are searched in order and the first one containing a file wins.
+    pub root: OneOrMany<PathBuf>,
 
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     pub canonicalize_uri: bool,
@@ -111,7 +828,8 @@ pub struct StaticFilesConf {
 
     /// List of file extensions to check when looking for pre-compressed versions of a file.
     /// Supported file extensions are gz (gzip), zz (zlib deflate), z (compress), br (Brotli),
-    /// zst (Zstandard).
+    /// zst (Zstandard). Deflate-compressed entries of archive roots are only served compressed if
+    /// gz is listed.
     pub precompressed: OneOrMany<CompressionAlgorithm>,
 
     /// The character set to declare for text files.
@@ -119,14 +837,114 @@ pub struct StaticFilesConf {
 
     /// List of MIME types that the `declare_charset` setting should apply to.
     pub declare_charset_types: OneOrMany<MimeMatch>,
+
+    /// Git revision (branch, tag or commit) to serve. If set, the root is a git repository. The
+    /// revision is resolved again for each request, so new commits on a branch are served
+    /// immediately.
+    pub git_ref: Option<String>,
+
+    /// Request header that can select a different git branch, tag or commit to serve.
+    pub git_ref_header: Option<String>,
+
+    /// If set, the subdomain of this domain selects the git branch or tag to serve, e.g. with
+    /// `docs.example.com` the host name `feature-x.docs.example.com` serves branch `feature-x`.
+    pub git_ref_domain: Option<String>,
+
+    /// If set, symbolic links in the root path are re-resolved at most this often (in
+    /// milliseconds), 0 means on every request. This allows switching releases by pointing a
+    /// symbolic link to a different directory.
+    pub root_refresh_ms: Option<u64>,
+
+    /// Determines which symbolic links within the root directory are followed.
+    pub symlinks: SymlinkPolicy,
+
+    /// Determines how requests to hidden files and directories are handled.
+    pub hidden: HiddenPolicy,
+
+    /// Glob patterns of paths to deny access to, matched against the normalized URI path, e.g.
+    /// `/**/*.bak` or `/**/.git/**`.
+    pub deny: OneOrMany<String>,
+
+    /// Lists of candidates to try instead of the request path, the first rule with a matching
+    /// pattern applies.
+    pub try_files: OneOrMany<TryFilesRule>,
+
+    /// Redirect rules, the first matching rule applies.
+    pub redirects: OneOrMany<RedirectRule>,
+
+    /// If `true`, redirect rules are also loaded from the Netlify-style `_redirects` file in the
+    /// root directory. These rules apply after the ones from the `redirects` setting.
+    pub netlify_redirects: bool,
+
+    /// If `true`, `.static.yaml` files in the directories are read at request time, these can
+    /// override `index_file`, `page_404` and `declare_charset` settings and add response headers
+    /// for the directory and its subdirectories.
+    pub directory_overrides: bool,
+
+    /// MIME types by file extension (with or without leading dot) or glob pattern. Patterns
+    /// containing a slash are matched against the path relative to the root, other patterns
+    /// against the file name. These take precedence over `mime_types_file` and built-in types.
+    pub mime_types: BTreeMap<String, String>,
+
+    /// File in Apache `mime.types` or nginx `types { … }` format to load MIME types from. These
+    /// take precedence over built-in types.
+    pub mime_types_file: Option<PathBuf>,
+
+    /// MIME type for files where no other type applies.
+    pub default_type: String,
+
+    /// If `true`, the MIME type of files without a known extension is determined from the file
+    /// contents (PNG, JPEG, GIF, WebP, PDF, gzip, HTML, JSON and UTF-8 text are recognized).
+    /// Otherwise `X-Content-Type-Options: nosniff` is sent to keep browsers from guessing.
+    pub sniff_content: bool,
+
+    /// Rules determining the `Content-Disposition` header, the first matching rule applies. No
+    /// header is sent if no rule matches.
+    pub content_disposition: OneOrMany<DispositionRule>,
+
+    /// If `true`, requests with a `download` query parameter like `/file.pdf?download` are
+    /// served as attachments.
+    pub download_query: bool,
+
+    /// If set, requests to this path are answered with the metrics in Prometheus text format.
+    /// Metrics are registered with the default `prometheus` registry regardless.
+    pub metrics_path: Option<String>,
+
+    /// File to write the access log of requests served by this handler to. The file is reopened
+    /// on `SIGHUP`.
+    pub access_log: Option<PathBuf>,
+
+    /// Access log format: `common`, `combined` or `json`. Resolved file path, encoding, range and
+    /// duration are always logged as well.
+    pub access_log_format: AccessLogFormat,
+
+    /// Paths requiring HTTP Basic authentication, the first matching rule applies.
+    pub basic_auth: OneOrMany<BasicAuthRule>,
+
+    /// Paths requiring a JSON Web Token, the first matching rule applies.
+    pub jwt_auth: OneOrMany<JwtAuthRule>,
+
+    /// Paths requiring signed, expiring URLs, the first matching rule applies.
+    pub signed_urls: OneOrMany<SignedUrlRule>,
+
+    /// Client networks allowed or denied access, the first rule with a matching path applies.
+    pub ip_access: OneOrMany<IpAccessRule>,
+
+    /// Networks of reverse proxies to accept the client address from. For requests from these
+    /// addresses the `Forwarded` or `X-Forwarded-For` header determines the client address.
+    pub trusted_proxies: OneOrMany<IpNetwork>,
+
+    /// Header that trusted proxies pass the client address in: `forwarded` or `x-forwarded-for`.
+    /// Only this header is considered, the proxy has to overwrite or append to it.
+    pub forwarded_header: ForwardedHeader,
 }
 
 impl StaticFilesConf {
     /// Merges the command line options into the current configuration. Any command line options
     /// present overwrite existing settings.
     pub fn merge_with_opt(&mut self, opt: StaticFilesOpt) {
-        if opt.root.is_some() {
-            self.root = opt.root;
+        if let Some(root) = opt.root {
+            self.root = root.into();
         }
 
         if let Some(canonicalize_uri) = opt.canonicalize_uri {
@@ -152,19 +970,201 @@ impl StaticFilesConf {
         if let Some(declare_charset_types) = opt.declare_charset_types {
             self.declare_charset_types = declare_charset_types.into();
         }
+
+        if opt.git_ref.is_some() {
+            self.git_ref = opt.git_ref;
+        }
+
+        if opt.git_ref_header.is_some() {
+            self.git_ref_header = opt.git_ref_header;
+        }
+
+        if opt.git_ref_domain.is_some() {
+            self.git_ref_domain = opt.git_ref_domain;
+        }
+
+        if opt.root_refresh_ms.is_some() {
+            self.root_refresh_ms = opt.root_refresh_ms;
+        }
+
+        if let Some(symlinks) = opt.symlinks {
+            self.symlinks = symlinks;
+        }
+
+        if let Some(hidden) = opt.hidden {
+            self.hidden = hidden;
+        }
+
+        if let Some(deny) = opt.deny {
+            self.deny = deny.into();
+        }
+
+        if let Some(try_files) = opt.try_files {
+            self.try_files = vec![TryFilesRule {
+                pattern: None,
+                candidates: try_files.into(),
+            }]
+            .into();
+        }
+
+        if let Some(netlify_redirects) = opt.netlify_redirects {
+            self.netlify_redirects = netlify_redirects;
+        }
+
+        if let Some(directory_overrides) = opt.directory_overrides {
+            self.directory_overrides = directory_overrides;
+        }
+
+        if let Some(mime_types) = opt.mime_types {
+            for MimeMapping(pattern, mime) in mime_types {
+                self.mime_types.insert(pattern, mime);
+            }
+        }
+
+        if opt.mime_types_file.is_some() {
+            self.mime_types_file = opt.mime_types_file;
+        }
+
+        if let Some(default_type) = opt.default_type {
+            self.default_type = default_type;
+        }
+
+        if let Some(sniff_content) = opt.sniff_content {
+            self.sniff_content = sniff_content;
+        }
+
+        if let Some(attachment) = opt.attachment {
+            for pattern in attachment {
+                self.content_disposition.push(DispositionRule {
+                    pattern: Some(pattern),
+                    mime: Default::default(),
+                    disposition: DispositionType::Attachment,
+                });
+            }
+        }
+
+        if let Some(download_query) = opt.download_query {
+            self.download_query = download_query;
+        }
+
+        if opt.metrics_path.is_some() {
+            self.metrics_path = opt.metrics_path;
+        }
+
+        if opt.access_log.is_some() {
+            self.access_log = opt.access_log;
+        }
+
+        if let Some(access_log_format) = opt.access_log_format {
+            self.access_log_format = access_log_format;
+        }
+
+        if let Some(htpasswd) = opt.htpasswd {
+            self.basic_auth.push(BasicAuthRule {
+                pattern: None,
+                htpasswd,
+                realm: default_realm(),
+            });
+        }
+
+        if opt.allow_ip.is_some() || opt.deny_ip.is_some() {
+            self.ip_access.push(IpAccessRule {
+                pattern: None,
+                allow: opt.allow_ip.unwrap_or_default().into(),
+                deny: opt.deny_ip.unwrap_or_default().into(),
+            });
+        }
+
+        if let Some(trusted_proxies) = opt.trusted_proxies {
+            self.trusted_proxies = trusted_proxies.into();
+        }
+
+        if let Some(forwarded_header) = opt.forwarded_header {
+            self.forwarded_header = forwarded_header;
+        }
     }
 }
 
 impl Default for StaticFilesConf {
     fn default() -> Self {
         Self {
-            root: None,
+            root: Default::default(),
             canonicalize_uri: true,
             index_file: Default::default(),
             page_404: None,
             precompressed: Default::default(),
             declare_charset: "utf-8".to_owned(),
             declare_charset_types: Default::default(),
+            git_ref: None,
+            git_ref_header: None,
+            git_ref_domain: None,
+            root_refresh_ms: None,
+            symlinks: Default::default(),
+            hidden: Default::default(),
+            deny: Default::default(),
+            try_files: Default::default(),
+            redirects: Default::default(),
+            netlify_redirects: false,
+            directory_overrides: false,
+            mime_types: Default::default(),
+            mime_types_file: None,
+            default_type: "application/octet-stream".to_owned(),
+            sniff_content: false,
+            content_disposition: Default::default(),
+            download_query: true,
+            metrics_path: None,
+            access_log: None,
+            access_log_format: Default::default(),
+            basic_auth: Default::default(),
+            jwt_auth: Default::default(),
+            signed_urls: Default::default(),
+            ip_access: Default::default(),
+            trusted_proxies: Default::default(),
+            forwarded_header: Default::default(),
+        }
+    }
+}
+
+/// Settings of the admin API, see [`AdminHandler`](crate::AdminHandler)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminConf {
+    /// Path prefix of the admin endpoints. This can be empty if the admin handler is bound to a
+    /// separate listener.
+    pub prefix: String,
+
+    /// Token expected in the `Authorization: Bearer <token>` request header, required.
+    pub token: String,
+}
+
+impl Default for AdminConf {
+    fn default() -> Self {
+        Self {
+            prefix: "/_admin".to_owned(),
+            token: String::new(),
+        }
+    }
+}
+
+/// Settings of the health endpoints, see [`HealthHandler`](crate::HealthHandler)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthConf {
+    /// Path of the liveness endpoint, responding whenever the server is running.
+    pub liveness_path: String,
+
+    /// Path of the readiness endpoint, responding with `503 Service Unavailable` if a root isn’t
+    /// accessible.
+    pub readiness_path: String,
+
+    /// Time in milliseconds a root check may take before the root is considered unavailable.
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthConf {
+    fn default() -> Self {
+        Self {
+            liveness_path: "/healthz".to_owned(),
+            readiness_path: "/readyz".to_owned(),
+            timeout_ms: 5000,
         }
     }
 }
@@ -202,4 +1202,81 @@ mod test {
             MimeMatch::Exact("text/xml".parse().unwrap())
         );
     }
+
+    #[test]
+    fn policy_parsing() {
+        assert_eq!("follow".parse(), Ok(SymlinkPolicy::Follow));
+        assert_eq!("owner-match".parse(), Ok(SymlinkPolicy::OwnerMatch));
+        assert_eq!("deny".parse(), Ok(SymlinkPolicy::Deny));
+        assert_eq!(
+            "allow:/srv/shared,/srv/media".parse(),
+            Ok(SymlinkPolicy::AllowTargets(vec![
+                PathBuf::from("/srv/shared"),
+                PathBuf::from("/srv/media"),
+            ]))
+        );
+        assert!("sometimes".parse::<SymlinkPolicy>().is_err());
+
+        assert_eq!(
+            serde_yaml::from_str::<SymlinkPolicy>("[/srv/shared]").unwrap(),
+            SymlinkPolicy::AllowTargets(vec![PathBuf::from("/srv/shared")])
+        );
+        assert_eq!(
+            serde_yaml::from_str::<HiddenPolicy>("404").unwrap(),
+            HiddenPolicy::NotFound
+        );
+        assert_eq!(
+            serde_yaml::from_str::<HiddenPolicy>("serve").unwrap(),
+            HiddenPolicy::Serve
+        );
+        assert!("500".parse::<HiddenPolicy>().is_err());
+    }
+
+    #[test]
+    fn ip_network_parsing() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(network.to_string(), "10.0.0.0/8");
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8:1::5".parse().unwrap()));
+        assert!(!network.contains("2001:db9::5".parse().unwrap()));
+
+        let network: IpNetwork = "192.168.1.5".parse().unwrap();
+        assert_eq!(network.to_string(), "192.168.1.5/32");
+        assert!(network.contains("192.168.1.5".parse().unwrap()));
+        assert!(!network.contains("192.168.1.6".parse().unwrap()));
+
+        let network: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(network.contains("203.0.113.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!(serde_yaml::from_str::<IpNetwork>("office").is_err());
+    }
+
+    #[test]
+    fn try_files_parsing() {
+        assert_eq!(
+            "$uri.html".parse(),
+            Ok(TryFilesEntry::Path("$uri.html".to_owned()))
+        );
+        assert_eq!(
+            "/fallback.html".parse(),
+            Ok(TryFilesEntry::Path("/fallback.html".to_owned()))
+        );
+        assert_eq!("=404".parse(), Ok(TryFilesEntry::Status(404)));
+        assert!("=abc".parse::<TryFilesEntry>().is_err());
+        assert!("fallback.html".parse::<TryFilesEntry>().is_err());
+
+        let rule: TryFilesRule =
+            serde_yaml::from_str("match: /docs/**\ntry: [$uri, $uri.html, =404]").unwrap();
+        assert_eq!(rule.pattern.as_deref(), Some("/docs/**"));
+        assert_eq!(rule.candidates.len(), 3);
+        assert!(rule.candidates[0].is_request_uri());
+        assert!(!rule.candidates[1].is_request_uri());
+    }
 }
@@ -1,6 +1,7 @@
 use pingora::server::configuration::ServerConf;
 use serde::de::value::{MapAccessDeserializer, StrDeserializer, StringDeserializer};
 use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error, SeqAccess, Visitor};
+use serde::{Serialize, Serializer};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
 
@@ -279,6 +280,18 @@ where
     }
 }
 
+impl<T> Serialize for OneOrMany<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.inner.serialize(serializer)
+    }
+}
+
 #[doc(hidden)]
 pub mod _private {
     //! This is a hack meant to make configuration merging possible even with types that don’t
@@ -0,0 +1,196 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `Content-Disposition` rules and header encoding
+
+use glob::PatternError;
+use mime_guess::Mime;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::configuration::{DispositionRule, DispositionType};
+use crate::mime_matcher::MimeMatcher;
+use crate::path_rules::PathRules;
+
+/// Characters that don’t need to be encoded in an RFC 5987 `ext-value`, see `attr-char`
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// `Content-Disposition` rules with patterns compiled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DispositionRules {
+    rules: PathRules<(Option<MimeMatcher>, DispositionType)>,
+}
+
+impl DispositionRules {
+    /// Compiles the rules from the configuration.
+    pub(crate) fn new(
+        rules: impl IntoIterator<Item = DispositionRule>,
+    ) -> Result<Self, PatternError> {
+        let rules = PathRules::new(rules.into_iter().map(|rule| {
+            let mime = if rule.mime.is_empty() {
+                None
+            } else {
+                let mut matcher = MimeMatcher::new();
+                for mime in Vec::from(rule.mime) {
+                    matcher.add(mime);
+                }
+                Some(matcher)
+            };
+            (rule.pattern, (mime, rule.disposition))
+        }))?;
+        Ok(Self { rules })
+    }
+
+    /// Determines the disposition type for a request path and MIME type, `None` if no rule
+    /// applies.
+    pub(crate) fn find(&self, uri_path: &str, mime: &Mime) -> Option<DispositionType> {
+        self.rules
+            .find_with(uri_path, |(matcher, _)| match matcher {
+                Some(matcher) => matcher.matches(mime),
+                None => true,
+            })
+            .map(|(_, disposition)| *disposition)
+    }
+}
+
+/// Checks whether the query string contains a `download` parameter, e.g. `?download` or
+/// `?download=1`.
+pub(crate) fn has_download_param(query: Option<&str>) -> bool {
+    query.is_some_and(|query| {
+        query
+            .split('&')
+            .any(|param| param.split_once('=').map_or(param, |(name, _)| name) == "download")
+    })
+}
+
+/// Produces the `Content-Disposition` header value for a file name. Following RFC 6266, the
+/// name is given as an ASCII-only `filename` parameter for older clients and as an RFC 5987
+/// encoded `filename*` parameter if it contains other characters.
+pub(crate) fn content_disposition(disposition: DispositionType, filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if fallback == filename {
+        format!("{disposition}; filename=\"{filename}\"")
+    } else {
+        format!(
+            "{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+            utf8_percent_encode(filename, ATTR_CHAR)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn rules() {
+        let rules = DispositionRules::new([
+            DispositionRule {
+                pattern: Some("/downloads/**".to_owned()),
+                mime: Default::default(),
+                disposition: DispositionType::Attachment,
+            },
+            DispositionRule {
+                pattern: None,
+                mime: vec![
+                    "image/*".try_into().unwrap(),
+                    "application/pdf".try_into().unwrap(),
+                ]
+                .into(),
+                disposition: DispositionType::Inline,
+            },
+        ])
+        .unwrap();
+
+        let pdf = "application/pdf".parse().unwrap();
+        let html = "text/html".parse().unwrap();
+        assert_eq!(
+            rules.find("/downloads/report.pdf", &pdf),
+            Some(DispositionType::Attachment)
+        );
+        assert_eq!(
+            rules.find("/downloads/sub/page.html", &html),
+            Some(DispositionType::Attachment)
+        );
+        assert_eq!(
+            rules.find("/docs/../downloads/report.pdf", &pdf),
+            Some(DispositionType::Attachment)
+        );
+        assert_eq!(
+            rules.find("/downloads/%72eport.pdf", &pdf),
+            Some(DispositionType::Attachment)
+        );
+        assert_eq!(
+            rules.find("/docs/report.pdf", &pdf),
+            Some(DispositionType::Inline)
+        );
+        assert_eq!(rules.find("/docs/page.html", &html), None);
+
+        assert!(DispositionRules::new([DispositionRule {
+            pattern: Some("[invalid".to_owned()),
+            mime: Default::default(),
+            disposition: DispositionType::Inline,
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn download_param() {
+        assert!(has_download_param(Some("download")));
+        assert!(has_download_param(Some("a=b&download=1")));
+        assert!(!has_download_param(Some("downloads")));
+        assert!(!has_download_param(Some("file=download")));
+        assert!(!has_download_param(None));
+    }
+
+    #[test]
+    fn header_encoding() {
+        assert_eq!(
+            content_disposition(DispositionType::Attachment, "report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\""
+        );
+        assert_eq!(
+            content_disposition(DispositionType::Inline, "a\"b\\c.txt"),
+            "inline; filename=\"a_b_c.txt\"; filename*=UTF-8''a%22b%5Cc.txt"
+        );
+        assert_eq!(
+            content_disposition(DispositionType::Attachment, "файл söndärzeichen.txt"),
+            "attachment; filename=\"____ s_nd_rzeichen.txt\"; \
+             filename*=UTF-8''%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt"
+        );
+    }
+}
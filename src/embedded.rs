@@ -0,0 +1,237 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backend serving files compiled into the binary.
+//!
+//! The file table is generated by a build script, using the `resource-proxy-pingora-embed` crate
+//! as build dependency:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     resource_proxy_pingora_embed::generate("assets", "assets.rs").unwrap();
+//! }
+//! ```
+//!
+//! The application can then include the table and serve it:
+//!
+//! ```ignore
+//! use resource_proxy_pingora::embedded::{EmbeddedFile, EmbeddedStorage};
+//! use resource_proxy_pingora::include_embedded;
+//!
+//! static ASSETS: &[EmbeddedFile] = include_embedded!("assets.rs");
+//!
+//! let handler = StaticFilesHandler::try_from(conf)?.with_storage(EmbeddedStorage::new(ASSETS));
+//! ```
+
+use async_trait::async_trait;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::io::{Cursor, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::path::normalize_uri;
+use crate::storage::{EntryKind, EntryStat, RangeReader, Storage};
+
+/// A file compiled into the binary, usually listed in a table generated by
+/// `resource_proxy_pingora_embed::generate()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddedFile {
+    /// Path of the file relative to the embedded directory, starting with a slash
+    pub path: &'static str,
+    /// File contents
+    pub data: &'static [u8],
+    /// Last modified time of the file at build time, in seconds since Unix epoch
+    pub modified: u64,
+    /// Hash of the file contents calculated at build time
+    pub hash: &'static str,
+}
+
+/// Includes a file table generated by `resource_proxy_pingora_embed::generate()` from the
+/// `OUT_DIR` directory.
+#[macro_export]
+macro_rules! include_embedded {
+    ($name:expr) => {
+        include!(concat!(env!("OUT_DIR"), "/", $name))
+    };
+}
+
+/// Storage backend serving files from a table compiled into the binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedStorage {
+    root: PathBuf,
+    files: HashMap<PathBuf, &'static EmbeddedFile>,
+    directories: BTreeSet<PathBuf>,
+}
+
+impl EmbeddedStorage {
+    /// Creates a new storage serving the given files.
+    pub fn new(files: &'static [EmbeddedFile]) -> Self {
+        let root = PathBuf::from("/");
+        let mut directories = BTreeSet::new();
+        directories.insert(root.clone());
+
+        let mut map = HashMap::new();
+        for file in files {
+            let path = root.join(file.path.trim_start_matches('/'));
+            let mut parent = path.parent();
+            while let Some(dir) = parent {
+                if !directories.insert(dir.to_path_buf()) {
+                    break;
+                }
+                parent = dir.parent();
+            }
+            map.insert(path, file);
+        }
+
+        Self {
+            root,
+            files: map,
+            directories,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for EmbeddedStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let path = normalize_uri(uri_path, &self.root)?;
+        if self.files.contains_key(&path) || self.directories.contains(&path) {
+            Ok(path)
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        if let Some(file) = self.files.get(path) {
+            Ok(EntryStat {
+                kind: EntryKind::File,
+                size: file.data.len() as u64,
+                modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(file.modified)),
+                etag: Some(file.hash.to_owned()),
+            })
+        } else if self.directories.contains(path) {
+            Ok(EntryStat {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: None,
+                etag: None,
+            })
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        let file = self.files.get(path).ok_or(ErrorKind::NotFound)?;
+        let data = file
+            .data
+            .get(start as usize..=end as usize)
+            .ok_or(ErrorKind::InvalidInput)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        if !self.directories.contains(path) {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        let mut result: Vec<_> = self
+            .files
+            .keys()
+            .chain(self.directories.iter())
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(|name| name.to_os_string()))
+            .collect();
+        result.sort();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+    use tokio::io::AsyncReadExt;
+
+    static FILES: &[EmbeddedFile] = &[
+        EmbeddedFile {
+            path: "/index.html",
+            data: b"<p>Hi!</p>",
+            modified: 1700000000,
+            hash: "0123456789abcdef",
+        },
+        EmbeddedFile {
+            path: "/dir/file.txt",
+            data: b"0123456789",
+            modified: 1700000000,
+            hash: "fedcba9876543210",
+        },
+    ];
+
+    #[test(tokio::test)]
+    async fn serving() {
+        let storage = EmbeddedStorage::new(FILES);
+
+        let path = storage.resolve("/dir/../dir/file.txt").await.unwrap();
+        assert_eq!(path, Path::new("/dir/file.txt"));
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 10);
+        assert_eq!(stat.etag.as_deref(), Some("fedcba9876543210"));
+        assert_eq!(
+            stat.modified,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+
+        let mut data = String::new();
+        storage
+            .open_range(&path, 2, 5)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "2345");
+        assert_eq!(
+            storage.open_range(&path, 5, 10).await.err().unwrap().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let path = storage.resolve("/dir").await.unwrap();
+        assert_eq!(
+            storage.stat(&path).await.unwrap().kind,
+            EntryKind::Directory
+        );
+        assert_eq!(
+            storage.list_dir(Path::new("/")).await.unwrap(),
+            vec![OsString::from("dir"), OsString::from("index.html")]
+        );
+        assert_eq!(
+            storage.resolve("/missing.txt").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            storage.resolve("/../index.html").await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
@@ -20,51 +20,48 @@ use log::error;
 use pingora::{Error, ErrorType};
 // use crate::session_wrapper::SessionWrapper;
 use std::cmp::min;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
 use pingora::proxy::Session;
+use tokio::io::AsyncReadExt;
+
+use crate::storage::{OpenEntry, Storage};
 
 const BUFFER_SIZE: usize = 64 * 1024;
 
 /// Writes a chunk of a file as a Pingora session response. The data will be passed through the
 /// compression handler first in case dynamic compression is enabled.
+///
+/// The response header has been sent already at this point. If the file turns out to be shorter
+/// than expected (truncated while being sent), the connection is closed without completing the
+/// response body, so that the client can detect the incomplete response.
 pub(crate) async fn file_response(
     session: &mut Session,
-    path: &Path,
+    storage: &dyn Storage,
+    entry: OpenEntry,
     start: u64,
     end: u64,
 ) -> Result<(), Box<Error>> {
-    let mut file = File::open(path).map_err(|err| {
+    let path = entry.path().to_path_buf();
+    let mut file = entry.read_range(storage, start, end).await.map_err(|err| {
         error!("failed opening file {path:?}: {err}");
         Error::new(ErrorType::HTTPStatus(
             StatusCode::INTERNAL_SERVER_ERROR.into(),
         ))
     })?;
 
-    if start != 0 {
-        file.seek(SeekFrom::Start(start)).map_err(|err| {
-            error!("failed seeking in file {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
-    }
-
     let mut remaining = (end - start + 1) as usize;
     while remaining > 0 {
         let mut buf = BytesMut::zeroed(min(remaining, BUFFER_SIZE));
-        let len = file.read(buf.as_mut()).map_err(|err| {
-            error!("failed reading data from {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
-
-        if len == 0 {
-            error!("file ended with {remaining} bytes left to be written");
-            return Err(Error::new(ErrorType::ReadError));
-        }
+        let len = match file.read(buf.as_mut()).await {
+            Ok(0) => {
+                error!("file {path:?} ended with {remaining} bytes left to be written, truncated?");
+                return abort(session, "file truncated during transfer");
+            }
+            Ok(len) => len,
+            Err(err) => {
+                error!("failed reading data from {path:?}: {err}");
+                return abort(session, "failed reading file during transfer");
+            }
+        };
 
         buf.truncate(len);
         session.write_response_body(Some(buf.into()), false).await?;
@@ -75,3 +72,70 @@ pub(crate) async fn file_response(
 
     Ok(())
 }
+
+/// Makes sure that the connection is closed after an incomplete response body.
+fn abort(session: &mut Session, reason: &'static str) -> Result<(), Box<Error>> {
+    session.set_keepalive(None);
+    Error::e_explain(ErrorType::ReadError, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pingora::http::ResponseHeader;
+    use std::fs::{self, OpenOptions};
+    use std::io::Cursor;
+    use test_log::test;
+
+    use crate::storage::LocalStorage;
+
+    async fn make_session() -> Session {
+        let request = Cursor::new(b"GET /file.txt HTTP/1.1\r\n\r\n".to_vec());
+        let mut session = Session::new_h1(Box::new(request));
+        assert!(session.read_request().await.unwrap());
+        session.set_keepalive(Some(60));
+
+        let header = ResponseHeader::build(200, None).unwrap();
+        session
+            .write_response_header(Box::new(header), false)
+            .await
+            .unwrap();
+        session
+    }
+
+    fn will_keepalive(session: &Session) -> bool {
+        session.as_http1().unwrap().will_keepalive()
+    }
+
+    #[test(tokio::test)]
+    async fn truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "0123456789").unwrap();
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let path = storage.resolve("/file.txt").await.unwrap();
+
+        let mut session = make_session().await;
+        let entry = storage.open(&path).await.unwrap();
+        file_response(&mut session, &storage, entry, 2, 9)
+            .await
+            .unwrap();
+        assert!(will_keepalive(&session));
+
+        // File shrinks between open() and sending the body
+        let mut session = make_session().await;
+        let entry = storage.open(&path).await.unwrap();
+        assert_eq!(entry.stat.size, 10);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        let err = file_response(&mut session, &storage, entry, 2, 9)
+            .await
+            .unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::ReadError);
+        assert!(!will_keepalive(&session));
+    }
+}
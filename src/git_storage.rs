@@ -0,0 +1,566 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backend serving a revision of a git repository
+
+use async_trait::async_trait;
+use git2::{ErrorCode, ObjectType, Oid, Repository};
+use http::header;
+use log::debug;
+use pingora::proxy::Session;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::io::{Cursor, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncReadExt;
+
+use crate::path::normalize_uri;
+use crate::storage::{EntryKind, EntryStat, RangeReader, Storage};
+
+/// File mode git uses for symbolic links
+const GIT_FILEMODE_LINK: i32 = 0o120000;
+
+/// Maximal total size of the blobs kept in memory. A request usually reads a file more than once,
+/// e.g. for content sniffing and for the response.
+const BLOB_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+thread_local! {
+    /// Repositories opened by the current thread, [`Repository`] cannot be shared between threads
+    static REPOSITORIES: RefCell<HashMap<PathBuf, Repository>> = RefCell::new(HashMap::new());
+}
+
+/// Determines how the git revision to be served is selected for a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GitRefSelector {
+    /// Name of a request header containing the branch, tag or commit to serve
+    pub header: Option<String>,
+    /// If set, the subdomain of this domain selects the branch or tag to serve, e.g. with
+    /// `docs.example.com` the host name `feature-x.docs.example.com` serves branch `feature-x`.
+    /// Other host names get the default revision.
+    pub domain: Option<String>,
+}
+
+/// Recently read blob contents, blobs are immutable so entries never become stale
+#[derive(Debug, Default)]
+struct BlobCache {
+    size: usize,
+    blobs: VecDeque<(Oid, Arc<[u8]>)>,
+}
+
+impl BlobCache {
+    fn get(&self, oid: Oid) -> Option<Arc<[u8]>> {
+        self.blobs
+            .iter()
+            .find(|(id, _)| *id == oid)
+            .map(|(_, data)| data.clone())
+    }
+
+    fn insert(&mut self, oid: Oid, data: Arc<[u8]>) {
+        if data.len() > BLOB_CACHE_SIZE {
+            return;
+        }
+        while self.size + data.len() > BLOB_CACHE_SIZE {
+            let Some((_, removed)) = self.blobs.pop_front() else {
+                break;
+            };
+            self.size -= removed.len();
+        }
+        self.size += data.len();
+        self.blobs.push_back((oid, data));
+    }
+}
+
+/// Storage backend serving the tree of a git revision from a local (typically bare) repository
+/// without checking it out.
+///
+/// Each thread opens the repository on its own, so that requests don’t wait for each other. The
+/// ETags of the files are the blob object IDs, last modified time is the commit time.
+#[derive(Clone)]
+pub struct GitStorage {
+    blobs: Arc<Mutex<BlobCache>>,
+    repo_path: PathBuf,
+    root: PathBuf,
+    default_ref: Option<String>,
+    commit: Oid,
+    commit_time: Option<SystemTime>,
+    selector: GitRefSelector,
+}
+
+impl GitStorage {
+    /// Opens the repository at `repo_path` and resolves the default revision `default_ref`, this
+    /// can be a branch, tag or any other revision specifier supported by git. The default revision
+    /// is resolved again for each request, so that new commits on a branch are served without a
+    /// restart.
+    pub fn new(
+        repo_path: impl AsRef<Path>,
+        default_ref: &str,
+        selector: GitRefSelector,
+    ) -> Result<Self, Error> {
+        let repo_path = repo_path.as_ref().to_path_buf();
+        let repo = Repository::open(&repo_path).map_err(git_error)?;
+        let (commit, commit_time) = resolve_revision(&repo, default_ref)?;
+
+        Ok(Self {
+            blobs: Default::default(),
+            repo_path,
+            root: PathBuf::from("/"),
+            default_ref: Some(default_ref.to_owned()),
+            commit,
+            commit_time,
+            selector,
+        })
+    }
+
+    /// Commit being served by this storage
+    pub fn commit(&self) -> Oid {
+        self.commit
+    }
+
+    /// Creates a view of the same repository for a different revision. Unlike the default
+    /// revision, this has to be a branch, a tag or a full commit ID, other revision specifiers
+    /// are rejected as they come from untrusted requests.
+    pub fn with_ref(&self, name: &str) -> Result<Self, Error> {
+        let (commit, commit_time) = self.with_repo(|repo| {
+            let commit = if let Some(oid) = Oid::from_str(name).ok().filter(|_| name.len() == 40) {
+                repo.find_commit(oid)
+            } else {
+                repo.resolve_reference_from_short_name(name)
+                    .and_then(|reference| reference.peel_to_commit())
+            }
+            .map_err(git_error)?;
+            Ok((commit.id(), system_time(commit.time().seconds())))
+        })?;
+
+        Ok(self.view(commit, commit_time))
+    }
+
+    /// Creates a view of the same repository fixed to the given commit.
+    fn view(&self, commit: Oid, commit_time: Option<SystemTime>) -> Self {
+        Self {
+            blobs: self.blobs.clone(),
+            repo_path: self.repo_path.clone(),
+            root: self.root.clone(),
+            default_ref: None,
+            commit,
+            commit_time,
+            selector: GitRefSelector::default(),
+        }
+    }
+
+    /// Runs the callback with the current thread’s handle of the repository, opening it if
+    /// necessary.
+    fn with_repo<T>(
+        &self,
+        callback: impl FnOnce(&Repository) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        REPOSITORIES.with(|repos| {
+            let mut repos = repos.borrow_mut();
+            let repo = match repos.entry(self.repo_path.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Repository::open(&self.repo_path).map_err(git_error)?)
+                }
+            };
+            callback(repo)
+        })
+    }
+
+    /// Retrieves the contents of a blob, from the cache if possible.
+    fn blob(&self, repo: &Repository, oid: Oid) -> Result<Arc<[u8]>, Error> {
+        let cached = self
+            .blobs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(oid);
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+
+        let data: Arc<[u8]> = repo.find_blob(oid).map_err(git_error)?.content().into();
+        self.blobs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(oid, data.clone());
+        Ok(data)
+    }
+
+    fn requested_ref<'a>(&self, session: &'a Session) -> Option<&'a str> {
+        let headers = &session.req_header().headers;
+        if let Some(name) = &self.selector.header {
+            if let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) {
+                return Some(value);
+            }
+        }
+
+        if let Some(domain) = &self.selector.domain {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|value| value.to_str().ok())
+                .or_else(|| session.req_header().uri.host())?;
+            return subdomain(host, domain);
+        }
+
+        None
+    }
+
+    /// Runs the callback for the tree entry corresponding to the given path. For the root path,
+    /// `None` is passed to the callback.
+    fn with_entry<T>(
+        &self,
+        path: &Path,
+        callback: impl FnOnce(&Repository, Option<&git2::TreeEntry<'_>>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let rel_path = path
+            .strip_prefix(&self.root)
+            .map_err(|_| ErrorKind::InvalidData)?;
+
+        self.with_repo(|repo| {
+            let tree = repo
+                .find_commit(self.commit)
+                .and_then(|commit| commit.tree())
+                .map_err(git_error)?;
+
+            if rel_path.as_os_str().is_empty() {
+                callback(repo, None)
+            } else {
+                let entry = tree.get_path(rel_path).map_err(git_error)?;
+                callback(repo, Some(&entry))
+            }
+        })
+    }
+}
+
+/// Resolves a revision specifier into the commit it refers to and the commit time.
+fn resolve_revision(repo: &Repository, spec: &str) -> Result<(Oid, Option<SystemTime>), Error> {
+    let commit = repo
+        .revparse_single(spec)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(git_error)?;
+    Ok((commit.id(), system_time(commit.time().seconds())))
+}
+
+impl std::fmt::Debug for GitStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitStorage")
+            .field("repo_path", &self.repo_path)
+            .field("default_ref", &self.default_ref)
+            .field("commit", &self.commit)
+            .field("selector", &self.selector)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Storage for GitStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn request_view(&self, session: &Session) -> Result<Option<Arc<dyn Storage>>, Error> {
+        if let Some(name) = self.requested_ref(session) {
+            debug!("serving git revision {name}");
+            return Ok(Some(Arc::new(self.with_ref(name)?)));
+        }
+
+        // The request is served from the commit the default revision points to right now
+        let Some(default_ref) = &self.default_ref else {
+            return Ok(None);
+        };
+        let (commit, commit_time) = self.with_repo(|repo| resolve_revision(repo, default_ref))?;
+        if commit == self.commit {
+            Ok(None)
+        } else {
+            Ok(Some(Arc::new(self.view(commit, commit_time))))
+        }
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let path = normalize_uri(uri_path, &self.root)?;
+        self.stat(&path).await?;
+        Ok(path)
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        self.with_entry(path, |repo, entry| {
+            let (kind, size, etag) = match entry {
+                None => (EntryKind::Directory, 0, None),
+                Some(entry) => match entry.kind() {
+                    Some(ObjectType::Tree) => (EntryKind::Directory, 0, None),
+                    Some(ObjectType::Blob) if entry.filemode() != GIT_FILEMODE_LINK => {
+                        // Only the object header is read, the contents might be large
+                        let (size, _) = repo
+                            .odb()
+                            .and_then(|odb| odb.read_header(entry.id()))
+                            .map_err(git_error)?;
+                        (EntryKind::File, size as u64, Some(entry.id().to_string()))
+                    }
+                    _ => (EntryKind::Other, 0, None),
+                },
+            };
+
+            Ok(EntryStat {
+                kind,
+                size,
+                modified: self.commit_time,
+                etag,
+            })
+        })
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        let data = self.with_entry(path, |repo, entry| {
+            let entry = entry.ok_or(ErrorKind::InvalidInput)?;
+            self.blob(repo, entry.id())
+        })?;
+        if end < start || end >= data.len() as u64 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        // The reader shares the cached contents rather than copying the range
+        let mut reader = Cursor::new(data);
+        reader.set_position(start);
+        Ok(Box::new(reader.take(end - start + 1)))
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        self.with_entry(path, |repo, entry| {
+            let tree = match entry {
+                None => repo
+                    .find_commit(self.commit)
+                    .and_then(|commit| commit.tree()),
+                Some(entry) => repo.find_tree(entry.id()),
+            }
+            .map_err(git_error)?;
+
+            let mut result: Vec<_> = tree
+                .iter()
+                .filter_map(|entry| entry.name().map(OsString::from))
+                .collect();
+            result.sort();
+            Ok(result)
+        })
+    }
+}
+
+/// Extracts the subdomain label from a host name (optionally with a port), `None` if the host
+/// isn’t a direct subdomain of the given domain.
+fn subdomain<'a>(host: &'a str, domain: &str) -> Option<&'a str> {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let domain = domain.trim_matches('.');
+
+    let label_len = host.len().checked_sub(domain.len())?;
+    if !host.get(label_len..)?.eq_ignore_ascii_case(domain) {
+        return None;
+    }
+    let label = host[..label_len].strip_suffix('.')?;
+    Some(label).filter(|label| !label.is_empty() && !label.contains('.'))
+}
+
+fn git_error(err: git2::Error) -> Error {
+    let kind = match err.code() {
+        ErrorCode::NotFound => ErrorKind::NotFound,
+        ErrorCode::InvalidSpec => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    Error::new(kind, err)
+}
+
+fn system_time(seconds: i64) -> Option<SystemTime> {
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use git2::Signature;
+    use test_log::test;
+
+    fn make_repo(dir: &Path) -> Oid {
+        let repo = Repository::init_bare(dir).unwrap();
+
+        let file = repo.blob(b"Hi!\n").unwrap();
+        let index = repo.blob(b"<html>Hi!</html>\n").unwrap();
+        let mut subdir = repo.treebuilder(None).unwrap();
+        subdir.insert("index.html", index, 0o100644).unwrap();
+        let subdir = subdir.write().unwrap();
+
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("file.txt", file, 0o100644).unwrap();
+        tree.insert("subdir", subdir, 0o040000).unwrap();
+        tree.insert("link", file, GIT_FILEMODE_LINK).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])
+            .unwrap();
+
+        let mut tree = repo.treebuilder(Some(&tree)).unwrap();
+        tree.remove("file.txt").unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let commit = repo
+            .commit(None, &signature, &signature, "Second", &tree, &[&parent])
+            .unwrap();
+        repo.branch("preview", &repo.find_commit(commit).unwrap(), false)
+            .unwrap();
+
+        file
+    }
+
+    #[test(tokio::test)]
+    async fn serve_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_id = make_repo(dir.path());
+
+        let storage = GitStorage::new(dir.path(), "HEAD", GitRefSelector::default()).unwrap();
+
+        let path = storage.resolve("/file.txt").await.unwrap();
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 4);
+        assert_eq!(stat.etag, Some(file_id.to_string()));
+
+        let mut data = String::new();
+        storage
+            .open_range(&path, 0, 2)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "Hi!");
+
+        // Contents are read once and then served from memory
+        let mut data = String::new();
+        storage
+            .open_range(&path, 1, 3)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "i!\n");
+        assert_eq!(storage.blobs.lock().unwrap().blobs.len(), 1);
+        assert_eq!(
+            storage.open_range(&path, 2, 4).await.err().unwrap().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let path = storage.resolve("/subdir").await.unwrap();
+        assert_eq!(
+            storage.stat(&path).await.unwrap().kind,
+            EntryKind::Directory
+        );
+        assert_eq!(
+            storage.list_dir(Path::new("/")).await.unwrap(),
+            vec![
+                OsString::from("file.txt"),
+                OsString::from("link"),
+                OsString::from("subdir"),
+            ]
+        );
+
+        let path = storage.resolve("/link").await.unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap().kind, EntryKind::Other);
+
+        let preview = storage.with_ref("preview").unwrap();
+        assert_eq!(
+            preview.resolve("/file.txt").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(preview.resolve("/subdir/index.html").await.is_ok());
+
+        assert!(storage.with_ref("HEAD~1").is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn default_ref_update() {
+        let dir = tempfile::tempdir().unwrap();
+        make_repo(dir.path());
+        let storage = GitStorage::new(dir.path(), "HEAD", GitRefSelector::default()).unwrap();
+
+        let request = Cursor::new(b"GET /file.txt HTTP/1.1\r\n\r\n".to_vec());
+        let mut session = Session::new_h1(Box::new(request));
+        assert!(session.read_request().await.unwrap());
+        assert!(storage.request_view(&session).await.unwrap().is_none());
+
+        // Requests are served from the commit HEAD points to now
+        let repo = Repository::open(dir.path()).unwrap();
+        repo.set_head("refs/heads/preview").unwrap();
+        let view = storage.request_view(&session).await.unwrap().unwrap();
+        assert_eq!(
+            view.resolve("/file.txt").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(storage.resolve("/file.txt").await.is_ok());
+    }
+
+    #[test]
+    fn subdomains() {
+        let domain = "docs.example.com";
+        assert_eq!(
+            subdomain("feature-x.docs.example.com", domain),
+            Some("feature-x")
+        );
+        assert_eq!(
+            subdomain("Feature-X.Docs.Example.com:8443", domain),
+            Some("Feature-X")
+        );
+        assert_eq!(
+            subdomain("feature-x.docs.example.com.", ".docs.example.com"),
+            Some("feature-x")
+        );
+        assert_eq!(subdomain("docs.example.com", domain), None);
+        assert_eq!(subdomain("a.b.docs.example.com", domain), None);
+        assert_eq!(subdomain("feature-x.evil.com", domain), None);
+        assert_eq!(subdomain("feature-xdocs.example.com", domain), None);
+        assert_eq!(subdomain("127.0.0.1", domain), None);
+    }
+
+    #[test]
+    fn threads() {
+        let dir = tempfile::tempdir().unwrap();
+        make_repo(dir.path());
+
+        // Each thread opens the repository on its own
+        let storage =
+            Arc::new(GitStorage::new(dir.path(), "HEAD", GitRefSelector::default()).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    runtime
+                        .block_on(storage.stat(Path::new("/file.txt")))
+                        .unwrap()
+                        .size
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 4);
+        }
+    }
+}
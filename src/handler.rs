@@ -15,22 +15,45 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::{method::Method, status::StatusCode};
+use http::{header, method::Method, status::StatusCode};
 use log::{debug, info, warn};
 use pingora::{Error, ErrorType};
 // use crate::session_wrapper::SessionWrapper;
-use crate::standard_response::{error_response, redirect_response};
+use crate::standard_response::{error_response, redirect_response, unauthorized_response};
 use crate::request_filter::{RequestFilter, RequestFilterResult};
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use pingora::proxy::Session;
+use crate::access::AccessRules;
+use crate::access_log::AccessLog;
+use crate::archive::ArchiveStorage;
+use crate::basic_auth::BasicAuthRules;
 use crate::compression::Compression;
-use crate::configuration::StaticFilesConf;
+use crate::configuration::{DispositionType, StaticFilesConf};
+use crate::disposition::{has_download_param, DispositionRules};
 use crate::file_writer::file_response;
+#[cfg(feature = "git")]
+use crate::git_storage::{GitRefSelector, GitStorage};
+use crate::ip_access::IpAccessRules;
+use crate::jwt_auth::{Denial, JwtAuthRules};
 use crate::metadata::Metadata;
+use crate::metrics::{metrics_response, Outcome, METRICS};
+use crate::mime_map::MimeMap;
 use crate::mime_matcher::MimeMatcher;
-use crate::path::{path_to_uri, resolve_uri};
+use crate::overrides::{DirectoryOverrides, OverridesCache, OVERRIDES_FILE};
+#[cfg(feature = "object-store")]
+use crate::object_storage::ObjectStoreStorage;
+use crate::path::normalize_uri;
 use crate::range::{extract_range, Range};
+use crate::redirects::{parse_netlify, Redirects};
+use crate::signed_url::SignedUrlRules;
+use crate::sniff::SniffCache;
+use crate::storage::{EntryKind, LayeredStorage, LocalStorage, Storage, SymlinkRootStorage};
+use crate::telemetry::Stage;
+use crate::try_files::{Resolved, TryFilesRules};
 use crate::CompressionAlgorithm;
 
 const DEFAULT_TEXT_TYPES: &[&str] = &[
@@ -42,61 +65,448 @@ const DEFAULT_TEXT_TYPES: &[&str] = &[
     "application/json5",
 ];
 
+/// Per-request state of the static files handler
+#[derive(Debug)]
+pub struct StaticFilesCtx {
+    /// Time the request processing started
+    pub received: Instant,
+    /// Whether the response was produced by this handler
+    pub handled: bool,
+    /// Storage path of the file served
+    pub path: Option<PathBuf>,
+    /// Compression algorithm if a pre-compressed file was served
+    pub encoding: Option<CompressionAlgorithm>,
+    /// Byte range served, both `start` and `end` are inclusive
+    pub range: Option<(u64, u64)>,
+    /// User authenticated via HTTP Basic authentication or the subject of a bearer token
+    pub user: Option<String>,
+}
+
 /// Static Files module handler
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct StaticFilesHandler {
-    root: Option<PathBuf>,
+    conf: Arc<StaticFilesConf>,
+    files: Arc<RwLock<Arc<FileState>>>,
+    storage: Option<Arc<dyn Storage>>,
+    access: AccessRules,
     canonicalize_uri: bool,
     index_file: Vec<String>,
     page_404: Option<String>,
+    try_files: TryFilesRules,
+    directory_overrides: Option<Arc<OverridesCache>>,
+    sniff: Option<Arc<SniffCache>>,
+    disposition: DispositionRules,
+    download_query: bool,
+    metrics_path: Option<String>,
+    access_log: Option<Arc<AccessLog>>,
+    basic_auth: Arc<BasicAuthRules>,
+    signed_urls: Arc<SignedUrlRules>,
+    ip_access: Arc<IpAccessRules>,
     precompressed: Vec<CompressionAlgorithm>,
     declare_charset: String,
     declare_charset_matcher: MimeMatcher,
 }
 
+impl StaticFilesHandler {
+    /// Replaces the storage backend files are served from. Without a storage backend the handler
+    /// leaves all requests unhandled.
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    /// Returns the configuration the handler was created from.
+    pub(crate) fn conf(&self) -> &StaticFilesConf {
+        &self.conf
+    }
+
+    /// Returns the storage backend files are served from.
+    pub(crate) fn storage(&self) -> Option<&Arc<dyn Storage>> {
+        self.storage.as_ref()
+    }
+
+    /// Returns the state read from files, as of handler creation or the last reload.
+    fn files(&self) -> Arc<FileState> {
+        self.files
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the time of handler creation or the last reload.
+    pub(crate) fn last_reload(&self) -> SystemTime {
+        self.files().loaded
+    }
+
+    /// Removes cache entries for the files where `matches` returns `true` for the URI path,
+    /// returns the number of entries removed.
+    pub(crate) fn purge(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let Some(storage) = &self.storage else {
+            return 0;
+        };
+        let matches_path = |path: &Path| {
+            storage
+                .path_to_uri(path, false)
+                .is_some_and(|uri| matches(&uri))
+        };
+
+        let mut purged = 0;
+        if let Some(cache) = &self.directory_overrides {
+            purged += cache.purge(matches_path);
+        }
+        if let Some(cache) = &self.sniff {
+            purged += cache.purge(matches_path);
+        }
+        purged
+    }
+
+    /// Reads `_redirects` files, the MIME types file and JWT key files again and drops all cached
+    /// state so that it is read from storage again. Returns the number of cache entries removed.
+    /// If reading any of the files fails, the previous state is kept. The configuration itself
+    /// isn’t reloaded.
+    pub(crate) fn reload(&self) -> Result<usize, Box<Error>> {
+        let files = FileState::load(&self.conf)?;
+        *self.files.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(files);
+        let purged = self.purge(|_| true);
+        info!("reloaded static files handler, {purged} cache entries dropped");
+        Ok(purged)
+    }
+
+    /// Returns the number of cache entries held.
+    pub(crate) fn cache_entries(&self) -> usize {
+        self.directory_overrides
+            .as_ref()
+            .map_or(0, |cache| cache.entry_count())
+            + self.sniff.as_ref().map_or(0, |cache| cache.entry_count())
+    }
+
+    /// Checks HTTP Basic authentication if the request path requires it, recording the user in
+    /// the context. Returns the `WWW-Authenticate` challenge to respond with on failure.
+    async fn check_basic_auth(
+        &self,
+        session: &Session,
+        ctx: &mut StaticFilesCtx,
+        uri_path: &str,
+    ) -> Result<(), String> {
+        let Some(auth) = self.basic_auth.find(uri_path) else {
+            return Ok(());
+        };
+
+        let authorization = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match auth.authenticate(authorization).await {
+            Some(user) => {
+                ctx.user = Some(user);
+                Ok(())
+            }
+            None => Err(auth.challenge()),
+        }
+    }
+
+    /// Checks the bearer token if the request path requires it, recording the token subject in
+    /// the context.
+    fn check_jwt_auth(
+        &self,
+        session: &Session,
+        ctx: &mut StaticFilesCtx,
+        uri_path: &str,
+    ) -> Result<(), Denial> {
+        let files = self.files();
+        let Some(auth) = files.jwt_auth.find(uri_path) else {
+            return Ok(());
+        };
+
+        let subject = auth.authorize(&session.req_header().headers, uri_path)?;
+        if subject.is_some() {
+            ctx.user = subject;
+        }
+        Ok(())
+    }
+
+    /// Determines the client address, taking forwarding headers of trusted proxies into account.
+    fn client_ip(&self, session: &Session) -> Option<IpAddr> {
+        let peer = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        self.ip_access.client_ip(peer, &session.req_header().headers)
+    }
+
+    /// Checks the client address against the IP access rules for the path, returns `false` if
+    /// access is denied.
+    fn check_ip_access(&self, session: &Session, uri_path: &str) -> bool {
+        let Some(rule) = self.ip_access.find(uri_path) else {
+            return true;
+        };
+        let client_ip = self.client_ip(session);
+        let allowed = rule.allows(client_ip);
+        if !allowed {
+            info!("denying access to {uri_path} for client {client_ip:?}");
+        }
+        allowed
+    }
+
+    /// Checks the URL signature if the path requires it, returns the response status if the
+    /// signature is missing, invalid or expired. The signature always covers the request path,
+    /// `uri_path` is merely used to find the applicable rule.
+    fn check_signature(&self, session: &Session, uri_path: &str) -> Option<StatusCode> {
+        let rule = self.signed_urls.find(uri_path)?;
+        let uri = &session.req_header().uri;
+        let client_ip = self.client_ip(session);
+        let status = rule
+            .verify(uri.path(), uri.query(), client_ip, SystemTime::now())
+            .err()?;
+        info!("rejecting request to {uri_path} without a valid URL signature");
+        Some(status)
+    }
+
+    /// Checks a request path against the access rules, returns the response status if access is
+    /// denied.
+    fn check_access(&self, uri_path: &str) -> Option<StatusCode> {
+        let (status, rule) = self.access.check(uri_path)?;
+        info!("denying access to {uri_path}, matched rule `{rule}`");
+        Some(status)
+    }
+}
+
+impl PartialEq for StaticFilesHandler {
+    fn eq(&self, other: &Self) -> bool {
+        let same_storage = match (&self.storage, &other.storage) {
+            (Some(storage), Some(other)) => Arc::ptr_eq(storage, other),
+            (None, None) => true,
+            _ => false,
+        };
+        same_storage
+            && self.conf == other.conf
+            && self.access == other.access
+            && self.canonicalize_uri == other.canonicalize_uri
+            && self.index_file == other.index_file
+            && self.page_404 == other.page_404
+            && self.try_files == other.try_files
+            && Arc::ptr_eq(&self.files, &other.files)
+            && self.directory_overrides.is_some() == other.directory_overrides.is_some()
+            && self.sniff.is_some() == other.sniff.is_some()
+            && self.disposition == other.disposition
+            && self.download_query == other.download_query
+            && self.metrics_path == other.metrics_path
+            && self.access_log.is_some() == other.access_log.is_some()
+            && Arc::ptr_eq(&self.basic_auth, &other.basic_auth)
+            && Arc::ptr_eq(&self.signed_urls, &other.signed_urls)
+            && Arc::ptr_eq(&self.ip_access, &other.ip_access)
+            && self.precompressed == other.precompressed
+            && self.declare_charset == other.declare_charset
+            && self.declare_charset_matcher == other.declare_charset_matcher
+    }
+}
 
+impl Eq for StaticFilesHandler {}
 
 #[async_trait]
 impl RequestFilter for StaticFilesHandler {
     type Conf = StaticFilesConf;
 
-    type CTX = ();
+    type CTX = StaticFilesCtx;
+
+    fn new_ctx() -> Self::CTX {
+        StaticFilesCtx {
+            received: Instant::now(),
+            handled: false,
+            path: None,
+            encoding: None,
+            range: None,
+            user: None,
+        }
+    }
+
+    async fn early_request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        // Paths that cannot be normalized cannot be matched against path rules either
+        let uri_path = session.req_header().uri.path();
+        if normalize_uri(uri_path, Path::new("/")).is_err() {
+            warn!("rejecting invalid path {uri_path}");
+            error_response(session, StatusCode::BAD_REQUEST).await?;
+            ctx.handled = true;
+            METRICS.record_response(session);
+            return Ok(());
+        }
 
-    fn new_ctx() -> Self::CTX {}
+        // Deny before the storage is touched, request_filter will stop processing then
+        if !self.check_ip_access(session, session.req_header().uri.path()) {
+            error_response(session, StatusCode::FORBIDDEN).await?;
+            ctx.handled = true;
+            METRICS.record_response(session);
+        }
+        Ok(())
+    }
 
     async fn request_filter(
         &self,
         session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if ctx.handled {
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if self.metrics_path.as_deref() == Some(session.req_header().uri.path()) {
+            metrics_response(session).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let span = Stage::request(session);
+        let result = span.run(self.serve(session, ctx)).await;
+        if let Ok(RequestFilterResult::ResponseSent) = result {
+            ctx.handled = true;
+            METRICS.record_response(session);
+            if let Some(header) = session.response_written() {
+                span.record("http.response.status_code", header.status.as_u16());
+            }
+        }
+        result
+    }
+
+    async fn logging(&self, session: &mut Session, _e: Option<&Error>, ctx: &mut Self::CTX) {
+        if let Some(access_log) = &self.access_log {
+            if ctx.handled {
+                access_log.log(session, self.client_ip(session), ctx);
+            }
+        }
+    }
+}
+
+impl StaticFilesHandler {
+    /// Serves the request, recording information about the response in the context.
+    async fn serve(
+        &self,
+        session: &mut Session,
+        ctx: &mut StaticFilesCtx,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        let root = if let Some(root) = self.root.as_ref() {
-            root
+        let storage = if let Some(storage) = self.storage.as_deref() {
+            storage
         } else {
             debug!("received request but static files handler is not configured, ignoring");
             return Ok(RequestFilterResult::Unhandled);
         };
 
+        let view = match storage.request_view(session).await {
+            Ok(view) => view,
+            Err(err) => {
+                let status = if err.kind() == ErrorKind::NotFound {
+                    debug!("no storage view for this request: {err}");
+                    StatusCode::NOT_FOUND
+                } else {
+                    warn!("failed selecting storage view: {err}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                error_response(session, status).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        };
+        let storage = view.as_deref().unwrap_or(storage);
+
         let uri = &session.req_header().uri;
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
-            Ok(path) => (path, false),
+        // Check access rules before touching the storage, the path will be resolved lexically
+        if let Some(status) = self.check_access(uri.path()) {
+            error_response(session, status).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if let Err(challenge) = self.check_basic_auth(session, ctx, uri.path()).await {
+            unauthorized_response(session, &challenge).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if let Err(denial) = self.check_jwt_auth(session, ctx, uri.path()) {
+            deny_response(session, denial).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if let Some(status) = self.check_signature(session, uri.path()) {
+            error_response(session, status).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let files = self.files();
+        if let Some(redirect) = files.redirects.find(uri.path(), uri.query()) {
+            // Redirects that aren't forced don't apply if the requested file exists
+            let applies = redirect.force
+                || matches!(
+                    storage.resolve(uri.path()).await,
+                    Err(err) if err.kind() == ErrorKind::NotFound
+                );
+            if applies {
+                info!("redirecting to {}", redirect.location);
+                redirect_response(session, redirect.status, &redirect.location).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        }
+
+        let stage = Stage::new("resolve");
+        let resolved = if let Some(rule) = self.try_files.find(uri.path()) {
+            stage.run(rule.resolve(storage, uri.path())).await
+        } else {
+            stage
+                .run(storage.resolve(uri.path()))
+                .await
+                .map(|path| Resolved::Path(path, true))
+        };
+        if let Ok(Resolved::Path(path, _)) = &resolved {
+            stage.record("path", path.display());
+        }
+
+        let resolved = match resolved {
+            Ok(Resolved::Path(path, is_request_uri)) => Ok((path, is_request_uri)),
+            Ok(Resolved::Status(StatusCode::NOT_FOUND)) => Err(ErrorKind::NotFound.into()),
+            Ok(Resolved::Status(status)) => {
+                debug!("try_files resulted in status {status}");
+                error_response(session, status).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            Err(err) => Err(err),
+        };
+
+        // Clean URLs found via try_files are canonical, only the request path itself is subject
+        // to canonicalization
+        if let Err(err) = &resolved {
+            METRICS.record_resolution_error(err.kind());
+        }
+        let mut overrides = None;
+        let (mut path, not_found, canonicalize) = match resolved {
+            Ok((path, is_request_uri)) => (path, false, is_request_uri),
             Err(err) if err.kind() == ErrorKind::NotFound => {
                 debug!("canonicalizing resulted in NotFound error");
 
-                let path = self.page_404.as_ref().and_then(|page_404| {
+                if let Some(cache) = &self.directory_overrides {
+                    overrides = Some(cache.collect_for_uri(storage, uri.path()).await);
+                }
+                let page_404 = overrides
+                    .as_ref()
+                    .and_then(|overrides| overrides.page_404.as_ref())
+                    .or(self.page_404.as_ref());
+
+                let path = if let Some(page_404) = page_404 {
                     debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
+                    match storage.resolve(page_404).await {
                         Ok(path) => Some(path),
                         Err(err) => {
                             warn!("Failed resolving error page {page_404}: {err}");
                             None
                         }
                     }
-                });
+                } else {
+                    None
+                };
 
                 if let Some(path) = path {
-                    (path, true)
+                    (path, true, false)
                 } else {
                     error_response(session, StatusCode::NOT_FOUND).await?;
                     return Ok(RequestFilterResult::ResponseSent);
@@ -128,8 +538,39 @@ impl RequestFilter for StaticFilesHandler {
 
         debug!("translated into file path {path:?}");
 
-        if self.canonicalize_uri && !not_found {
-            if let Some(mut canonical) = path_to_uri(&path, root) {
+        // Symbolic links might have led to a different location, check the access rules and
+        // authentication again
+        let resolved_uri = storage.path_to_uri(&path, false).filter(|_| !not_found);
+        if let Some(resolved_uri) = &resolved_uri {
+            if let Some(status) = self.check_access(resolved_uri) {
+                error_response(session, status).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            if let Err(challenge) = self.check_basic_auth(session, ctx, resolved_uri).await {
+                unauthorized_response(session, &challenge).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            if let Err(denial) = self.check_jwt_auth(session, ctx, resolved_uri) {
+                deny_response(session, denial).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            if let Some(status) = self.check_signature(session, resolved_uri) {
+                error_response(session, status).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            if !self.check_ip_access(session, resolved_uri) {
+                error_response(session, StatusCode::FORBIDDEN).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        }
+
+        let is_dir = Stage::new("canonicalize")
+            .run(storage.stat(&path))
+            .await
+            .is_ok_and(|stat| stat.kind == EntryKind::Directory);
+
+        if self.canonicalize_uri && canonicalize {
+            if let Some(mut canonical) = storage.path_to_uri(&path, is_dir) {
                 if canonical != uri.path() {
                     if let Some(query) = uri.query() {
                         canonical.push('?');
@@ -153,10 +594,25 @@ impl RequestFilter for StaticFilesHandler {
             }
         }
 
-        if path.is_dir() {
-            for filename in &self.index_file {
+        let overrides = match (overrides, &self.directory_overrides) {
+            (Some(overrides), _) => overrides,
+            (None, Some(cache)) => {
+                let dir = if is_dir {
+                    path.as_path()
+                } else {
+                    path.parent().unwrap_or(path.as_path())
+                };
+                cache.collect(storage, dir).await
+            }
+            (None, None) => DirectoryOverrides::default(),
+        };
+
+        if is_dir {
+            let stage = Stage::new("index");
+            let index_file = overrides.index_file.as_deref().unwrap_or(&self.index_file);
+            for filename in index_file {
                 let candidate = path.join(filename);
-                if candidate.is_file() {
+                if stage.run(storage.is_file(&candidate)).await {
                     debug!("using directory index file {filename}");
                     path = candidate;
                 }
@@ -178,45 +634,117 @@ impl RequestFilter for StaticFilesHandler {
 
         let mut compression = Compression::new(session, &self.precompressed);
 
-        let (path, orig_path) =
-            if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
-                (precompressed_path, Some(path))
-            } else {
-                (path, None)
-            };
+        let stage = Stage::new("precompressed");
+        let rewritten = stage.run(compression.rewrite_path(session, storage, &path)).await;
+        let (path, orig_path) = match rewritten {
+            Some(precompressed_path) => (precompressed_path, Some(path)),
+            None => (path, None),
+        };
+        ctx.encoding = compression.precompressed_active();
+        if let Some(algorithm) = ctx.encoding {
+            stage.record("encoding", algorithm);
+            METRICS.record_precompressed(algorithm);
+        }
 
-        let meta = match Metadata::from_path(&path, orig_path.as_ref()) {
-            Ok(meta) => meta,
+        // Metadata and response body have to come from the same file handle, the file might be
+        // replaced in between otherwise
+        let stage = Stage::new("metadata");
+        stage.record("path", path.display());
+        let entry = stage.run(storage.open(&path)).await;
+        let mime_path = orig_path.as_deref().unwrap_or(path.as_path());
+        let mime_path = storage.relative_path(mime_path).unwrap_or(mime_path);
+        let entry = match (entry, files.mime_map.lookup(mime_path), &self.sniff) {
+            (Ok(entry), Some(mime), _) => Ok((entry, mime)),
+            (Ok(mut entry), None, Some(sniff)) => {
+                let mime = if entry.stat.kind != EntryKind::File {
+                    None
+                } else if let Some(orig_path) = &orig_path {
+                    // Pre-compressed variants cannot be sniffed, their contents are compressed.
+                    // Sniff the original file instead, this shares its cache entry.
+                    match stage.run(storage.open(orig_path)).await {
+                        Ok(mut original) if original.stat.kind == EntryKind::File => {
+                            stage.run(sniff.sniff(storage, &mut original)).await
+                        }
+                        _ => None,
+                    }
+                } else {
+                    stage.run(sniff.sniff(storage, &mut entry)).await
+                };
+                let mime = mime.unwrap_or_else(|| files.mime_map.default_type().clone());
+                Ok((entry, mime))
+            }
+            (Ok(entry), None, _) => Ok((entry, files.mime_map.default_type().clone())),
+            (Err(err), _, _) => Err(err),
+        };
+        let result = entry.and_then(|(entry, mime)| {
+            Metadata::from_stat_with_mime(&entry.stat, mime).map(|meta| (meta, entry))
+        });
+        if let Err(err) = &result {
+            METRICS.record_resolution_error(err.kind());
+        }
+        let (mut meta, entry) = match result {
+            Ok(result) => {
+                stage.record("size", result.0.size);
+                ctx.path = Some(path.clone());
+                result
+            }
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                debug!("opening path {path:?} resulted in PermissionDenied error");
+                error_response(session, StatusCode::FORBIDDEN).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
             Err(err) if err.kind() == ErrorKind::InvalidInput => {
                 warn!("Path {path:?} is not a regular file, denying access");
                 error_response(session, StatusCode::FORBIDDEN).await?;
                 return Ok(RequestFilterResult::ResponseSent);
             }
             Err(err) => {
-                warn!("failed retrieving metadata for path {path:?}: {err}");
+                warn!("failed opening path {path:?}: {err}");
                 error_response(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
                 return Ok(RequestFilterResult::ResponseSent);
             }
         };
 
-        if meta.has_failed_precondition(session) {
+        let uri = &session.req_header().uri;
+        let disposition = if self.download_query && has_download_param(uri.query()) {
+            Some(DispositionType::Attachment)
+        } else {
+            self.disposition.find(uri.path(), &meta.mime)
+        };
+        if let Some(disposition) = disposition {
+            if let Some(filename) = mime_path.file_name() {
+                meta.set_disposition(disposition, &filename.to_string_lossy());
+            }
+        }
+
+        let stage = Stage::new("conditional");
+        if stage.in_scope(|| meta.has_failed_precondition(session)) {
             debug!("If-Match/If-Unmodified-Since precondition failed");
             let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
+            let header = overrides.apply(header)?;
             let header = compression.transform_header(session, header)?;
             session.write_response_header(header, true).await?;
+            METRICS.record_outcome(Outcome::PreconditionFailed, ctx.received);
             return Ok(RequestFilterResult::ResponseSent);
         }
 
-        if meta.is_not_modified(session) {
+        if stage.in_scope(|| meta.is_not_modified(session)) {
             debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
             let header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
+            let header = overrides.apply(header)?;
             let header = compression.transform_header(session, header)?;
             session.write_response_header(header, true).await?;
+            METRICS.record_outcome(Outcome::NotModified, ctx.received);
             return Ok(RequestFilterResult::ResponseSent);
         }
 
         let charset = if self.declare_charset_matcher.matches(&meta.mime) {
-            Some(self.declare_charset.as_str())
+            Some(
+                overrides
+                    .declare_charset
+                    .as_deref()
+                    .unwrap_or(&self.declare_charset),
+            )
         } else {
             None
         };
@@ -224,20 +752,25 @@ impl RequestFilter for StaticFilesHandler {
         let (mut header, start, end) = match extract_range(session, &meta) {
             Some(Range::Valid(start, end)) => {
                 debug!("bytes range requested: {start}-{end}");
+                ctx.range = Some((start, end));
                 let header = meta.to_partial_content_header(charset, start, end)?;
+                let header = overrides.apply(header)?;
                 let header = compression.transform_header(session, header)?;
                 (header, start, end)
             }
             Some(Range::OutOfBounds) => {
                 debug!("requested bytes range is out of bounds");
                 let header = meta.to_not_satisfiable_header(charset)?;
+                let header = overrides.apply(header)?;
                 let header = compression.transform_header(session, header)?;
                 session.write_response_header(header, true).await?;
+                METRICS.record_outcome(Outcome::RangeNotSatisfiable, ctx.received);
                 return Ok(RequestFilterResult::ResponseSent);
             }
             None => {
                 // Range is either missing or cannot be parsed, produce the entire file.
                 let header = meta.to_response_header(charset)?;
+                let header = overrides.apply(header)?;
                 let header = compression.transform_header(session, header)?;
                 (header, 0, meta.size - 1)
             }
@@ -247,34 +780,279 @@ impl RequestFilter for StaticFilesHandler {
             header.set_status(StatusCode::NOT_FOUND)?;
         }
 
+        if self.sniff.is_none() {
+            header.insert_header("X-Content-Type-Options", "nosniff")?;
+        }
+
+        let outcome = if header.status == StatusCode::PARTIAL_CONTENT {
+            Outcome::Partial
+        } else {
+            Outcome::Full
+        };
+
         let send_body = session.req_header().method != Method::HEAD;
         session.write_response_header(header, !send_body).await?;
+        if !not_found {
+            METRICS.record_outcome(outcome, ctx.received);
+        }
 
         if send_body {
             // sendfile would be nice but not currently possible within pingora-proxy (see
             // https://github.com/cloudflare/pingora/issues/160)
-            file_response(session, &path, start, end).await?;
+            let stage = Stage::new("body");
+            stage.record("size", end - start + 1);
+            stage.run(file_response(session, storage, entry, start, end)).await?;
         }
         Ok(RequestFilterResult::ResponseSent)
     }
 }
 
+/// Responds to a request rejected by JWT authorization.
+async fn deny_response(session: &mut Session, denial: Denial) -> Result<(), Box<Error>> {
+    match denial {
+        Denial::Unauthorized(challenge) => unauthorized_response(session, &challenge).await,
+        Denial::Forbidden => error_response(session, StatusCode::FORBIDDEN).await,
+    }
+}
+
+#[cfg(feature = "object-store")]
+fn object_store_root(root: &std::path::Path) -> Result<Arc<dyn Storage>, Box<Error>> {
+    let url = root.to_str().unwrap_or_default();
+    let storage = ObjectStoreStorage::from_url(url).map_err(|err| {
+        Error::because(
+            ErrorType::InternalError,
+            format!("Failed accessing object store {url}"),
+            err,
+        )
+    })?;
+    Ok(Arc::new(storage))
+}
+
+#[cfg(not(feature = "object-store"))]
+fn object_store_root(root: &std::path::Path) -> Result<Arc<dyn Storage>, Box<Error>> {
+    Error::e_explain(
+        ErrorType::InternalError,
+        format!("Cannot use {root:?} as root, object store support is not enabled"),
+    )
+}
+
+#[cfg(feature = "git")]
+fn git_root(
+    root: &std::path::Path,
+    git_ref: &str,
+    header: Option<String>,
+    domain: Option<String>,
+) -> Result<Arc<dyn Storage>, Box<Error>> {
+    let selector = GitRefSelector { header, domain };
+    let storage = GitStorage::new(root, git_ref, selector).map_err(|err| {
+        Error::because(
+            ErrorType::InternalError,
+            format!("Failed resolving git revision {git_ref} in repository {root:?}"),
+            err,
+        )
+    })?;
+    Ok(Arc::new(storage))
+}
+
+#[cfg(not(feature = "git"))]
+fn git_root(
+    root: &std::path::Path,
+    _git_ref: &str,
+    _header: Option<String>,
+    _domain: Option<String>,
+) -> Result<Arc<dyn Storage>, Box<Error>> {
+    Error::e_explain(
+        ErrorType::InternalError,
+        format!("Cannot serve repository {root:?}, git support is not enabled"),
+    )
+}
+
+/// Handler state read from files named in the configuration: `_redirects` files, the MIME types
+/// file and JWT key files. It is read when the handler is created and replaced on reload.
+#[derive(Debug)]
+struct FileState {
+    loaded: SystemTime,
+    redirects: Redirects,
+    mime_map: MimeMap,
+    jwt_auth: JwtAuthRules,
+}
+
+impl FileState {
+    /// Reads the files named in the configuration.
+    fn load(conf: &StaticFilesConf) -> Result<Self, Box<Error>> {
+        let mut redirect_rules: Vec<_> = conf.redirects.clone().into();
+        if conf.netlify_redirects {
+            for root in conf.root.iter().filter(|root| root.is_dir()) {
+                let path = root.join("_redirects");
+                match std::fs::read_to_string(&path) {
+                    Ok(text) => redirect_rules.extend(parse_netlify(&text)),
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(Error::because(
+                            ErrorType::InternalError,
+                            format!("Failed reading redirects file {path:?}"),
+                            err,
+                        ))
+                    }
+                }
+            }
+        }
+        let redirects = Redirects::new(redirect_rules).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid redirect rule", err)
+        })?;
+
+        let mut mime_map = MimeMap::default();
+        if let Some(path) = &conf.mime_types_file {
+            let text = std::fs::read_to_string(path).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Failed reading MIME types file {path:?}"),
+                    err,
+                )
+            })?;
+            mime_map.add_mime_types(&text).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Invalid MIME types file {path:?}"),
+                    err,
+                )
+            })?;
+        }
+        // Explicitly configured mappings take precedence over the MIME types file
+        for (pattern, mime) in &conf.mime_types {
+            mime_map.add(pattern, mime).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Invalid MIME type mapping for {pattern}"),
+                    err,
+                )
+            })?;
+        }
+        mime_map.set_default(&conf.default_type).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid default MIME type", err)
+        })?;
+
+        let jwt_auth = JwtAuthRules::new(conf.jwt_auth.clone())?;
+
+        Ok(Self {
+            loaded: SystemTime::now(),
+            redirects,
+            mime_map,
+            jwt_auth,
+        })
+    }
+}
+
 impl TryFrom<StaticFilesConf> for StaticFilesHandler {
     type Error = Box<Error>;
 
     fn try_from(conf: StaticFilesConf) -> Result<Self, Self::Error> {
-        let root = if let Some(root) = conf.root {
-            Some(root.canonicalize().map_err(|err| {
+        let effective_conf = Arc::new(conf.clone());
+        let files = FileState::load(&conf)?;
+        let precompressed: Vec<_> = conf.precompressed.into();
+        let mut layers = Vec::new();
+        for root in &conf.root {
+            let storage: Arc<dyn Storage> = if let Some(git_ref) = &conf.git_ref {
+                let header = conf.git_ref_header.clone();
+                let domain = conf.git_ref_domain.clone();
+                git_root(root, git_ref, header, domain)?
+            } else if root.to_str().is_some_and(|r| r.contains("://")) {
+                object_store_root(root)?
+            } else if root.is_file() {
+                Arc::new(ArchiveStorage::from_path(root).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Failed reading root archive {:?}", root),
+                        err,
+                    )
+                })?)
+            } else if let Some(refresh_ms) = conf.root_refresh_ms {
+                let interval = Duration::from_millis(refresh_ms);
+                let storage = SymlinkRootStorage::new(root, interval).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Failed accessing root path {:?}", root),
+                        err,
+                    )
+                })?;
+                Arc::new(storage.with_symlink_policy(conf.symlinks.clone()))
+            } else {
+                let storage = LocalStorage::new(root).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Failed accessing root path {:?}", root),
+                        err,
+                    )
+                })?;
+                Arc::new(storage.with_symlink_policy(conf.symlinks.clone()))
+            };
+            layers.push(storage);
+        }
+
+        let storage = if layers.len() > 1 {
+            Some(Arc::new(LayeredStorage::new(layers)) as Arc<dyn Storage>)
+        } else {
+            layers.pop()
+        };
+
+        let mut deny: Vec<_> = conf.deny.into();
+        if conf.netlify_redirects {
+            // The redirects file itself shouldn't be served
+            deny.push("/_redirects".to_owned());
+        }
+
+        let directory_overrides = if conf.directory_overrides {
+            // Override files shouldn't be served
+            deny.push(format!("/**/{OVERRIDES_FILE}"));
+            Some(Arc::new(OverridesCache::default()))
+        } else {
+            None
+        };
+
+        let access = AccessRules::new(conf.hidden, &deny).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid deny pattern", err)
+        })?;
+
+        let try_files = TryFilesRules::new(conf.try_files).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid try_files pattern", err)
+        })?;
+
+        let sniff = conf.sniff_content.then(|| Arc::new(SniffCache::default()));
+
+        let access_log = if let Some(path) = &conf.access_log {
+            let access_log = AccessLog::open(path, conf.access_log_format).map_err(|err| {
                 Error::because(
                     ErrorType::InternalError,
-                    format!("Failed accessing root path {:?}", root),
+                    format!("Failed opening access log {path:?}"),
                     err,
                 )
-            })?)
+            })?;
+            Some(Arc::new(access_log))
         } else {
             None
         };
 
+        let disposition = DispositionRules::new(conf.content_disposition).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid content_disposition pattern", err)
+        })?;
+
+        let basic_auth = BasicAuthRules::new(conf.basic_auth).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid basic_auth pattern", err)
+        })?;
+
+        let signed_urls = SignedUrlRules::new(conf.signed_urls).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid signed_urls pattern", err)
+        })?;
+
+        let ip_access = IpAccessRules::new(
+            conf.ip_access,
+            conf.trusted_proxies,
+            conf.forwarded_header,
+        )
+        .map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid ip_access pattern", err)
+        })?;
+
         let mut declare_charset_matcher = MimeMatcher::new();
         if !conf.declare_charset_types.is_empty() {
             for mime in conf.declare_charset_types {
@@ -287,11 +1065,24 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
         }
 
         Ok(Self {
-            root,
+            conf: effective_conf,
+            files: Arc::new(RwLock::new(Arc::new(files))),
+            storage,
+            access,
             canonicalize_uri: conf.canonicalize_uri,
             index_file: conf.index_file.into(),
             page_404: conf.page_404,
-            precompressed: conf.precompressed.into(),
+            try_files,
+            directory_overrides,
+            sniff,
+            disposition,
+            download_query: conf.download_query,
+            metrics_path: conf.metrics_path,
+            access_log,
+            basic_auth: Arc::new(basic_auth),
+            signed_urls: Arc::new(signed_urls),
+            ip_access: Arc::new(ip_access),
+            precompressed,
             declare_charset: conf.declare_charset,
             declare_charset_matcher,
         })
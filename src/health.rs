@@ -0,0 +1,382 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Liveness and readiness endpoints for load balancers
+//!
+//! The readiness check verifies that the root of every storage layer can be accessed, so that
+//! the server is taken out of rotation e.g. after an NFS unmount. Checks run as separate tasks
+//! and time out. Only one check per root runs at a time, so that checks don’t pile up on a hanging
+//! mount.
+
+use async_trait::async_trait;
+use http::status::StatusCode;
+use log::warn;
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+
+use crate::configuration::HealthConf;
+use crate::handler::StaticFilesHandler;
+use crate::request_filter::{RequestFilter, RequestFilterResult};
+use crate::standard_response::data_response;
+use crate::storage::{EntryKind, Storage};
+
+/// Error message reported for checks exceeding the timeout
+const TIMED_OUT: &str = "root check timed out";
+
+/// Result of a root check, the error message on failure
+type CheckResult = Result<(), String>;
+
+/// Checks of a root
+#[derive(Debug, Default)]
+struct RootChecks {
+    /// Start time of the check currently running and the channel receiving its result
+    running: Option<(Instant, watch::Receiver<Option<CheckResult>>)>,
+    /// Result of the last completed check
+    last: Option<CheckResult>,
+}
+
+/// Health endpoints handler
+#[derive(Debug, Clone)]
+pub struct HealthHandler {
+    liveness_path: String,
+    readiness_path: String,
+    timeout: Duration,
+    handler: StaticFilesHandler,
+    checks: Arc<Mutex<HashMap<PathBuf, RootChecks>>>,
+}
+
+impl PartialEq for HealthHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.liveness_path == other.liveness_path
+            && self.readiness_path == other.readiness_path
+            && self.timeout == other.timeout
+            && self.handler == other.handler
+    }
+}
+
+impl Eq for HealthHandler {}
+
+/// Readiness of a root
+#[derive(Debug, Serialize)]
+struct RootStatus {
+    root: PathBuf,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl StaticFilesHandler {
+    /// Creates a handler for the health endpoints, checking the roots of this handler.
+    pub fn health(&self, conf: HealthConf) -> HealthHandler {
+        HealthHandler {
+            liveness_path: conf.liveness_path,
+            readiness_path: conf.readiness_path,
+            timeout: Duration::from_millis(conf.timeout_ms),
+            handler: self.clone(),
+            checks: Default::default(),
+        }
+    }
+}
+
+impl HealthHandler {
+    /// Checks all roots, produces the response status and the report.
+    async fn readiness(&self) -> (StatusCode, serde_json::Value) {
+        let storages = match self.handler.storage() {
+            Some(storage) if storage.layers().is_empty() => vec![storage.clone()],
+            Some(storage) => storage.layers().to_vec(),
+            None => Vec::new(),
+        };
+
+        // Start all checks before waiting for any, so that they share the same deadline
+        let deadline = Instant::now() + self.timeout;
+        let checks: Vec<_> = storages
+            .into_iter()
+            .map(|storage| (storage.root().to_path_buf(), self.start_check(storage)))
+            .collect();
+
+        let mut roots = Vec::with_capacity(checks.len());
+        for (root, check) in checks {
+            let result = match check {
+                Ok(mut receiver) => {
+                    match timeout_at(deadline, receiver.wait_for(Option::is_some)).await {
+                        Ok(Ok(result)) => result.clone().unwrap_or(Ok(())),
+                        Ok(Err(_)) => Err("root check failed".to_owned()),
+                        Err(_) => Err(TIMED_OUT.to_owned()),
+                    }
+                }
+                Err(result) => result,
+            };
+            let (status, error) = match result {
+                Ok(()) => ("ok", None),
+                Err(err) => {
+                    warn!("root {root:?} isn't accessible: {err}");
+                    ("error", Some(err))
+                }
+            };
+            roots.push(RootStatus {
+                root,
+                status,
+                error,
+            });
+        }
+        let ready = roots.iter().all(|root| root.error.is_none());
+
+        let last_reload = self
+            .handler
+            .last_reload()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let file_descriptors = fd_usage().map(|(open, limit)| {
+            json!({
+                "open": open,
+                "limit": limit,
+                "headroom": limit.saturating_sub(open),
+            })
+        });
+
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let report = json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "roots": roots,
+            "last_reload": last_reload,
+            "file_descriptors": file_descriptors,
+        });
+        (status, report)
+    }
+
+    /// Starts a check of the storage root unless one is running already, producing the channel
+    /// to receive the result from. While a check is running, the result of the last completed
+    /// check is produced instead, or a timeout if the running check exceeded it.
+    fn start_check(
+        &self,
+        storage: Arc<dyn Storage>,
+    ) -> Result<watch::Receiver<Option<CheckResult>>, CheckResult> {
+        let root = storage.root().to_path_buf();
+        let mut checks = self.checks.lock().unwrap_or_else(PoisonError::into_inner);
+        let root_checks = checks.entry(root.clone()).or_default();
+        if let Some((started, receiver)) = &root_checks.running {
+            return match &root_checks.last {
+                _ if started.elapsed() >= self.timeout => Err(Err(TIMED_OUT.to_owned())),
+                Some(last) => Err(last.clone()),
+                None => Ok(receiver.clone()),
+            };
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        root_checks.running = Some((Instant::now(), receiver.clone()));
+        let checks = self.checks.clone();
+        tokio::spawn(async move {
+            let result = check_root(storage.as_ref())
+                .await
+                .map_err(|err| err.to_string());
+            let mut checks = checks.lock().unwrap_or_else(PoisonError::into_inner);
+            let root_checks = checks.entry(root).or_default();
+            root_checks.running = None;
+            root_checks.last = Some(result.clone());
+            sender.send_replace(Some(result));
+        });
+        Ok(receiver)
+    }
+}
+
+#[async_trait]
+impl RequestFilter for HealthHandler {
+    type Conf = HealthConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let path = session.req_header().uri.path();
+        let (status, report) = if path == self.liveness_path {
+            (StatusCode::OK, json!({ "status": "ok" }))
+        } else if path == self.readiness_path {
+            self.readiness().await
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let text = serde_json::to_string_pretty(&report).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Failed serializing health report", err)
+        })?;
+        data_response(session, status, "application/json", text).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+/// Checks that the root of a storage can be accessed and, if it is a directory, listed.
+async fn check_root(storage: &dyn Storage) -> Result<(), std::io::Error> {
+    let root = storage.root();
+    if storage.stat(root).await?.kind == EntryKind::Directory {
+        storage.list_dir(root).await?;
+    }
+    Ok(())
+}
+
+/// Determines the number of open file descriptors and the limit.
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)] // `rlim_t` is 32 bits wide on some targets
+fn fd_usage() -> Option<(u64, u64)> {
+    // Listing the directory takes a file descriptor itself
+    let open = std::fs::read_dir("/proc/self/fd")
+        .ok()?
+        .count()
+        .saturating_sub(1) as u64;
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: the pointer is valid for the duration of the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some((open, limit.rlim_cur as u64))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fd_usage() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io::{self, ErrorKind};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_log::test;
+
+    use crate::configuration::StaticFilesConf;
+    use crate::storage::{EntryStat, RangeReader};
+
+    /// Storage hanging on every access, like a stale network mount
+    #[derive(Debug, Default)]
+    struct HangingStorage {
+        checks: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Storage for HangingStorage {
+        fn root(&self) -> &Path {
+            Path::new("/")
+        }
+
+        async fn resolve(&self, _uri_path: &str) -> Result<PathBuf, io::Error> {
+            Err(ErrorKind::NotFound.into())
+        }
+
+        async fn stat(&self, _path: &Path) -> Result<EntryStat, io::Error> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Err(ErrorKind::NotFound.into())
+        }
+
+        async fn open_range(
+            &self,
+            _path: &Path,
+            _start: u64,
+            _end: u64,
+        ) -> Result<RangeReader, io::Error> {
+            Err(ErrorKind::NotFound.into())
+        }
+
+        async fn list_dir(&self, _path: &Path) -> Result<Vec<OsString>, io::Error> {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn readiness() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main");
+        let mount = dir.path().join("mount");
+        fs::create_dir(&main).unwrap();
+        fs::create_dir(&mount).unwrap();
+        fs::write(mount.join("file.txt"), "Hi!").unwrap();
+
+        let conf = StaticFilesConf {
+            root: vec![main.clone(), mount.clone()].into(),
+            ..Default::default()
+        };
+        let health = StaticFilesHandler::try_from(conf)
+            .unwrap()
+            .health(HealthConf::default());
+
+        let (status, report) = health.readiness().await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["status"], "ready");
+        assert_eq!(report["roots"][0]["status"], "ok");
+        assert_eq!(report["roots"][1]["status"], "ok");
+        assert!(report["last_reload"].as_u64().unwrap() > 0);
+        if cfg!(target_os = "linux") {
+            assert!(report["file_descriptors"]["headroom"].as_u64().unwrap() > 0);
+        }
+
+        // Mount disappears
+        fs::remove_dir_all(&mount).unwrap();
+        let (status, report) = health.readiness().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["status"], "unavailable");
+        assert_eq!(report["roots"][0]["status"], "ok");
+        assert_eq!(report["roots"][1]["status"], "error");
+        assert!(report["roots"][1]["error"].is_string());
+    }
+
+    #[test(tokio::test)]
+    async fn timeout() {
+        let storage = HangingStorage::default();
+        let checks = storage.checks.clone();
+        let health = StaticFilesHandler::try_from(StaticFilesConf::default())
+            .unwrap()
+            .with_storage(storage)
+            .health(HealthConf {
+                timeout_ms: 50,
+                ..Default::default()
+            });
+
+        let (status, report) = health.readiness().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["roots"][0]["root"], "/");
+        assert_eq!(report["roots"][0]["status"], "error");
+        assert_eq!(report["roots"][0]["error"], "root check timed out");
+
+        // The check is still running, no new one is started
+        let (status, report) = health.readiness().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["roots"][0]["error"], "root check timed out");
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+    }
+}
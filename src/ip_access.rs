@@ -0,0 +1,310 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client IP allow and deny lists
+//!
+//! The client address is the address of the peer unless the peer is a trusted proxy. In that
+//! case the configured forwarding header is processed from the right, skipping trusted proxies,
+//! and the first other address is the client address. The other forwarding header is ignored, it
+//! might have been passed through from the client unchanged.
+
+use glob::PatternError;
+use http::header::{self, HeaderMap};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::configuration::{ForwardedHeader, IpAccessRule, IpNetwork};
+use crate::path_rules::PathRules;
+
+/// IP access rules with patterns compiled
+#[derive(Debug, Default)]
+pub(crate) struct IpAccessRules {
+    rules: PathRules<IpAccess>,
+    trusted_proxies: Vec<IpNetwork>,
+    forwarded_header: ForwardedHeader,
+}
+
+impl IpAccessRules {
+    /// Compiles the rules from the configuration.
+    pub(crate) fn new(
+        rules: impl IntoIterator<Item = IpAccessRule>,
+        trusted_proxies: impl IntoIterator<Item = IpNetwork>,
+        forwarded_header: ForwardedHeader,
+    ) -> Result<Self, PatternError> {
+        let rules = PathRules::new(rules.into_iter().map(|rule| {
+            let access = IpAccess {
+                allow: rule.allow.into(),
+                deny: rule.deny.into(),
+            };
+            (rule.pattern, access)
+        }))?;
+        Ok(Self {
+            rules,
+            trusted_proxies: trusted_proxies.into_iter().collect(),
+            forwarded_header,
+        })
+    }
+
+    /// Finds the rule applying to a request path, `None` if the path isn’t restricted.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&IpAccess> {
+        self.rules.find(uri_path)
+    }
+
+    /// Determines the client address of a request from the peer address and the forwarding
+    /// headers. Returns `None` if the address forwarded by a trusted proxy cannot be parsed.
+    pub(crate) fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?.to_canonical();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let forwarded = match self.forwarded_header {
+            ForwardedHeader::Forwarded => forwarded_for(headers),
+            ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+        };
+
+        let mut client = peer;
+        for node in forwarded.iter().rev() {
+            client = parse_node(node)?.to_canonical();
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    /// Checks whether an address belongs to a trusted proxy.
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+}
+
+/// Client networks allowed or denied access to matching paths
+#[derive(Debug)]
+pub(crate) struct IpAccess {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl IpAccess {
+    /// Checks whether a client is allowed access. Unknown clients are denied unless the rule has
+    /// neither an allow nor a deny list.
+    pub(crate) fn allows(&self, client_ip: Option<IpAddr>) -> bool {
+        match client_ip {
+            Some(ip) => {
+                !self.deny.iter().any(|network| network.contains(ip))
+                    && (self.allow.is_empty()
+                        || self.allow.iter().any(|network| network.contains(ip)))
+            }
+            None => self.allow.is_empty() && self.deny.is_empty(),
+        }
+    }
+}
+
+/// Extracts the `for` parameters of all `Forwarded` headers, in order.
+fn forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| value.trim().trim_matches('"'))
+                })
+                .unwrap_or("")
+        })
+        .collect()
+}
+
+/// Extracts the addresses of all `X-Forwarded-For` headers, in order.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .map(str::trim)
+        .collect()
+}
+
+/// Parses a forwarded node: an IP address, optionally in brackets and with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::HeaderValue;
+    use test_log::test;
+
+    fn network(value: &str) -> IpNetwork {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        value.parse().ok()
+    }
+
+    #[test]
+    fn rules() {
+        let rules = IpAccessRules::new(
+            [
+                IpAccessRule {
+                    pattern: Some("/internal/**".to_owned()),
+                    allow: vec![network("10.0.0.0/8"), network("2001:db8::/32")].into(),
+                    deny: vec![network("10.66.0.0/16")].into(),
+                },
+                IpAccessRule {
+                    pattern: None,
+                    allow: Default::default(),
+                    deny: vec![network("203.0.113.0/24")].into(),
+                },
+            ],
+            [],
+            ForwardedHeader::default(),
+        )
+        .unwrap();
+
+        let internal = rules.find("/internal/report.pdf").unwrap();
+        assert!(std::ptr::eq(internal, rules.find("/internal").unwrap()));
+        assert!(std::ptr::eq(
+            internal,
+            rules.find("/public/../internal/x").unwrap()
+        ));
+        assert!(internal.allows(ip("10.1.2.3")));
+        assert!(internal.allows(ip("::ffff:10.1.2.3")));
+        assert!(internal.allows(ip("2001:db8::1")));
+        assert!(!internal.allows(ip("10.66.1.1")));
+        assert!(!internal.allows(ip("192.0.2.1")));
+        assert!(!internal.allows(None));
+
+        let public = rules.find("/index.html").unwrap();
+        assert!(public.allows(ip("192.0.2.1")));
+        assert!(!public.allows(ip("203.0.113.7")));
+        // Unknown clients could be anybody, including denied ones
+        assert!(!public.allows(None));
+    }
+
+    #[test]
+    fn client_ip() {
+        let untrusted = IpAccessRules::new([], [], ForwardedHeader::XForwardedFor).unwrap();
+        let trusted =
+            IpAccessRules::new([], [network("10.0.0.0/8")], ForwardedHeader::XForwardedFor)
+                .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For",
+            HeaderValue::from_static("198.51.100.1, 192.0.2.1, 10.0.0.2"),
+        );
+        assert_eq!(
+            untrusted.client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            trusted.client_ip(ip("192.0.2.9"), &headers),
+            ip("192.0.2.9")
+        );
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.1"));
+        assert_eq!(
+            trusted.client_ip(ip("::ffff:10.0.0.1"), &headers),
+            ip("192.0.2.1")
+        );
+        assert_eq!(trusted.client_ip(None, &headers), None);
+
+        // Forwarded header passed through from the client is ignored
+        headers.insert(header::FORWARDED, HeaderValue::from_static("for=10.0.0.3"));
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.1"));
+
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("unknown"));
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), None);
+
+        headers.clear();
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let trusted =
+            IpAccessRules::new([], [network("10.0.0.0/8")], ForwardedHeader::Forwarded).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static(
+                "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\";by=10.0.0.1",
+            ),
+        );
+        assert_eq!(
+            trusted.client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+
+        // X-Forwarded-For passed through from the client is ignored
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(
+            trusted.client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+
+        headers.insert(header::FORWARDED, HeaderValue::from_static("for=10.0.0.3"));
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+
+        headers.remove(header::FORWARDED);
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn unknown_client() {
+        let rules = IpAccessRules::new(
+            [IpAccessRule {
+                pattern: None,
+                allow: Default::default(),
+                deny: vec![network("203.0.113.0/24")].into(),
+            }],
+            [network("10.0.0.0/8")],
+            ForwardedHeader::Forwarded,
+        )
+        .unwrap();
+        let rule = rules.find("/index.html").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=203.0.113.7"),
+        );
+        assert!(!rule.allows(rules.client_ip(ip("10.0.0.1"), &headers)));
+
+        // An unparsable address must not bypass the deny list
+        headers.insert(header::FORWARDED, HeaderValue::from_static("for=unknown"));
+        assert_eq!(rules.client_ip(ip("10.0.0.1"), &headers), None);
+        assert!(!rule.allows(rules.client_ip(ip("10.0.0.1"), &headers)));
+
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=unknown, for=192.0.2.1"),
+        );
+        assert!(rule.allows(rules.client_ip(ip("10.0.0.1"), &headers)));
+    }
+}
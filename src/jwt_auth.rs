@@ -0,0 +1,430 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bearer token authorization with JSON Web Tokens
+//!
+//! Tokens are verified against public keys (or `oct` secrets) from local JWKS and PEM files, no
+//! keys are fetched from the network. Each key only accepts the algorithms of its key type, so
+//! that a public key cannot be abused as an HMAC secret.
+
+use http::header::{self, HeaderMap};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::debug;
+use pingora::{Error, ErrorType};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+
+use crate::configuration::JwtAuthRule;
+use crate::path::normalize_uri;
+use crate::path_rules::PathRules;
+
+const RSA_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const HMAC_ALGORITHMS: &[Algorithm] = &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// JWT authorization rules with patterns compiled and keys loaded
+#[derive(Debug, Default)]
+pub(crate) struct JwtAuthRules {
+    rules: PathRules<JwtAuth>,
+}
+
+impl JwtAuthRules {
+    /// Compiles the rules from the configuration, reading the key files.
+    pub(crate) fn new(rules: impl IntoIterator<Item = JwtAuthRule>) -> Result<Self, Box<Error>> {
+        let rules = rules
+            .into_iter()
+            .map(|mut rule| Ok((rule.pattern.take(), JwtAuth::new(rule)?)))
+            .collect::<Result<Vec<_>, Box<Error>>>()?;
+        let rules = PathRules::new(rules).map_err(|err| {
+            Error::because(ErrorType::InternalError, "Invalid jwt_auth pattern", err)
+        })?;
+        Ok(Self { rules })
+    }
+
+    /// Finds the rule applying to a request path, `None` if the path isn’t protected.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&JwtAuth> {
+        self.rules.find(uri_path)
+    }
+}
+
+/// Reason for rejecting a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Denial {
+    /// Token missing or invalid, respond with `401 Unauthorized` and this challenge
+    Unauthorized(String),
+    /// Token valid but its claims don’t allow access to the path
+    Forbidden,
+}
+
+/// A key tokens can be signed with
+struct JwtKey {
+    kid: Option<String>,
+    key: DecodingKey,
+    algorithms: &'static [Algorithm],
+}
+
+impl Debug for JwtKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtKey")
+            .field("kid", &self.kid)
+            .field("algorithms", &self.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Paths protected by JWT authorization
+#[derive(Debug)]
+pub(crate) struct JwtAuth {
+    keys: Vec<JwtKey>,
+    validation: Validation,
+    cookie: Option<String>,
+    claims: BTreeMap<String, String>,
+}
+
+impl JwtAuth {
+    /// Creates a rule from the configuration, reading the key files.
+    fn new(rule: JwtAuthRule) -> Result<Self, Box<Error>> {
+        let mut keys = Vec::new();
+        for path in &rule.keys {
+            keys.extend(load_keys(path)?);
+        }
+        if keys.is_empty() {
+            return Error::e_explain(ErrorType::InternalError, "jwt_auth rule without keys");
+        }
+
+        // Algorithms are restricted per key during verification
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.validate_nbf = true;
+        if !rule.audience.is_empty() {
+            validation.set_audience(&rule.audience);
+            validation.required_spec_claims.insert("aud".to_owned());
+        } else {
+            validation.validate_aud = false;
+        }
+        if !rule.issuer.is_empty() {
+            validation.set_issuer(&rule.issuer);
+            validation.required_spec_claims.insert("iss".to_owned());
+        }
+
+        Ok(Self {
+            keys,
+            validation,
+            cookie: rule.cookie,
+            claims: rule.claims,
+        })
+    }
+
+    /// Extracts the token from the `Authorization` header or the configured cookie.
+    fn token<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        if let Some(authorization) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        {
+            if let Some((scheme, token)) = authorization.split_once(' ') {
+                if scheme.eq_ignore_ascii_case("bearer") {
+                    return Some(token.trim());
+                }
+            }
+        }
+
+        let cookie = self.cookie.as_deref()?;
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                (name == cookie).then_some(value)
+            })
+    }
+
+    /// Verifies the token of a request and checks its claims against the request path. Returns
+    /// the token subject if there is one.
+    pub(crate) fn authorize(
+        &self,
+        headers: &HeaderMap,
+        uri_path: &str,
+    ) -> Result<Option<String>, Denial> {
+        let Some(token) = self.token(headers) else {
+            debug!("no bearer token for {uri_path}");
+            return Err(Denial::Unauthorized(challenge(false)));
+        };
+        let claims = self
+            .verify(token)
+            .ok_or_else(|| Denial::Unauthorized(challenge(true)))?;
+
+        if !self.claims_match(&claims, uri_path) {
+            debug!("token claims don't allow access to {uri_path}");
+            return Err(Denial::Forbidden);
+        }
+        Ok(claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned))
+    }
+
+    /// Verifies the signature and the time, audience and issuer claims of a token, returns its
+    /// claims if the token is valid.
+    fn verify(&self, token: &str) -> Option<Value> {
+        let header = decode_header(token)
+            .map_err(|err| debug!("malformed token: {err}"))
+            .ok()?;
+
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithms.contains(&header.alg)
+                && (header.kid.is_none() || key.kid.is_none() || key.kid == header.kid)
+        });
+        for key in candidates {
+            match decode::<Value>(token, &key.key, &validation) {
+                Ok(data) => return Some(data.claims),
+                Err(err) => debug!("token rejected with key {:?}: {err}", key.kid),
+            }
+        }
+        None
+    }
+
+    /// Checks the required claims, `$1`, `$2` etc. refer to the segments of the request path.
+    fn claims_match(&self, claims: &Value, uri_path: &str) -> bool {
+        let path = normalize_uri(uri_path, Path::new("/")).unwrap_or_default();
+        let segments: Vec<_> = path
+            .iter()
+            .skip(1)
+            .map(|segment| segment.to_string_lossy())
+            .collect();
+
+        self.claims.iter().all(|(name, expected)| {
+            let expected = match expected
+                .strip_prefix('$')
+                .and_then(|index| index.parse::<usize>().ok())
+            {
+                Some(index) => match index.checked_sub(1).and_then(|i| segments.get(i)) {
+                    Some(segment) => &**segment,
+                    None => return false,
+                },
+                None => expected.as_str(),
+            };
+            match claims.get(name) {
+                Some(Value::String(value)) => value == expected,
+                Some(Value::Array(values)) => values.iter().any(|value| value == expected),
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Produces the `WWW-Authenticate` header value for `401 Unauthorized` responses.
+fn challenge(invalid_token: bool) -> String {
+    if invalid_token {
+        "Bearer error=\"invalid_token\"".to_owned()
+    } else {
+        "Bearer".to_owned()
+    }
+}
+
+/// Reads the keys of a JWKS or PEM file.
+fn load_keys(path: &Path) -> Result<Vec<JwtKey>, Box<Error>> {
+    let invalid = |err: Box<dyn std::error::Error + Send + Sync>| {
+        Error::because(
+            ErrorType::InternalError,
+            format!("Failed reading JWT keys from {path:?}"),
+            err,
+        )
+    };
+    let data = fs::read(path).map_err(|err| invalid(err.into()))?;
+
+    if data.trim_ascii_start().starts_with(b"{") {
+        let jwks: JwkSet = serde_json::from_slice(&data).map_err(|err| invalid(err.into()))?;
+        jwks.keys
+            .iter()
+            .map(|jwk| {
+                let algorithms: &'static [Algorithm] = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => RSA_ALGORITHMS,
+                    AlgorithmParameters::EllipticCurve(params) => match params.curve {
+                        EllipticCurve::P256 => &[Algorithm::ES256],
+                        EllipticCurve::P384 => &[Algorithm::ES384],
+                        _ => &[],
+                    },
+                    AlgorithmParameters::OctetKeyPair(_) => &[Algorithm::EdDSA],
+                    AlgorithmParameters::OctetKey(_) => HMAC_ALGORITHMS,
+                };
+                Ok(JwtKey {
+                    kid: jwk.common.key_id.clone(),
+                    key: DecodingKey::from_jwk(jwk).map_err(|err| invalid(err.into()))?,
+                    algorithms,
+                })
+            })
+            .collect()
+    } else {
+        let (key, algorithms): (_, &'static [Algorithm]) =
+            if let Ok(key) = DecodingKey::from_rsa_pem(&data) {
+                (key, RSA_ALGORITHMS)
+            } else if let Ok(key) = DecodingKey::from_ec_pem(&data) {
+                // The curve isn't known here, the signature check will fail for the wrong one
+                (key, &[Algorithm::ES256, Algorithm::ES384])
+            } else {
+                let key = DecodingKey::from_ed_pem(&data).map_err(|err| invalid(err.into()))?;
+                (key, &[Algorithm::EdDSA])
+            };
+        Ok(vec![JwtKey {
+            kid: None,
+            key,
+            algorithms,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::HeaderValue;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use test_log::test;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn rule(dir: &Path) -> JwtAuth {
+        let jwks = dir.join("jwks.json");
+        fs::write(
+            &jwks,
+            r#"{"keys": [{"kty": "oct", "kid": "current", "k": "c2VjcmV0"}]}"#,
+        )
+        .unwrap();
+
+        JwtAuth::new(JwtAuthRule {
+            pattern: Some("/tenants/**".to_owned()),
+            keys: vec![jwks].into(),
+            issuer: vec!["https://idp.example.com/".to_owned()].into(),
+            audience: vec!["static-files".to_owned()].into(),
+            cookie: Some("token".to_owned()),
+            claims: [("tenant".to_owned(), "$2".to_owned())].into(),
+        })
+        .unwrap()
+    }
+
+    fn token(claims: Value, secret: &[u8]) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("current".to_owned());
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn authorization() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = rule(dir.path());
+        let claims = json!({
+            "sub": "alice",
+            "iss": "https://idp.example.com/",
+            "aud": "static-files",
+            "exp": now() + 60,
+            "tenant": "acme",
+        });
+
+        let valid = token(claims.clone(), b"secret");
+        assert_eq!(
+            auth.authorize(&bearer(&valid), "/tenants/acme/data.json"),
+            Ok(Some("alice".to_owned()))
+        );
+        assert_eq!(
+            auth.authorize(&bearer(&valid), "/tenants/other/data.json"),
+            Err(Denial::Forbidden)
+        );
+        assert_eq!(
+            auth.authorize(&bearer(&valid), "/tenants"),
+            Err(Denial::Forbidden)
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            auth.authorize(&headers, "/tenants/acme/data.json"),
+            Err(Denial::Unauthorized("Bearer".to_owned()))
+        );
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; token={valid}")).unwrap(),
+        );
+        assert_eq!(
+            auth.authorize(&headers, "/tenants/acme/data.json"),
+            Ok(Some("alice".to_owned()))
+        );
+
+        let invalid = Err(Denial::Unauthorized(
+            "Bearer error=\"invalid_token\"".to_owned(),
+        ));
+        let forged = token(claims.clone(), b"other");
+        assert_eq!(
+            auth.authorize(&bearer(&forged), "/tenants/acme/data.json"),
+            invalid
+        );
+        assert_eq!(
+            auth.authorize(&bearer("garbage"), "/tenants/acme/data.json"),
+            invalid
+        );
+
+        for (name, value) in [
+            ("exp", json!(now() - 3600)),
+            ("nbf", json!(now() + 3600)),
+            ("aud", json!("other")),
+            ("iss", json!("https://evil.example.com/")),
+        ] {
+            let mut claims = claims.clone();
+            claims[name] = value;
+            let token = token(claims, b"secret");
+            assert_eq!(
+                auth.authorize(&bearer(&token), "/tenants/acme/data.json"),
+                invalid,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn claim_arrays() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = rule(dir.path());
+        let claims = json!({ "tenant": ["acme", "globex"] });
+        assert!(auth.claims_match(&claims, "/tenants/globex/file"));
+        assert!(!auth.claims_match(&claims, "/tenants/initech/file"));
+        assert!(!auth.claims_match(&json!({}), "/tenants/acme/file"));
+    }
+}
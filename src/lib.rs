@@ -14,23 +14,60 @@
 
 #![doc = include_str!("../README.md")]
 
+mod access;
+mod access_log;
+mod admin;
+pub mod archive;
+mod basic_auth;
+#[cfg(target_os = "linux")]
+mod beneath;
 mod compression;
 mod compression_algorithm;
 mod configuration;
 mod file_writer;
+#[cfg(feature = "git")]
+pub mod git_storage;
 mod handler;
+mod health;
+mod ip_access;
+mod jwt_auth;
 pub mod metadata;
+mod mime_map;
 mod mime_matcher;
+mod metrics;
+#[cfg(feature = "object-store")]
+pub mod object_storage;
+mod overrides;
 pub mod path;
+mod path_rules;
 pub mod range;
+mod redirects;
+pub mod storage;
 #[cfg(test)]
 mod tests;
+mod secret;
 mod session_wrapper;
+mod signed_url;
+mod sniff;
+mod telemetry;
+mod try_files;
 mod request_filter;
 mod standard_response;
 mod deserialize;
+mod disposition;
+pub mod embedded;
 
+pub use admin::AdminHandler;
 pub use compression_algorithm::{CompressionAlgorithm, UnsupportedCompressionAlgorithm};
-pub use configuration::{StaticFilesConf, StaticFilesOpt};
-pub use handler::StaticFilesHandler;
+pub use configuration::{
+    AccessLogFormat, AdminConf, BasicAuthRule, DispositionType, ForwardedHeader, HealthConf,
+    HiddenPolicy, IpAccessRule, IpNetwork, JwtAuthRule, SignedUrlRule, StaticFilesConf,
+    StaticFilesOpt, SymlinkPolicy,
+};
+pub use handler::{StaticFilesCtx, StaticFilesHandler};
+pub use health::HealthHandler;
+pub use metrics::encode_metrics;
+pub use signed_url::sign_url;
+#[cfg(feature = "otel")]
+pub use telemetry::init_otlp;
 pub use request_filter::RequestFilter;
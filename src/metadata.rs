@@ -24,6 +24,10 @@ use std::path::Path;
 use std::time::SystemTime;
 use pingora::proxy::Session;
 
+use crate::configuration::DispositionType;
+use crate::disposition::content_disposition;
+use crate::storage::{EntryKind, EntryStat};
+
 /// Helper wrapping file metadata information
 #[derive(Debug)]
 pub struct Metadata {
@@ -34,8 +38,11 @@ pub struct Metadata {
     /// Last modified time of the file in the format `Fri, 15 May 2015 15:34:21 GMT` if the time
     /// can be retrieved
     pub modified: Option<String>,
-    /// ETag header for the file, encoding last modified time and file size
+    /// ETag header for the file, encoding last modified time and file size unless the storage
+    /// backend provided one
     pub etag: String,
+    /// `Content-Disposition` header value if one should be sent
+    pub content_disposition: Option<String>,
 }
 
 impl Metadata {
@@ -48,32 +55,60 @@ impl Metadata {
         path: &P,
         orig_path: Option<&P>,
     ) -> Result<Self, Error> {
-        let meta = path.as_ref().metadata()?;
+        let stat: EntryStat = path.as_ref().metadata()?.into();
+        Self::from_stat(&stat, orig_path.unwrap_or(path).as_ref())
+    }
+
+    /// Converts the entry information produced by a storage backend into file metadata. The MIME
+    /// type is determined from `mime_path`.
+    ///
+    /// This will result in a [`ErrorKind::InvalidInput`] error if the entry isn’t a regular file.
+    pub fn from_stat(stat: &EntryStat, mime_path: &Path) -> Result<Self, Error> {
+        let mime = mime_guess::from_path(mime_path).first_or_octet_stream();
+        Self::from_stat_with_mime(stat, mime)
+    }
 
-        if !meta.is_file() {
+    /// Converts the entry information produced by a storage backend into file metadata, using
+    /// a MIME type determined by the caller.
+    ///
+    /// This will result in a [`ErrorKind::InvalidInput`] error if the entry isn’t a regular file.
+    pub fn from_stat_with_mime(stat: &EntryStat, mime: Mime) -> Result<Self, Error> {
+        if stat.kind != EntryKind::File {
             return Err(ErrorKind::InvalidInput.into());
         }
 
-        let mime = mime_guess::from_path(orig_path.unwrap_or(path)).first_or_octet_stream();
-        let size = meta.len();
-        let modified = meta.modified().ok().map(fmt_http_date);
-        let etag = format!(
-            "\"{:x}-{:x}\"",
-            meta.modified()
-                .ok()
-                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .map_or(0, |duration| duration.as_secs()),
-            meta.len()
-        );
+        let size = stat.size;
+        let modified = stat.modified.map(fmt_http_date);
+        let etag = if let Some(etag) = &stat.etag {
+            match etag.strip_prefix("W/") {
+                Some(etag) => format!("W/\"{etag}\""),
+                None => format!("\"{etag}\""),
+            }
+        } else {
+            format!(
+                "\"{:x}-{:x}\"",
+                stat.modified
+                    .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map_or(0, |duration| duration.as_secs()),
+                stat.size
+            )
+        };
 
         Ok(Self {
             mime,
             size,
             modified,
             etag,
+            content_disposition: None,
         })
     }
 
+    /// Makes responses declare the given disposition type and file name via the
+    /// `Content-Disposition` header.
+    pub fn set_disposition(&mut self, disposition: DispositionType, filename: &str) {
+        self.content_disposition = Some(content_disposition(disposition, filename));
+    }
+
     /// Checks `If-Match` and `If-Unmodified-Since` headers of the request to determine whether
     /// a `412 Precondition Failed` response should be produced.
     pub fn has_failed_precondition(&self, session: &Session) -> bool {
@@ -82,11 +117,13 @@ impl Metadata {
             .get(header::IF_MATCH)
             .and_then(|value| value.to_str().ok())
         {
+            // If-Match requires strong comparison, weak validators never match
             value != "*"
-                && value
-                    .split(',')
-                    .map(str::trim)
-                    .all(|value| value != self.etag)
+                && (self.etag.starts_with("W/")
+                    || value
+                        .split(',')
+                        .map(str::trim)
+                        .all(|value| value != self.etag))
         } else if let Some(value) = headers
             .get(header::IF_UNMODIFIED_SINCE)
             .and_then(|value| value.to_str().ok())
@@ -141,6 +178,17 @@ impl Metadata {
         Ok(())
     }
 
+    #[inline(always)]
+    fn add_content_disposition(
+        &self,
+        header: &mut ResponseHeader,
+    ) -> Result<(), Box<pingora::Error>> {
+        if let Some(content_disposition) = &self.content_disposition {
+            header.append_header(header::CONTENT_DISPOSITION, content_disposition)?;
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     fn add_etag(
         &self,
@@ -162,6 +210,7 @@ impl Metadata {
         header.append_header(header::CONTENT_LENGTH, self.size.to_string())?;
         header.append_header(header::ACCEPT_RANGES, "bytes")?;
         self.add_content_type(&mut header, charset)?;
+        self.add_content_disposition(&mut header)?;
         self.add_etag(&mut header)?;
         Ok(Box::new(header))
     }
@@ -180,6 +229,7 @@ impl Metadata {
             format!("bytes {start}-{end}/{}", self.size),
         )?;
         self.add_content_type(&mut header, charset)?;
+        self.add_content_disposition(&mut header)?;
         self.add_etag(&mut header)?;
         Ok(Box::new(header))
     }
@@ -0,0 +1,216 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus metrics of the static files handler
+//!
+//! The metrics are registered with the default registry of the `prometheus` crate, so they are
+//! exposed along with Pingora’s own metrics by its Prometheus service. Alternatively, the handler
+//! can respond to scrape requests itself if `metrics_path` is configured.
+
+use http::status::StatusCode;
+use once_cell::sync::Lazy;
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType};
+use prometheus::proto::MetricType;
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+    Encoder, Histogram, IntCounter, IntCounterVec, TextEncoder, TEXT_FORMAT,
+};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::time::Instant;
+
+use crate::standard_response::data_response;
+use crate::CompressionAlgorithm;
+
+/// Outcome of a request for a file, depending on conditional and range headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// `200 OK`, full file sent
+    Full,
+    /// `206 Partial Content`, range sent
+    Partial,
+    /// `304 Not Modified`
+    NotModified,
+    /// `412 Precondition Failed`
+    PreconditionFailed,
+    /// `416 Range Not Satisfiable`
+    RangeNotSatisfiable,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Partial => "partial",
+            Self::NotModified => "not_modified",
+            Self::PreconditionFailed => "precondition_failed",
+            Self::RangeNotSatisfiable => "range_not_satisfiable",
+        }
+    }
+}
+
+/// Metrics collected by the static files handler
+pub(crate) struct Metrics {
+    requests: IntCounterVec,
+    outcomes: IntCounterVec,
+    sent_bytes: IntCounter,
+    precompressed_hits: IntCounterVec,
+    resolution_errors: IntCounterVec,
+    time_to_first_byte: Histogram,
+}
+
+/// Metrics instance, registered on first use
+pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+    requests: register_int_counter_vec!(
+        "static_files_requests_total",
+        "Requests handled by the static files handler, by response status",
+        &["status"]
+    )
+    .unwrap(),
+    outcomes: register_int_counter_vec!(
+        "static_files_outcomes_total",
+        "File responses by cache and range outcome",
+        &["outcome"]
+    )
+    .unwrap(),
+    sent_bytes: register_int_counter!(
+        "static_files_sent_bytes_total",
+        "Response body bytes sent by the static files handler"
+    )
+    .unwrap(),
+    precompressed_hits: register_int_counter_vec!(
+        "static_files_precompressed_hits_total",
+        "Requests served from a pre-compressed file variant, by compression algorithm",
+        &["algorithm"]
+    )
+    .unwrap(),
+    resolution_errors: register_int_counter_vec!(
+        "static_files_resolution_errors_total",
+        "Errors resolving or opening the requested file, by error kind",
+        &["kind"]
+    )
+    .unwrap(),
+    time_to_first_byte: register_histogram!(
+        "static_files_time_to_first_byte_seconds",
+        "Time from receiving the request until the response header of a file is sent",
+        exponential_buckets(0.0001, 2.0, 16).unwrap()
+    )
+    .unwrap(),
+});
+
+impl Metrics {
+    /// Records a response sent by the handler, using the status and the number of body bytes
+    /// recorded in the session.
+    pub(crate) fn record_response(&self, session: &Session) {
+        if let Some(header) = session.response_written() {
+            self.requests.with_label_values(&[header.status.as_str()]).inc();
+        }
+        self.sent_bytes.inc_by(session.body_bytes_sent() as u64);
+    }
+
+    /// Records the outcome of a file request once the response header has been sent.
+    pub(crate) fn record_outcome(&self, outcome: Outcome, start: Instant) {
+        self.outcomes.with_label_values(&[outcome.label()]).inc();
+        self.time_to_first_byte.observe(start.elapsed().as_secs_f64());
+    }
+
+    /// Records a request served from a pre-compressed file.
+    pub(crate) fn record_precompressed(&self, algorithm: CompressionAlgorithm) {
+        self.precompressed_hits.with_label_values(&[algorithm.name()]).inc();
+    }
+
+    /// Records an error resolving or opening the requested file.
+    pub(crate) fn record_resolution_error(&self, kind: ErrorKind) {
+        let kind = format!("{kind:?}");
+        self.resolution_errors.with_label_values(&[kind.as_str()]).inc();
+    }
+}
+
+/// Produces the metrics of the default registry in Prometheus text format. This includes the
+/// static files handler metrics and any other metrics registered there.
+pub fn encode_metrics() -> Result<String, Box<Error>> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|err| Error::because(ErrorType::InternalError, "Failed encoding metrics", err))?;
+    String::from_utf8(buffer).map_err(|err| {
+        Error::because(ErrorType::InternalError, "Failed encoding metrics", err)
+    })
+}
+
+/// Returns the current values of the static files handler’s counters, keyed by metric name and
+/// labels like `static_files_requests_total{status="200"}`. For histograms, the number of
+/// observations is listed as `<name>_count`.
+pub(crate) fn counters() -> BTreeMap<String, u64> {
+    Lazy::force(&METRICS);
+
+    let mut result = BTreeMap::new();
+    for family in prometheus::gather() {
+        if !family.get_name().starts_with("static_files_") {
+            continue;
+        }
+        for metric in family.get_metric() {
+            let (suffix, value) = match family.get_field_type() {
+                MetricType::COUNTER => ("", metric.get_counter().get_value() as u64),
+                MetricType::HISTOGRAM => ("_count", metric.get_histogram().get_sample_count()),
+                _ => continue,
+            };
+            let labels: Vec<_> = metric
+                .get_label()
+                .iter()
+                .map(|label| format!("{}=\"{}\"", label.get_name(), label.get_value()))
+                .collect();
+            let mut name = format!("{}{suffix}", family.get_name());
+            if !labels.is_empty() {
+                name = format!("{name}{{{}}}", labels.join(","));
+            }
+            result.insert(name, value);
+        }
+    }
+    result
+}
+
+/// Responds to a metrics scrape request.
+pub(crate) async fn metrics_response(session: &mut Session) -> Result<(), Box<Error>> {
+    // Make sure the handler's metrics are listed even before the first request
+    Lazy::force(&METRICS);
+
+    let text = encode_metrics()?;
+    data_response(session, StatusCode::OK, TEXT_FORMAT, text).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn encoding() {
+        METRICS.record_precompressed(CompressionAlgorithm::Brotli);
+        METRICS.record_resolution_error(ErrorKind::PermissionDenied);
+        METRICS.record_outcome(Outcome::NotModified, Instant::now());
+
+        let text = encode_metrics().unwrap();
+        assert!(text.contains("static_files_precompressed_hits_total{algorithm=\"br\"}"));
+        assert!(text.contains("static_files_resolution_errors_total{kind=\"PermissionDenied\"}"));
+        assert!(text.contains("static_files_outcomes_total{outcome=\"not_modified\"}"));
+        assert!(text.contains("static_files_time_to_first_byte_seconds_count"));
+
+        let counters = counters();
+        assert!(counters["static_files_precompressed_hits_total{algorithm=\"br\"}"] >= 1);
+        assert!(counters["static_files_time_to_first_byte_seconds_count"] >= 1);
+    }
+}
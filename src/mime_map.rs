@@ -0,0 +1,227 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Determining MIME types of files from configured mappings, `mime.types` files and `mime_guess`
+
+use glob::{MatchOptions, Pattern};
+use mime_guess::Mime;
+use std::collections::HashMap;
+use std::path::Path;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Maps file paths to MIME types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MimeMap {
+    extensions: HashMap<String, Mime>,
+    globs: Vec<(Pattern, bool, Mime)>,
+    default: Mime,
+}
+
+impl Default for MimeMap {
+    fn default() -> Self {
+        Self {
+            extensions: HashMap::new(),
+            globs: Vec::new(),
+            default: mime_guess::mime::APPLICATION_OCTET_STREAM,
+        }
+    }
+}
+
+impl MimeMap {
+    /// Sets the MIME type to be used if no other type applies.
+    pub(crate) fn set_default(&mut self, mime: &str) -> Result<(), String> {
+        self.default = parse_mime(mime)?;
+        Ok(())
+    }
+
+    /// Adds a mapping. The key can be a file extension (with or without leading dot) or a glob
+    /// pattern. Patterns containing a slash are matched against the path relative to the root,
+    /// other patterns against the file name only.
+    pub(crate) fn add(&mut self, key: &str, mime: &str) -> Result<(), String> {
+        let mime = parse_mime(mime)?;
+        if key.contains(['*', '?', '[', '/']) {
+            let pattern =
+                Pattern::new(key.trim_start_matches('/')).map_err(|err| err.to_string())?;
+            self.globs.push((pattern, key.contains('/'), mime));
+        } else {
+            let ext = key.trim_start_matches('.').to_ascii_lowercase();
+            self.extensions.insert(ext, mime);
+        }
+        Ok(())
+    }
+
+    /// Adds the mappings from a file in Apache `mime.types` or nginx `types { … }` format.
+    /// Mappings already present are kept.
+    ///
+    /// Apache entries end with the line. Within braces, entries end with a semicolon like in
+    /// nginx and can span multiple lines.
+    pub(crate) fn add_mime_types(&mut self, text: &str) -> Result<(), String> {
+        let mut depth = 0usize;
+        let mut entry = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            for token in tokenize(line) {
+                match token {
+                    "{" => {
+                        // Block header like `types`
+                        entry.clear();
+                        depth += 1;
+                    }
+                    "}" | ";" => {
+                        self.add_entry(&entry)?;
+                        entry.clear();
+                        if token == "}" {
+                            depth = depth.saturating_sub(1);
+                        }
+                    }
+                    _ => entry.push((index + 1, token)),
+                }
+            }
+            if depth == 0 && !matches!(entry.as_slice(), [(_, "types")]) {
+                self.add_entry(&entry)?;
+                entry.clear();
+            }
+        }
+        self.add_entry(&entry)
+    }
+
+    /// Adds a `mime.types` entry: MIME type followed by extensions, along with line numbers.
+    fn add_entry(&mut self, entry: &[(usize, &str)]) -> Result<(), String> {
+        let Some(((line, mime), extensions)) = entry.split_first() else {
+            return Ok(());
+        };
+        let mime = parse_mime(mime).map_err(|err| format!("line {line}: {err}"))?;
+        for (_, ext) in extensions {
+            self.extensions
+                .entry(ext.to_ascii_lowercase())
+                .or_insert_with(|| mime.clone());
+        }
+        Ok(())
+    }
+
+    /// MIME type to be used if no other type applies
+    pub(crate) fn default_type(&self) -> &Mime {
+        &self.default
+    }
+
+    /// Looks up the MIME type for a path relative to the root, `None` if no mapping applies.
+    pub(crate) fn lookup(&self, rel_path: &Path) -> Option<Mime> {
+        let file_name = rel_path.file_name().map(Path::new).unwrap_or(rel_path);
+        for (pattern, full_path, mime) in &self.globs {
+            let path = if *full_path { rel_path } else { file_name };
+            if pattern.matches_path_with(path, MATCH_OPTIONS) {
+                return Some(mime.clone());
+            }
+        }
+
+        if let Some(mime) = rel_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.extensions.get(&ext.to_ascii_lowercase()))
+        {
+            return Some(mime.clone());
+        }
+
+        mime_guess::from_path(rel_path).first()
+    }
+}
+
+/// Splits a line into words and the separators `{`, `}` and `;`, these don’t need whitespace
+/// around them.
+fn tokenize(line: &str) -> impl Iterator<Item = &str> {
+    line.split_whitespace().flat_map(|word| {
+        let mut tokens = Vec::new();
+        let mut rest = word;
+        while let Some(pos) = rest.find(['{', '}', ';']) {
+            if pos > 0 {
+                tokens.push(&rest[..pos]);
+            }
+            tokens.push(&rest[pos..pos + 1]);
+            rest = &rest[pos + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(rest);
+        }
+        tokens
+    })
+}
+
+fn parse_mime(mime: &str) -> Result<Mime, String> {
+    mime.parse().map_err(|err| format!("invalid MIME type {mime}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn mapping() {
+        let mut map = MimeMap::default();
+        map.add_mime_types(
+            "# Comment\n\
+             application/wasm wasm\n\
+             types {\n\
+                 application/x-inhouse  ihx ihy;\n\
+                 application/vnd.openxmlformats-officedocument.presentationml.presentation\n\
+                     pptx;\n\
+                 text/plain txt;}\n\
+             types\n\
+             {\n\
+                 font/woff2 woff2;\n\
+             }\n",
+        )
+        .unwrap();
+        map.add(".webmanifest", "application/manifest+json").unwrap();
+        map.add("txt", "text/x-custom").unwrap();
+        map.add("*.min.js", "text/javascript").unwrap();
+        map.add("/data/**/*.json", "application/vnd.data+json").unwrap();
+        map.set_default("text/plain").unwrap();
+
+        let guess = |path: &str| {
+            map.lookup(Path::new(path))
+                .unwrap_or_else(|| map.default_type().clone())
+                .to_string()
+        };
+        assert_eq!(guess("module.wasm"), "application/wasm");
+        assert_eq!(guess("dir/file.IHY"), "application/x-inhouse");
+        assert_eq!(
+            guess("slides.pptx"),
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        );
+        assert_eq!(guess("font.woff2"), "font/woff2");
+        assert_eq!(guess("app.webmanifest"), "application/manifest+json");
+        assert_eq!(guess("file.txt"), "text/x-custom");
+        assert_eq!(guess("lib/app.min.js"), "text/javascript");
+        assert_eq!(guess("data/2024/stats.json"), "application/vnd.data+json");
+        assert_eq!(guess("other/stats.json"), "application/json");
+        assert_eq!(guess("image.png"), "image/png");
+        assert_eq!(guess("README"), "text/plain");
+        assert_eq!(map.lookup(Path::new("README")), None);
+
+        assert!(map.add("ext", "not a type").is_err());
+        assert!(map.add_mime_types("invalid ext\n").is_err());
+        // Errors refer to the line the entry starts at
+        assert!(map
+            .add_mime_types("types {\n  text/plain txt;\n  invalid\n    ext;\n}\n")
+            .unwrap_err()
+            .starts_with("line 3: "));
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backend serving files from an object store such as S3
+
+use async_trait::async_trait;
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, ObjectMeta, ObjectStore};
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::io::StreamReader;
+
+use crate::path::normalize_uri;
+use crate::storage::{EntryKind, EntryStat, OpenEntry, RangeReader, Storage};
+
+/// Storage backend mapping URI paths to keys of an object store.
+///
+/// Any [`ObjectStore`] implementation can be used: S3-compatible buckets in production,
+/// [`object_store::memory::InMemory`] or [`object_store::local::LocalFileSystem`] for tests.
+/// Directories are emulated via key prefixes, like most object store clients do.
+#[derive(Debug, Clone)]
+pub struct ObjectStoreStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    root: PathBuf,
+}
+
+impl ObjectStoreStorage {
+    /// Creates a new storage for the given object store. Only keys below `prefix` will be served.
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: ObjectPath) -> Self {
+        Self {
+            store,
+            prefix,
+            root: PathBuf::from("/"),
+        }
+    }
+
+    /// Creates a new storage from an object store URL like `s3://bucket/prefix`. Store
+    /// configuration such as credentials is taken from `AWS_*`, `AZURE_*` and `GOOGLE_*`
+    /// environment variables.
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        let url = url::Url::parse(url).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let options = std::env::vars().filter_map(|(key, value)| {
+            let key = key.to_ascii_lowercase();
+            if key.starts_with("aws_") || key.starts_with("azure_") || key.starts_with("google_")
+            {
+                Some((key, value))
+            } else {
+                None
+            }
+        });
+        let (store, prefix) = object_store::parse_url_opts(&url, options)?;
+        Ok(Self::new(store.into(), prefix))
+    }
+
+    /// Maps a storage path to the corresponding object key.
+    fn key(&self, path: &Path) -> Result<ObjectPath, Error> {
+        let rel_path = path
+            .strip_prefix(&self.root)
+            .map_err(|_| ErrorKind::InvalidData)?;
+
+        let mut key = self.prefix.clone();
+        for component in rel_path.components() {
+            match component {
+                Component::Normal(name) => {
+                    // Keys have to be valid UTF-8, so non-Unicode paths cannot exist
+                    key = key.child(name.to_str().ok_or(ErrorKind::NotFound)?);
+                }
+                _ => return Err(ErrorKind::InvalidData.into()),
+            }
+        }
+        Ok(key)
+    }
+
+    /// Retrieves the metadata of the object corresponding to a storage path, `None` if there is
+    /// no such object.
+    async fn head(&self, path: &Path, key: &ObjectPath) -> Result<Option<ObjectMeta>, Error> {
+        if path == self.root {
+            return Ok(None);
+        }
+
+        match self.store.head(key).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Produces the information for a storage path without a corresponding object, this is a
+    /// directory if any keys exist below it.
+    async fn dir_stat(&self, path: &Path, key: &ObjectPath) -> Result<EntryStat, Error> {
+        if path == self.root || self.is_dir(key).await? {
+            Ok(EntryStat {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: None,
+                etag: None,
+            })
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    /// Checks whether there are any keys with the given prefix.
+    async fn is_dir(&self, key: &ObjectPath) -> Result<bool, Error> {
+        let list = self.store.list_with_delimiter(Some(key)).await?;
+        Ok(!list.objects.is_empty() || !list.common_prefixes.is_empty())
+    }
+}
+
+/// Converts object metadata into the information about a file entry.
+fn file_stat(meta: &ObjectMeta) -> EntryStat {
+    EntryStat {
+        kind: EntryKind::File,
+        size: meta.size as u64,
+        modified: Some(SystemTime::from(meta.last_modified)),
+        // Weak validators have to stay weak, the contents might differ byte-wise
+        etag: meta.e_tag.as_deref().map(|etag| match etag.strip_prefix("W/") {
+            Some(etag) => format!("W/{}", etag.trim_matches('"')),
+            None => etag.trim_matches('"').to_owned(),
+        }),
+    }
+}
+
+#[async_trait]
+impl Storage for ObjectStoreStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let path = normalize_uri(uri_path, &self.root)?;
+        self.stat(&path).await?;
+        Ok(path)
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        let key = self.key(path)?;
+        if let Some(meta) = self.head(path, &key).await? {
+            return Ok(file_stat(&meta));
+        }
+        self.dir_stat(path, &key).await
+    }
+
+    async fn open(&self, path: &Path) -> Result<OpenEntry, Error> {
+        let key = self.key(path)?;
+        if let Some(meta) = self.head(path, &key).await? {
+            // Contents are requested separately, make sure these still belong to this version
+            let entry = OpenEntry::new(file_stat(&meta), path);
+            return Ok(match meta.e_tag {
+                Some(etag) => entry.with_if_match(etag),
+                None => entry,
+            });
+        }
+        Ok(OpenEntry::new(self.dir_stat(path, &key).await?, path))
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        self.open_range_if_match(path, None, start, end).await
+    }
+
+    async fn open_range_if_match(
+        &self,
+        path: &Path,
+        etag: Option<&str>,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        if end < start {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let key = self.key(path)?;
+        let range = usize::try_from(start).map_err(|_| ErrorKind::InvalidInput)?
+            ..usize::try_from(end + 1).map_err(|_| ErrorKind::InvalidInput)?;
+        let options = GetOptions {
+            if_match: etag.map(str::to_owned),
+            range: Some(range.into()),
+            ..Default::default()
+        };
+        let result = self.store.get_opts(&key, options).await?;
+        Ok(Box::new(StreamReader::new(result.into_stream())))
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        let key = self.key(path)?;
+        let list = self.store.list_with_delimiter(Some(&key)).await?;
+        let mut result: Vec<_> = list
+            .common_prefixes
+            .iter()
+            .chain(list.objects.iter().map(|meta| &meta.location))
+            .filter_map(|location| location.filename().map(OsString::from))
+            .collect();
+        result.sort();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use object_store::memory::InMemory;
+    use test_log::test;
+    use tokio::io::AsyncReadExt;
+
+    async fn make_storage() -> ObjectStoreStorage {
+        let store = InMemory::new();
+        for (key, data) in [
+            ("site/index.html", "<html>Hi!</html>\n"),
+            ("site/file.txt", "Hi!\n"),
+            ("site/subdir/empty.js", ""),
+            ("other/secret.txt", "Secret\n"),
+        ] {
+            store.put(&ObjectPath::from(key), data.into()).await.unwrap();
+        }
+        ObjectStoreStorage::new(Arc::new(store), ObjectPath::from("site"))
+    }
+
+    #[test(tokio::test)]
+    async fn resolve_and_read() {
+        let storage = make_storage().await;
+
+        let path = storage.resolve("/file%2etxt").await.unwrap();
+        assert_eq!(path, PathBuf::from("/file.txt"));
+
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 4);
+        assert!(stat.etag.is_some_and(|etag| !etag.contains('"')));
+
+        let mut data = String::new();
+        storage
+            .open_range(&path, 1, 2)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "i!");
+
+        let path = storage.resolve("/subdir/").await.unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap().kind, EntryKind::Directory);
+        assert_eq!(storage.path_to_uri(&path, true).unwrap(), "/subdir/");
+
+        assert_eq!(
+            storage.resolve("/missing.txt").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            storage.resolve("/../other/secret.txt").await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn listing() {
+        let storage = make_storage().await;
+        assert_eq!(
+            storage.list_dir(Path::new("/")).await.unwrap(),
+            vec![
+                OsString::from("file.txt"),
+                OsString::from("index.html"),
+                OsString::from("subdir"),
+            ]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn changed_object() {
+        let store = Arc::new(InMemory::new());
+        let key = ObjectPath::from("site/file.txt");
+        store.put(&key, "Hi!\n".into()).await.unwrap();
+        let storage = ObjectStoreStorage::new(store.clone(), ObjectPath::from("site"));
+
+        let path = storage.resolve("/file.txt").await.unwrap();
+        let entry = storage.open(&path).await.unwrap();
+        assert_eq!(entry.stat.size, 4);
+
+        // Contents of a different version aren't mixed with the information of the opened one
+        store.put(&key, "Bye!\n".into()).await.unwrap();
+        assert!(entry.read_range(&storage, 0, 3).await.is_err());
+
+        let mut data = String::new();
+        storage
+            .open(&path)
+            .await
+            .unwrap()
+            .read_range(&storage, 0, 4)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "Bye!\n");
+    }
+
+    #[test]
+    fn weak_etag() {
+        let meta = ObjectMeta {
+            location: ObjectPath::from("site/file.txt"),
+            last_modified: SystemTime::UNIX_EPOCH.into(),
+            size: 4,
+            e_tag: Some("W/\"abc\"".to_owned()),
+            version: None,
+        };
+        assert_eq!(file_stat(&meta).etag.as_deref(), Some("W/abc"));
+
+        let meta = ObjectMeta {
+            e_tag: Some("\"abc\"".to_owned()),
+            ..meta
+        };
+        assert_eq!(file_stat(&meta).etag.as_deref(), Some("abc"));
+    }
+}
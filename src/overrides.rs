@@ -0,0 +1,333 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-directory configuration overrides read from `.static.yaml` files
+
+use http::{HeaderName, HeaderValue};
+use log::warn;
+use pingora::http::ResponseHeader;
+use pingora::Error;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::io::AsyncReadExt;
+
+use crate::deserialize::OneOrMany;
+use crate::path::{normalize_uri, relative_path_to_uri};
+use crate::storage::{EntryKind, EntryStat, Storage};
+
+/// Name of the per-directory override files
+pub(crate) const OVERRIDES_FILE: &str = ".static.yaml";
+
+/// Headers that can be added via override files, all other headers are reserved to the central
+/// configuration and the handler
+const ALLOWED_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-language",
+    "content-security-policy",
+    "expires",
+    "link",
+    "referrer-policy",
+    "x-robots-tag",
+];
+
+/// Settings that can be overridden for a directory and its subdirectories
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DirectoryOverrides {
+    /// List of index files to look for in a directory
+    pub(crate) index_file: Option<OneOrMany<String>>,
+    /// URI path of the page to display instead of the default Not Found page
+    pub(crate) page_404: Option<String>,
+    /// The character set to declare for text files
+    pub(crate) declare_charset: Option<String>,
+    /// Additional response headers, e.g. `Cache-Control`
+    #[serde(default)]
+    pub(crate) headers: BTreeMap<String, String>,
+}
+
+impl DirectoryOverrides {
+    /// Fills in settings missing here from the overrides of a parent directory.
+    fn inherit(&mut self, parent: &Self) {
+        if self.index_file.is_none() {
+            self.index_file = parent.index_file.clone();
+        }
+        if self.page_404.is_none() {
+            self.page_404 = parent.page_404.clone();
+        }
+        if self.declare_charset.is_none() {
+            self.declare_charset = parent.declare_charset.clone();
+        }
+        for (name, value) in &parent.headers {
+            self.headers
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+
+    /// Makes sure that the custom headers can be added to a response and are allowed to be set
+    /// via override files.
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in &self.headers {
+            let header_name =
+                HeaderName::try_from(name).map_err(|err| format!("header {name}: {err}"))?;
+            if !ALLOWED_HEADERS.contains(&header_name.as_str()) {
+                return Err(format!("header {name} cannot be overridden"));
+            }
+            HeaderValue::try_from(value).map_err(|err| format!("header {name}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Adds the custom headers to a response header.
+    pub(crate) fn apply(
+        &self,
+        mut header: Box<ResponseHeader>,
+    ) -> Result<Box<ResponseHeader>, Box<Error>> {
+        for (name, value) in &self.headers {
+            header.insert_header(name.clone(), value)?;
+        }
+        Ok(header)
+    }
+}
+
+/// Cached state of an override file, `None` if the file is invalid
+type CacheEntry = (EntryStat, Option<Arc<DirectoryOverrides>>);
+
+/// Cache of parsed override files, entries are invalidated when the file changes. Invalid files
+/// are cached as well so that they aren't parsed and reported again on every request.
+#[derive(Debug, Default)]
+pub(crate) struct OverridesCache {
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl OverridesCache {
+    /// Removes the entries where `matches` returns `true` for the path, returns the number of
+    /// entries removed.
+    pub(crate) fn purge(&self, matches: impl Fn(&Path) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = entries.len();
+        entries.retain(|path, _| !matches(path));
+        len - entries.len()
+    }
+
+    /// Returns the number of cached entries.
+    pub(crate) fn entry_count(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Collects the overrides applying to a storage directory, walking up to the root. Settings
+    /// from override files closer to the directory take precedence.
+    pub(crate) async fn collect(&self, storage: &dyn Storage, dir: &Path) -> DirectoryOverrides {
+        let mut result = DirectoryOverrides::default();
+        let mut current = Some(dir);
+        while let Some(dir) = current.filter(|dir| dir.starts_with(storage.root())) {
+            if let Some(overrides) = self.load(storage, &dir.join(OVERRIDES_FILE)).await {
+                result.inherit(&overrides);
+            }
+            current = dir.parent();
+        }
+        result
+    }
+
+    /// Collects the overrides for a request path that doesn’t exist, starting with its closest
+    /// existing ancestor directory.
+    pub(crate) async fn collect_for_uri(
+        &self,
+        storage: &dyn Storage,
+        uri_path: &str,
+    ) -> DirectoryOverrides {
+        if let Ok(rel_path) = normalize_uri(uri_path, Path::new("")) {
+            for ancestor in rel_path.ancestors() {
+                let uri = relative_path_to_uri(ancestor, true);
+                let Ok(dir) = storage.resolve(&uri).await else {
+                    continue;
+                };
+                if storage
+                    .stat(&dir)
+                    .await
+                    .is_ok_and(|stat| stat.kind == EntryKind::Directory)
+                {
+                    return self.collect(storage, &dir).await;
+                }
+            }
+        }
+        DirectoryOverrides::default()
+    }
+
+    async fn load(&self, storage: &dyn Storage, path: &Path) -> Option<Arc<DirectoryOverrides>> {
+        let stat = storage
+            .stat(path)
+            .await
+            .ok()
+            .filter(|stat| stat.kind == EntryKind::File)?;
+
+        {
+            let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some((cached_stat, overrides)) = entries.get(path) {
+                if *cached_stat == stat {
+                    return overrides.clone();
+                }
+            }
+        }
+
+        let mut text = String::new();
+        if stat.size > 0 {
+            let result = match storage.open_range(path, 0, stat.size - 1).await {
+                Ok(mut reader) => reader.read_to_string(&mut text).await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("failed reading overrides file {path:?}: {err}");
+                return None;
+            }
+        }
+
+        let overrides = if text.trim().is_empty() {
+            Some(DirectoryOverrides::default())
+        } else {
+            match serde_yaml::from_str::<DirectoryOverrides>(&text)
+                .map_err(|err| err.to_string())
+                .and_then(|overrides| overrides.validate().map(|_| overrides))
+            {
+                Ok(overrides) => Some(overrides),
+                Err(err) => {
+                    warn!("ignoring invalid overrides file {path:?}: {err}");
+                    None
+                }
+            }
+        };
+
+        let overrides = overrides.map(Arc::new);
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_path_buf(), (stat, overrides.clone()));
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use test_log::test;
+
+    use crate::storage::LocalStorage;
+
+    #[test(tokio::test)]
+    async fn overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("docs/api")).unwrap();
+        fs::write(
+            dir.path().join(OVERRIDES_FILE),
+            "page_404: /404.html\nheaders:\n  Cache-Control: max-age=60\n  X-Robots-Tag: noindex\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("docs").join(OVERRIDES_FILE),
+            "index_file: [README.html]\nheaders:\n  Cache-Control: no-cache\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("docs/api").join(OVERRIDES_FILE), "[invalid").unwrap();
+
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let cache = OverridesCache::default();
+
+        let api = storage.resolve("/docs/api/").await.unwrap();
+        let overrides = cache.collect(&storage, &api).await;
+        assert_eq!(
+            overrides.index_file,
+            Some(vec!["README.html".to_owned()].into())
+        );
+        assert_eq!(overrides.page_404.as_deref(), Some("/404.html"));
+        assert_eq!(overrides.declare_charset, None);
+        assert_eq!(
+            overrides.headers.get("Cache-Control").map(String::as_str),
+            Some("no-cache")
+        );
+        assert_eq!(
+            overrides.headers.get("X-Robots-Tag").map(String::as_str),
+            Some("noindex")
+        );
+
+        let missing = cache.collect_for_uri(&storage, "/docs/missing/page").await;
+        assert_eq!(missing, overrides);
+
+        // Invalid files are cached as well
+        assert_eq!(cache.entry_count(), 3);
+
+        // Changes are picked up once the file is modified (size differs here)
+        let docs_file = dir.path().join("docs").join(OVERRIDES_FILE);
+        fs::write(&docs_file, "declare_charset: latin1\n").unwrap();
+        let overrides = cache.collect(&storage, &api).await;
+        assert_eq!(overrides.index_file, None);
+        assert_eq!(overrides.declare_charset.as_deref(), Some("latin1"));
+
+        // Unknown settings make the file invalid
+        fs::write(dir.path().join(OVERRIDES_FILE), "root: /etc\n").unwrap();
+        let root = storage.resolve("/").await.unwrap();
+        assert_eq!(
+            cache.collect(&storage, &root).await,
+            DirectoryOverrides::default()
+        );
+
+        // So do headers that aren't allowed
+        fs::write(
+            dir.path().join(OVERRIDES_FILE),
+            "headers:\n  X-Robots-Tag: noindex\n  content-length: 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            cache.collect(&storage, &root).await,
+            DirectoryOverrides::default()
+        );
+    }
+
+    #[test]
+    fn allowed_headers() {
+        let overrides = |name: &str| DirectoryOverrides {
+            headers: [(name.to_owned(), "value".to_owned())].into(),
+            ..Default::default()
+        };
+        assert!(overrides("Cache-Control").validate().is_ok());
+        assert!(overrides("X-Robots-Tag").validate().is_ok());
+        assert!(overrides("link").validate().is_ok());
+        for name in [
+            "Content-Type",
+            "Set-Cookie",
+            "Location",
+            "X-Content-Type-Options",
+            "Content-Length",
+            "content-range",
+            "Content-Encoding",
+            "Transfer-Encoding",
+            "ETag",
+            "Last-Modified",
+            "Accept-Ranges",
+            "Connection",
+        ] {
+            assert_eq!(
+                overrides(name).validate(),
+                Err(format!("header {name} cannot be overridden"))
+            );
+        }
+        assert!(overrides("invalid header").validate().is_err());
+    }
+}
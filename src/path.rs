@@ -16,7 +16,7 @@
 
 use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
 use std::io::{Error, ErrorKind};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 // This matches pingora logic, see https://github.com/cloudflare/pingora/blob/2501d4adb038d93613c0edbd7c1e3b3de9b415b1/pingora-core/src/protocols/http/v1/server.rs#L934
 const URI_ESC_CHARSET: &AsciiSet = &CONTROLS.add(b' ').add(b'<').add(b'>').add(b'"');
@@ -36,6 +36,22 @@ fn path_from_bytes(bytes: &[u8]) -> String {
     String::from_utf8_lossy(bytes).into_owned()
 }
 
+/// Decodes the path from a URI into a relative path without resolving `.` and `..` components.
+/// A trailing slash is dropped, so that `/file.txt/` resolves to the file and can be redirected.
+///
+/// This will result in [`ErrorKind::InvalidInput`] error if the path doesn’t start with a slash.
+pub(crate) fn decode_uri(uri_path: &str) -> Result<PathBuf, Error> {
+    let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
+    let uri_path = uri_path.strip_suffix('/').unwrap_or(uri_path);
+
+    let mut relative = PathBuf::new();
+    for component in uri_path.split('/') {
+        let decoded = percent_decode_str(component).collect::<Vec<_>>();
+        relative.push(path_from_bytes(&decoded))
+    }
+    Ok(relative)
+}
+
 /// Resolves the path from a URI against the path to a root directory.
 ///
 /// This will return an error under the following conditions:
@@ -45,17 +61,7 @@ fn path_from_bytes(bytes: &[u8]) -> String {
 /// * [`std::fs::canonicalize()`] failed: results in [`ErrorKind::NotFound`],
 ///   [`ErrorKind::PermissionDenied`] and other errors
 pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
-    let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
-
-    let uri_path = uri_path.strip_suffix('/').unwrap_or(uri_path);
-
-    let mut path = root.to_path_buf();
-    for component in uri_path.split('/') {
-        let decoded = percent_decode_str(component).collect::<Vec<_>>();
-        path.push(path_from_bytes(&decoded))
-    }
-
-    let path = path.canonicalize()?;
+    let path = root.join(decode_uri(uri_path)?).canonicalize()?;
 
     if path.starts_with(root) {
         Ok(path)
@@ -64,12 +70,47 @@ pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
     }
 }
 
+/// Resolves the path from a URI against a virtual root path without accessing the file system.
+/// This is meant for storage backends that don’t map to local directories, `.` and `..`
+/// components are resolved lexically.
+///
+/// This will return an error under the following conditions:
+///
+/// * Invalid path, not starting with a slash (/): results in [`ErrorKind::InvalidInput`]
+/// * Resolved path outside the root: results in [`ErrorKind::InvalidData`]
+pub fn normalize_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+    let relative = decode_uri(uri_path)?;
+
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if path == root {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                path.pop();
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(ErrorKind::InvalidData.into());
+            }
+        }
+    }
+    Ok(path)
+}
+
 /// Calculates the canonical URI path describing the path relative to a root directory.
 ///
 /// This will return `None` for paths outside the root directory.
 pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
     let rel_path = path.strip_prefix(root).ok()?;
+    Some(relative_path_to_uri(rel_path, path.is_dir()))
+}
 
+/// Calculates the canonical URI path for a path relative to the root. Unlike [`path_to_uri`], this
+/// doesn’t access the file system, the caller has to indicate whether the path is a directory.
+pub(crate) fn relative_path_to_uri(rel_path: &Path, is_dir: bool) -> String {
     let mut uri = String::from('/');
     for component in rel_path.components() {
         uri.push_str(
@@ -77,8 +118,31 @@ pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
         );
         uri.push('/');
     }
-    if !path.is_dir() && uri.len() > 1 {
+    if !is_dir && uri.len() > 1 {
         uri.pop();
     }
-    Some(uri)
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn normalizing() {
+        let root = Path::new("/");
+        assert_eq!(normalize_uri("/", root).unwrap(), PathBuf::from("/"));
+        assert_eq!(normalize_uri("/dir/file.txt", root).unwrap(), PathBuf::from("/dir/file.txt"));
+        assert_eq!(
+            normalize_uri("/dir/./sub//../file%2etxt", root).unwrap(),
+            PathBuf::from("/dir/file.txt")
+        );
+        assert_eq!(normalize_uri("/dir/", root).unwrap(), PathBuf::from("/dir"));
+        assert_eq!(decode_uri("/dir/file.txt/").unwrap(), PathBuf::from("dir/file.txt"));
+        assert_eq!(normalize_uri("dir", root).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(normalize_uri("/dir/../..", root).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(normalize_uri("/dir/%2Fetc", root).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,137 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rules selected by glob patterns matched against the request path
+
+use glob::{MatchOptions, Pattern, PatternError};
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::path::normalize_uri;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Glob pattern matched against normalized request paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PathPattern(Pattern);
+
+impl PathPattern {
+    /// Compiles a glob pattern.
+    pub(crate) fn new(pattern: &str) -> Result<Self, PatternError> {
+        Ok(Self(Pattern::new(pattern)?))
+    }
+
+    /// Checks whether a normalized request path matches the pattern. The directory form is
+    /// matched as well, so that `/private/**` applies to `/private` as well.
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.0.matches_with(path, MATCH_OPTIONS)
+            || self
+                .0
+                .matches_with(&format!("{}/", path.trim_end_matches('/')), MATCH_OPTIONS)
+    }
+}
+
+impl Display for PathPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// Rules with patterns compiled, the first rule with a matching pattern applies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PathRules<T> {
+    rules: Vec<(Option<PathPattern>, T)>,
+}
+
+impl<T> Default for PathRules<T> {
+    fn default() -> Self {
+        Self { rules: Vec::new() }
+    }
+}
+
+impl<T> PathRules<T> {
+    /// Compiles the patterns of the rules. Rules without a pattern apply to all paths.
+    pub(crate) fn new(
+        rules: impl IntoIterator<Item = (Option<String>, T)>,
+    ) -> Result<Self, PatternError> {
+        let rules = rules
+            .into_iter()
+            .map(|(pattern, rule)| {
+                Ok((pattern.as_deref().map(PathPattern::new).transpose()?, rule))
+            })
+            .collect::<Result<_, PatternError>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Finds the rule applying to a request path, `None` if no rule applies.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&T> {
+        self.find_with(uri_path, |_| true)
+    }
+
+    /// Finds the first rule applying to a request path that `filter` accepts, `None` if no rule
+    /// applies.
+    pub(crate) fn find_with(&self, uri_path: &str, filter: impl Fn(&T) -> bool) -> Option<&T> {
+        // Invalid paths are rejected in early_request_filter, no rules apply to them
+        let path = normalize_uri(uri_path, Path::new("/")).ok()?;
+        let path = path.to_string_lossy();
+        self.rules
+            .iter()
+            .find(|(pattern, rule)| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.matches(&path))
+                    && filter(rule)
+            })
+            .map(|(_, rule)| rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn matching() {
+        let rules = PathRules::new([
+            (Some("/private/**".to_owned()), 1),
+            (Some("/*.pdf".to_owned()), 2),
+            (None, 3),
+        ])
+        .unwrap();
+
+        assert_eq!(rules.find("/private/file.txt"), Some(&1));
+        assert_eq!(rules.find("/private"), Some(&1));
+        assert_eq!(rules.find("/private/"), Some(&1));
+        assert_eq!(rules.find("/public/../private/file.txt"), Some(&1));
+        assert_eq!(rules.find("/%70rivate/file.txt"), Some(&1));
+        assert_eq!(rules.find("/report.pdf"), Some(&2));
+        assert_eq!(rules.find("/docs/report.pdf"), Some(&3));
+
+        // Invalid paths are rejected before rules are looked up
+        assert_eq!(rules.find("private/file.txt"), None);
+        assert_eq!(rules.find("/../private"), None);
+
+        let rules = PathRules::new([(Some("/private/**".to_owned()), ())]).unwrap();
+        assert_eq!(rules.find("/public/file.txt"), None);
+        assert_eq!(PathRules::<()>::default().find("/file.txt"), None);
+
+        assert!(PathRules::new([(Some("/[".to_owned()), ())]).is_err());
+    }
+}
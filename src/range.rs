@@ -77,7 +77,8 @@ pub fn extract_range(session: &Session, meta: &Metadata) -> Option<Range> {
         .get(header::IF_RANGE)
         .and_then(|value| value.to_str().ok())
     {
-        if value != meta.etag
+        // If-Range requires strong comparison, weak validators never match
+        if (value != meta.etag || meta.etag.starts_with("W/"))
             && !meta
                 .modified
                 .as_ref()
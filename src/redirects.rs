@@ -0,0 +1,482 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Declarative redirect rules, including the Netlify `_redirects` file format
+
+use http::status::StatusCode;
+use log::warn;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::configuration::RedirectRule;
+use crate::path::{normalize_uri, relative_path_to_uri};
+use crate::path_rules::PathPattern;
+
+/// Parses a Netlify-style `_redirects` file. Each line contains source path, target and
+/// optionally the status code, with `!` suffix for forced rules. Rules that cannot be represented
+/// (rewrites, conditions) are skipped with a warning.
+pub(crate) fn parse_netlify(text: &str) -> Vec<RedirectRule> {
+    let mut rules = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        // Comments start at the beginning of a line or after whitespace, URLs can contain #
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let line = line
+            .split_once(" #")
+            .or_else(|| line.split_once("\t#"))
+            .map_or(line, |(line, _)| line);
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (from, to, status) = match fields.as_slice() {
+            [] => continue,
+            [from, to] => (*from, *to, "301"),
+            [from, to, status] => (*from, *to, *status),
+            _ => {
+                warn!("_redirects line {}: unsupported rule `{line}`", index + 1);
+                continue;
+            }
+        };
+
+        let (status, force) = match status.strip_suffix('!') {
+            Some(status) => (status, true),
+            None => (status, false),
+        };
+        let Some(status) = status.parse().ok().filter(|s| is_redirect_status(*s)) else {
+            warn!("_redirects line {}: unsupported status `{status}`", index + 1);
+            continue;
+        };
+
+        rules.push(RedirectRule {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            status,
+            preserve_query: true,
+            force,
+        });
+    }
+    rules
+}
+
+fn is_redirect_status(status: u16) -> bool {
+    matches!(status, 301 | 302 | 307 | 308)
+}
+
+/// Removes the trailing slash from a path for matching, `/dir/` and `/dir` are treated the same.
+fn normalize(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => path,
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    Exact,
+    Segments { segments: Vec<Segment>, splat: bool },
+    Glob(PathPattern),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    source: Source,
+    to: String,
+    status: StatusCode,
+    preserve_query: bool,
+    force: bool,
+}
+
+/// A redirect produced by a matching rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Redirect {
+    /// Redirect status code
+    pub(crate) status: StatusCode,
+    /// Redirect target with placeholders expanded
+    pub(crate) location: String,
+    /// If `false`, the redirect only applies if the requested file doesn’t exist
+    pub(crate) force: bool,
+}
+
+/// Compiled redirect table. Exact sources are looked up in a hash map, rules with placeholders
+/// are indexed by their first path segment. Only rules without a literal first segment, glob and
+/// regular expression sources are checked for every request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Redirects {
+    rules: Vec<Rule>,
+    exact: HashMap<String, usize>,
+    /// Rules to check by first path segment in rule order, including the rules in `other`
+    by_segment: HashMap<String, Vec<usize>>,
+    /// Rules to check for all paths in rule order
+    other: Vec<usize>,
+}
+
+impl Redirects {
+    /// Compiles the rules, invalid rules are an error.
+    pub(crate) fn new(rules: impl IntoIterator<Item = RedirectRule>) -> Result<Self, String> {
+        let mut result = Self::default();
+        for rule in rules {
+            result.add(rule)?;
+        }
+        Ok(result)
+    }
+
+    fn add(&mut self, rule: RedirectRule) -> Result<(), String> {
+        if !is_redirect_status(rule.status) {
+            return Err(format!(
+                "Unsupported redirect status {} for {}",
+                rule.status, rule.from
+            ));
+        }
+        let status = StatusCode::from_u16(rule.status).map_err(|err| err.to_string())?;
+
+        let index = self.rules.len();
+        let source = if let Some(regex) = rule.from.strip_prefix('~') {
+            self.add_other(index);
+            Source::Regex(Regex::new(regex).map_err(|err| err.to_string())?)
+        } else if !rule.from.starts_with('/') {
+            return Err(format!("Redirect source has to start with /: {}", rule.from));
+        } else if let Some((segments, splat)) = Self::parse_segments(&rule.from) {
+            let first = match segments.first() {
+                Some(Segment::Literal(first)) => Some(first.clone()),
+                _ => None,
+            };
+            let is_exact = !splat
+                && segments
+                    .iter()
+                    .all(|segment| matches!(segment, Segment::Literal(_)));
+            if is_exact {
+                self.exact
+                    .entry(normalize(&rule.from).to_owned())
+                    .or_insert(index);
+                Source::Exact
+            } else {
+                if let Some(first) = first {
+                    self.by_segment
+                        .entry(first)
+                        .or_insert_with(|| self.other.clone())
+                        .push(index);
+                } else {
+                    self.add_other(index);
+                }
+                Source::Segments { segments, splat }
+            }
+        } else {
+            self.add_other(index);
+            Source::Glob(PathPattern::new(&rule.from).map_err(|err| err.to_string())?)
+        };
+
+        self.rules.push(Rule {
+            source,
+            to: rule.to,
+            status,
+            preserve_query: rule.preserve_query,
+            force: rule.force,
+        });
+        Ok(())
+    }
+
+    /// Adds a rule to be checked for all paths. Rules are added in order, so the lists stay sorted.
+    fn add_other(&mut self, index: usize) {
+        self.other.push(index);
+        for indexes in self.by_segment.values_mut() {
+            indexes.push(index);
+        }
+    }
+
+    /// Splits a Netlify-style source into segments, `None` if it is a glob pattern.
+    fn parse_segments(from: &str) -> Option<(Vec<Segment>, bool)> {
+        let mut parts: Vec<_> = normalize(from)[1..].split('/').collect();
+        let splat = parts.last() == Some(&"*");
+        if splat {
+            parts.pop();
+        }
+
+        let mut segments = Vec::new();
+        for part in parts.into_iter().filter(|part| !part.is_empty()) {
+            if part.contains(['*', '?', '[']) {
+                return None;
+            }
+            segments.push(if let Some(name) = part.strip_prefix(':') {
+                Segment::Placeholder(name.to_owned())
+            } else {
+                Segment::Literal(part.to_owned())
+            });
+        }
+        Some((segments, splat))
+    }
+
+    /// Finds the first rule matching the request path and produces the redirect. Rules are
+    /// matched against the normalized path, so that `/%6Fld` matches the same rules as `/old`.
+    pub(crate) fn find(&self, uri_path: &str, query: Option<&str>) -> Option<Redirect> {
+        let path = normalize_uri(uri_path, Path::new("")).ok()?;
+        let path = relative_path_to_uri(&path, false);
+        let path = normalize(&path);
+        let exact = self.exact.get(path).copied();
+        let first = path.strip_prefix('/')?.split('/').next().unwrap_or("");
+
+        let (rule, mut location) = self
+            .by_segment
+            .get(first)
+            .unwrap_or(&self.other)
+            .iter()
+            // Only rules preceding the exact match can take precedence
+            .take_while(|index| exact.is_none_or(|exact| **index < exact))
+            .find_map(|index| {
+                let rule = &self.rules[*index];
+                rule.expand(path).map(|location| (rule, location))
+            })
+            .or_else(|| {
+                let rule = &self.rules[exact?];
+                Some((rule, rule.to.clone()))
+            })?;
+
+        if let Some(query) = query.filter(|_| rule.preserve_query) {
+            location.push(if location.contains('?') { '&' } else { '?' });
+            location.push_str(query);
+        }
+
+        Some(Redirect {
+            status: rule.status,
+            location,
+            force: rule.force,
+        })
+    }
+}
+
+impl Rule {
+    /// Matches the rule against a normalized path and expands the target if it matches.
+    fn expand(&self, path: &str) -> Option<String> {
+        match &self.source {
+            Source::Exact => None,
+            Source::Segments { segments, splat } => {
+                let mut parts = path[1..].split('/').filter(|part| !part.is_empty());
+                let mut values = HashMap::new();
+                for segment in segments {
+                    let part = parts.next()?;
+                    match segment {
+                        Segment::Literal(literal) if literal == part => {}
+                        Segment::Literal(_) => return None,
+                        Segment::Placeholder(name) => {
+                            values.insert(name.as_str(), part);
+                        }
+                    }
+                }
+
+                let rest = parts.collect::<Vec<_>>().join("/");
+                if !splat && !rest.is_empty() {
+                    return None;
+                }
+                Some(expand_placeholders(&self.to, &values, &rest))
+            }
+            Source::Glob(pattern) => {
+                if pattern.matches(path) {
+                    Some(self.to.clone())
+                } else {
+                    None
+                }
+            }
+            Source::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let mut location = String::new();
+                captures.expand(&self.to, &mut location);
+                Some(location)
+            }
+        }
+    }
+}
+
+/// Replaces `:name` and `:splat` placeholders in the target, unknown placeholders are left
+/// unchanged.
+fn expand_placeholders(to: &str, values: &HashMap<&str, &str>, splat: &str) -> String {
+    let mut result = String::with_capacity(to.len());
+    let mut rest = to;
+    while let Some(pos) = rest.find(':') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        if name == "splat" {
+            result.push_str(splat);
+        } else if let Some(value) = values.get(name).filter(|_| len > 0) {
+            result.push_str(value);
+        } else {
+            result.push(':');
+            result.push_str(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn rule(from: &str, to: &str, status: u16) -> RedirectRule {
+        RedirectRule {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            status,
+            preserve_query: true,
+            force: true,
+        }
+    }
+
+    fn location(redirects: &Redirects, path: &str) -> Option<String> {
+        redirects.find(path, None).map(|redirect| redirect.location)
+    }
+
+    #[test]
+    fn matching() {
+        let redirects = Redirects::new([
+            rule("/blog/:year/:slug", "/posts/:year-:slug", 301),
+            rule("/old.html", "/new.html", 308),
+            rule("/docs/*", "https://docs.example.com/:splat", 302),
+            rule("/**/*.php", "/legacy", 301),
+            rule("~^/post/(?P<id>\\d+)$", "/p/${id}", 307),
+            rule("/old.html", "/ignored.html", 301),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            redirects.find("/old.html", Some("a=b")),
+            Some(Redirect {
+                status: StatusCode::PERMANENT_REDIRECT,
+                location: "/new.html?a=b".to_owned(),
+                force: true,
+            })
+        );
+        assert_eq!(
+            location(&redirects, "/blog/2020/hello/"),
+            Some("/posts/2020-hello".to_owned())
+        );
+        assert_eq!(location(&redirects, "/blog/2020"), None);
+        assert_eq!(
+            location(&redirects, "/docs/guide/intro"),
+            Some("https://docs.example.com/guide/intro".to_owned())
+        );
+        assert_eq!(
+            location(&redirects, "/docs"),
+            Some("https://docs.example.com/".to_owned())
+        );
+        assert_eq!(
+            location(&redirects, "/app/index.php"),
+            Some("/legacy".to_owned())
+        );
+        assert_eq!(location(&redirects, "/post/12"), Some("/p/12".to_owned()));
+        assert_eq!(location(&redirects, "/post/abc"), None);
+        assert_eq!(location(&redirects, "/index.html"), None);
+
+        // Rules match the normalized path
+        assert_eq!(
+            location(&redirects, "/%6Fld.html"),
+            Some("/new.html".to_owned())
+        );
+        assert_eq!(
+            location(&redirects, "/docs/../blog/2020/hello"),
+            Some("/posts/2020-hello".to_owned())
+        );
+        assert_eq!(location(&redirects, "/../old.html"), None);
+
+        assert!(Redirects::new([rule("/a", "/b", 200)]).is_err());
+        assert!(Redirects::new([rule("a", "/b", 301)]).is_err());
+    }
+
+    #[test]
+    fn rule_order() {
+        let redirects = Redirects::new([
+            rule("/shop/*", "/store/:splat", 301),
+            rule("/shop/cart", "/cart", 301),
+        ])
+        .unwrap();
+        assert_eq!(
+            location(&redirects, "/shop/cart"),
+            Some("/store/cart".to_owned())
+        );
+
+        // Indexed and general rules are checked in rule order
+        let redirects = Redirects::new([
+            rule("/**/*.php", "/legacy", 301),
+            rule("/shop/:page", "/store/:page", 301),
+            rule("/*/cart", "/cart", 301),
+            rule("/shop/*", "/store", 301),
+        ])
+        .unwrap();
+        assert_eq!(
+            location(&redirects, "/shop/index.php"),
+            Some("/legacy".to_owned())
+        );
+        assert_eq!(
+            location(&redirects, "/shop/cart"),
+            Some("/store/cart".to_owned())
+        );
+        assert_eq!(
+            location(&redirects, "/shop/a/cart"),
+            Some("/store".to_owned())
+        );
+        assert_eq!(location(&redirects, "/home/cart"), Some("/cart".to_owned()));
+    }
+
+    #[test]
+    fn netlify() {
+        let rules = parse_netlify(
+            "# Comment\n\
+             /home / 301!\n\
+             /news/:year/* /blog/:year/:splat 302\n\
+             \n\
+             /spa/* /index.html 200\n\
+             /old /new # trailing comment\n\
+             /geo /de 302 Country=de\n",
+        );
+        assert_eq!(
+            rules,
+            vec![
+                RedirectRule {
+                    from: "/home".to_owned(),
+                    to: "/".to_owned(),
+                    status: 301,
+                    preserve_query: true,
+                    force: true,
+                },
+                RedirectRule {
+                    from: "/news/:year/*".to_owned(),
+                    to: "/blog/:year/:splat".to_owned(),
+                    status: 302,
+                    preserve_query: true,
+                    force: false,
+                },
+                RedirectRule {
+                    from: "/old".to_owned(),
+                    to: "/new".to_owned(),
+                    status: 301,
+                    preserve_query: true,
+                    force: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signed, expiring URLs
+//!
+//! A signed URL carries the query parameters `expires` (Unix timestamp), optionally `ip` (client
+//! address the link is bound to) and `sig`. The signature is the URL-safe base64 encoding of
+//! HMAC-SHA256 over the request path as it appears in the URL, the expiration time and the IP
+//! address, separated by newlines. Other query parameters aren’t signed.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use glob::PatternError;
+use hmac::{Hmac, Mac};
+use http::status::StatusCode;
+use log::debug;
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::configuration::SignedUrlRule;
+use crate::path_rules::PathRules;
+
+/// Signed URL rules with patterns compiled
+#[derive(Debug, Default)]
+pub(crate) struct SignedUrlRules {
+    rules: PathRules<SignedUrls>,
+}
+
+impl SignedUrlRules {
+    /// Compiles the rules from the configuration.
+    pub(crate) fn new(
+        rules: impl IntoIterator<Item = SignedUrlRule>,
+    ) -> Result<Self, PatternError> {
+        let rules = PathRules::new(rules.into_iter().map(|rule| {
+            let keys = rule.keys.into();
+            (rule.pattern, SignedUrls { keys })
+        }))?;
+        Ok(Self { rules })
+    }
+
+    /// Finds the rule applying to a request path, `None` if the path doesn’t require signatures.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&SignedUrls> {
+        self.rules.find(uri_path)
+    }
+}
+
+/// Paths requiring signed URLs
+#[derive(Debug)]
+pub(crate) struct SignedUrls {
+    keys: Vec<String>,
+}
+
+impl SignedUrls {
+    /// Verifies the signature of a request, returns the response status on failure: `403
+    /// Forbidden` for missing or invalid signatures, `410 Gone` for expired links.
+    pub(crate) fn verify(
+        &self,
+        uri_path: &str,
+        query: Option<&str>,
+        client_ip: Option<IpAddr>,
+        now: SystemTime,
+    ) -> Result<(), StatusCode> {
+        let (mut expires, mut ip, mut sig) = (None, None, None);
+        for param in query.unwrap_or("").split('&') {
+            match param.split_once('=') {
+                Some(("expires", value)) => expires = Some(value),
+                Some(("ip", value)) => ip = Some(value),
+                Some(("sig", value)) => sig = Some(value),
+                _ => {}
+            }
+        }
+
+        let (Some(expires), Some(sig)) = (expires, sig) else {
+            debug!("request to {uri_path} lacks a signature");
+            return Err(StatusCode::FORBIDDEN);
+        };
+        let Ok(sig) = URL_SAFE_NO_PAD.decode(sig) else {
+            debug!("malformed signature for {uri_path}");
+            return Err(StatusCode::FORBIDDEN);
+        };
+
+        let valid = self.keys.iter().any(|key| {
+            mac(key.as_bytes(), uri_path, expires, ip.unwrap_or(""))
+                .verify_slice(&sig)
+                .is_ok()
+        });
+        if !valid {
+            debug!("invalid signature for {uri_path}");
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        if let Some(ip) = ip {
+            if client_ip.is_none() || ip.parse::<IpAddr>().ok() != client_ip {
+                debug!("signed link to {uri_path} is bound to {ip}, used by {client_ip:?}");
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        let expires = expires.parse::<u64>().map_err(|_| StatusCode::FORBIDDEN)?;
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        if expires < now {
+            debug!("signed link to {uri_path} expired at {expires}");
+            return Err(StatusCode::GONE);
+        }
+        Ok(())
+    }
+}
+
+/// Initializes HMAC-SHA256 with the signed data of a URL.
+fn mac(key: &[u8], uri_path: &str, expires: &str, ip: &str) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(uri_path.as_bytes());
+    mac.update(b"\n");
+    mac.update(expires.as_bytes());
+    mac.update(b"\n");
+    mac.update(ip.as_bytes());
+    mac
+}
+
+/// Signs a URL path with a key from the `signed_urls` configuration, returns the path with the
+/// `expires`, `ip` and `sig` query parameters added. `uri_path` has to be given exactly as it
+/// will appear in the URL, including any percent encoding. If `ip` is given, the link will only
+/// be accepted from this client address.
+///
+/// ```
+/// use resource_proxy_pingora::sign_url;
+/// use std::time::{Duration, SystemTime};
+///
+/// let expires = SystemTime::now() + Duration::from_secs(3600);
+/// let url = sign_url("/exports/report.csv", "secret", expires, None);
+/// assert!(url.starts_with("/exports/report.csv?expires="));
+/// ```
+pub fn sign_url(uri_path: &str, key: &str, expires: SystemTime, ip: Option<IpAddr>) -> String {
+    let expires = expires
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+        .to_string();
+    let ip = ip.map(|ip| ip.to_string());
+    let sig = mac(
+        key.as_bytes(),
+        uri_path,
+        &expires,
+        ip.as_deref().unwrap_or(""),
+    )
+    .finalize()
+    .into_bytes();
+    let sig = URL_SAFE_NO_PAD.encode(sig);
+
+    match ip {
+        Some(ip) => format!("{uri_path}?expires={expires}&ip={ip}&sig={sig}"),
+        None => format!("{uri_path}?expires={expires}&sig={sig}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+    use test_log::test;
+
+    fn rules() -> SignedUrlRules {
+        SignedUrlRules::new([SignedUrlRule {
+            pattern: Some("/exports/**".to_owned()),
+            keys: vec!["new".to_owned(), "old".to_owned()].into(),
+        }])
+        .unwrap()
+    }
+
+    fn verify(rules: &SignedUrlRules, url: &str, client_ip: &str) -> Result<(), StatusCode> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        rules.find(path).unwrap().verify(
+            path,
+            Some(query),
+            client_ip.parse().ok(),
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn matching() {
+        let rules = rules();
+        assert!(rules.find("/exports/file.csv").is_some());
+        assert!(rules.find("/exports").is_some());
+        assert!(rules.find("/public/../exports/file.csv").is_some());
+        assert!(rules.find("/public/file.csv").is_none());
+    }
+
+    #[test]
+    fn verification() {
+        let rules = rules();
+        let future = SystemTime::now() + Duration::from_secs(60);
+        let past = SystemTime::now() - Duration::from_secs(60);
+
+        let url = sign_url("/exports/file.csv", "new", future, None);
+        assert_eq!(verify(&rules, &url, "127.0.0.1"), Ok(()));
+        assert_eq!(
+            verify(&rules, &format!("{url}&download"), "127.0.0.1"),
+            Ok(())
+        );
+
+        // Rotated key still valid, unknown key isn't
+        let url = sign_url("/exports/file.csv", "old", future, None);
+        assert_eq!(verify(&rules, &url, "127.0.0.1"), Ok(()));
+        let url = sign_url("/exports/file.csv", "other", future, None);
+        assert_eq!(
+            verify(&rules, &url, "127.0.0.1"),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        // Signature doesn't transfer to other paths or expiration times
+        let url = sign_url("/exports/file.csv", "new", future, None);
+        let other = url.replace("file.csv", "other.csv");
+        assert_eq!(
+            verify(&rules, &other, "127.0.0.1"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        let other = url.replace("expires=", "expires=1");
+        assert_eq!(
+            verify(&rules, &other, "127.0.0.1"),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        assert_eq!(
+            verify(&rules, "/exports/file.csv", "127.0.0.1"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            verify(&rules, "/exports/file.csv?expires=1&sig=!!!", "127.0.0.1"),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        let url = sign_url("/exports/file.csv", "new", past, None);
+        assert_eq!(verify(&rules, &url, "127.0.0.1"), Err(StatusCode::GONE));
+    }
+
+    #[test]
+    fn ip_binding() {
+        let rules = rules();
+        let future = SystemTime::now() + Duration::from_secs(60);
+
+        let url = sign_url(
+            "/exports/file.csv",
+            "new",
+            future,
+            "2001:db8::1".parse().ok(),
+        );
+        assert!(url.contains("&ip=2001:db8::1&"));
+        assert_eq!(verify(&rules, &url, "2001:db8::1"), Ok(()));
+        assert_eq!(
+            verify(&rules, &url, "2001:db8::2"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(verify(&rules, &url, ""), Err(StatusCode::FORBIDDEN));
+
+        // Removing the binding invalidates the signature
+        let unbound = url.replace("&ip=2001:db8::1", "");
+        assert_eq!(
+            verify(&rules, &unbound, "2001:db8::2"),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Determining MIME types from file contents if the file name doesn’t indicate one
+
+use log::{debug, warn};
+use mime_guess::Mime;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use crate::storage::{EntryStat, OpenEntry, Storage};
+
+/// Number of bytes at the start of the file to look at
+const SNIFF_LENGTH: u64 = 512;
+
+/// Number of sniffing results to remember, the cache is cleared once it is full
+const MAX_ENTRIES: usize = 10000;
+
+/// File signatures and the corresponding MIME types
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+];
+
+/// Tags that an HTML document can start with, these have to be followed by whitespace or `>`
+const HTML_TAGS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<script",
+    b"<style",
+    b"<title",
+    b"<iframe",
+    b"<table",
+    b"<div",
+    b"<p",
+    b"<br",
+    b"<h1",
+    b"<a",
+    b"<b",
+    b"<font",
+];
+
+/// Determines the MIME type from the start of the file contents, `None` if the contents aren’t
+/// recognized.
+pub(crate) fn sniff(data: &[u8]) -> Option<Mime> {
+    let mime = if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        *mime
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else if !is_text(data) {
+        return None;
+    } else {
+        let text = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+        let start = text
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(text.len());
+        let text = &text[start..];
+        if is_html(text) {
+            "text/html"
+        } else if text.starts_with(b"{") || text.starts_with(b"[") {
+            "application/json"
+        } else {
+            "text/plain"
+        }
+    };
+    mime.parse().ok()
+}
+
+/// Checks whether the data is UTF-8 text without control characters. The data might have been
+/// cut off in the middle of a character, an incomplete character at the end is accepted.
+fn is_text(data: &[u8]) -> bool {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            // Valid up to this point as checked by from_utf8()
+            std::str::from_utf8(&data[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+}
+
+fn is_html(text: &[u8]) -> bool {
+    if text.starts_with(b"<!--") {
+        return true;
+    }
+
+    HTML_TAGS.iter().any(|tag| {
+        text.len() > tag.len()
+            && text[..tag.len()].eq_ignore_ascii_case(tag)
+            && matches!(text[tag.len()], b' ' | b'\t' | b'\n' | b'\r' | b'>')
+    })
+}
+
+/// Cache of sniffing results, entries are invalidated when the file changes. The number of entries
+/// is limited to `MAX_ENTRIES`.
+#[derive(Debug, Default)]
+pub(crate) struct SniffCache {
+    entries: Mutex<HashMap<PathBuf, (EntryStat, Option<Mime>)>>,
+}
+
+impl SniffCache {
+    /// Removes the entries where `matches` returns `true` for the path, returns the number of
+    /// entries removed.
+    pub(crate) fn purge(&self, matches: impl Fn(&Path) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = entries.len();
+        entries.retain(|path, _| !matches(path));
+        len - entries.len()
+    }
+
+    /// Returns the number of cached entries.
+    pub(crate) fn entry_count(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Determines the MIME type of an opened file from its contents, `None` if the contents
+    /// aren’t recognized.
+    pub(crate) async fn sniff(
+        &self,
+        storage: &dyn Storage,
+        entry: &mut OpenEntry,
+    ) -> Option<Mime> {
+        {
+            let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some((stat, mime)) = entries.get(entry.path()) {
+                if *stat == entry.stat {
+                    return mime.clone();
+                }
+            }
+        }
+
+        let mime = match entry.read_head(storage, SNIFF_LENGTH).await {
+            Ok(data) => sniff(&data),
+            Err(err) => {
+                warn!("failed reading {:?} for content sniffing: {err}", entry.path());
+                return None;
+            }
+        };
+        debug!("sniffed MIME type of {:?}: {mime:?}", entry.path());
+
+        self.insert(entry.path(), entry.stat.clone(), mime.clone());
+        mime
+    }
+
+    fn insert(&self, path: &Path, stat: EntryStat, mime: Option<Mime>) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(path) {
+            entries.clear();
+        }
+        entries.insert(path.to_path_buf(), (stat, mime));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use test_log::test;
+    use tokio::io::AsyncReadExt;
+
+    use crate::storage::{EntryKind, LocalStorage};
+
+    fn sniffed(data: &[u8]) -> Option<String> {
+        sniff(data).map(|mime| mime.to_string())
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(
+            sniffed(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(sniffed(b"\xff\xd8\xff\xe0\0\x10JFIF").as_deref(), Some("image/jpeg"));
+        assert_eq!(sniffed(b"GIF89a\x01\0\x01\0").as_deref(), Some("image/gif"));
+        assert_eq!(sniffed(b"RIFF\x24\0\0\0WEBPVP8 ").as_deref(), Some("image/webp"));
+        assert_eq!(sniffed(b"%PDF-1.7\n").as_deref(), Some("application/pdf"));
+        assert_eq!(sniffed(b"\x1f\x8b\x08\0\0\0\0\0").as_deref(), Some("application/gzip"));
+
+        assert_eq!(
+            sniffed(b"\xef\xbb\xbf\n  <!DOCTYPE html>\n<html>").as_deref(),
+            Some("text/html")
+        );
+        assert_eq!(sniffed(b"<HTML lang=\"en\">").as_deref(), Some("text/html"));
+        assert_eq!(sniffed(b"<!-- comment -->").as_deref(), Some("text/html"));
+        assert_eq!(sniffed(b"<body>").as_deref(), Some("text/html"));
+        assert_eq!(sniffed(b"<bodyguard>").as_deref(), Some("text/plain"));
+        assert_eq!(sniffed(b" {\"key\": 1}").as_deref(), Some("application/json"));
+        assert_eq!(sniffed(b"[1, 2, 3]").as_deref(), Some("application/json"));
+        assert_eq!(sniffed("Grüße\r\n".as_bytes()).as_deref(), Some("text/plain"));
+        // Cut off in the middle of a character
+        assert_eq!(sniffed(b"Gr\xc3").as_deref(), Some("text/plain"));
+
+        assert_eq!(sniffed(b""), None);
+        assert_eq!(sniffed(b"\0\x01\x02\x03"), None);
+        assert_eq!(sniffed(b"\xc3\x28 invalid"), None);
+    }
+
+    #[test(tokio::test)]
+    async fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("page");
+        fs::write(&file, "<html><body>Hi!</body></html>").unwrap();
+
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let cache = SniffCache::default();
+        let path = storage.resolve("/page").await.unwrap();
+
+        let mut entry = storage.open(&path).await.unwrap();
+        let mime = cache.sniff(&storage, &mut entry).await;
+        assert_eq!(mime.map(|mime| mime.to_string()).as_deref(), Some("text/html"));
+
+        // The entry can still be read in full after sniffing
+        let mut data = String::new();
+        entry
+            .read_range(&storage, 0, 4)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "<html");
+
+        // Changed files are sniffed again
+        fs::write(&file, "Plain text now").unwrap();
+        let mut entry = storage.open(&path).await.unwrap();
+        let mime = cache.sniff(&storage, &mut entry).await;
+        assert_eq!(mime.map(|mime| mime.to_string()).as_deref(), Some("text/plain"));
+
+        assert_eq!(cache.entry_count(), 1);
+        assert_eq!(cache.purge(|path| path.ends_with("other")), 0);
+        assert_eq!(cache.purge(|path| path.ends_with("page")), 1);
+        assert_eq!(cache.entry_count(), 0);
+    }
+
+    #[test]
+    fn cache_limit() {
+        let cache = SniffCache::default();
+        let stat = EntryStat {
+            kind: EntryKind::File,
+            size: 0,
+            modified: None,
+            etag: None,
+        };
+        for index in 0..MAX_ENTRIES {
+            cache.insert(&PathBuf::from(format!("/{index}")), stat.clone(), None);
+        }
+        assert_eq!(cache.entry_count(), MAX_ENTRIES);
+
+        // Updating an existing entry keeps the cache
+        cache.insert(Path::new("/0"), stat.clone(), None);
+        assert_eq!(cache.entry_count(), MAX_ENTRIES);
+
+        cache.insert(Path::new("/new"), stat, None);
+        assert_eq!(cache.entry_count(), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use http::{header, method::Method, status::StatusCode};
+use http::{header, header::HeaderName, method::Method, status::StatusCode};
 use maud::{html, DOCTYPE};
 
 use pingora::{Error};
@@ -34,19 +34,15 @@ pub fn response_text(status: StatusCode) -> String {
 async fn response(
     session: &mut Session,
     status: StatusCode,
-    location: Option<&str>,
-    cookie: Option<&str>,
+    headers: &[(HeaderName, &str)],
 ) -> Result<(), Box<Error>> {
     let text = response_text(status);
 
     let mut header = ResponseHeader::build(status, Some(4))?;
     header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
-    if let Some(location) = location {
-        header.append_header(header::LOCATION, location)?;
-    }
-    if let Some(cookie) = cookie {
-        header.append_header(header::SET_COOKIE, cookie)?;
+    for (name, value) in headers {
+        header.append_header(name.clone(), *value)?;
     }
 
     let send_body = session.req_header().method != Method::HEAD;
@@ -61,12 +57,49 @@ async fn response(
     Ok(())
 }
 
+/// Responds with the given data, e.g. a JSON document. The response isn’t supposed to be cached.
+pub async fn data_response(
+    session: &mut Session,
+    status: StatusCode,
+    content_type: &str,
+    data: String,
+) -> Result<(), Box<Error>> {
+    let mut header = ResponseHeader::build(status, Some(4))?;
+    header.append_header(header::CONTENT_LENGTH, data.len().to_string())?;
+    header.append_header(header::CONTENT_TYPE, content_type)?;
+    header.append_header(header::CACHE_CONTROL, "no-store")?;
+
+    let send_body = session.req_header().method != Method::HEAD;
+    session
+        .write_response_header(Box::new(header), !send_body)
+        .await?;
+
+    if send_body {
+        session.write_response_body(Some(data.into()), true).await?;
+    }
+
+    Ok(())
+}
+
 /// Responds with a standard error page for the given status code.
 pub async fn error_response(
     session: &mut Session,
     status: StatusCode,
 ) -> Result<(), Box<Error>> {
-    response(session, status, None, None).await
+    response(session, status, &[]).await
+}
+
+/// Responds with `401 Unauthorized`, sending the given `WWW-Authenticate` challenge.
+pub async fn unauthorized_response(
+    session: &mut Session,
+    challenge: &str,
+) -> Result<(), Box<Error>> {
+    response(
+        session,
+        StatusCode::UNAUTHORIZED,
+        &[(header::WWW_AUTHENTICATE, challenge)],
+    )
+    .await
 }
 
 /// Responds with a redirect to the given location.
@@ -75,7 +108,7 @@ pub async fn redirect_response(
     status: StatusCode,
     location: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), None).await
+    response(session, status, &[(header::LOCATION, location)]).await
 }
 
 /// Responds with a redirect to the given location and setting a cookie.
@@ -85,5 +118,10 @@ pub async fn redirect_response_with_cookie(
     location: &str,
     cookie: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), Some(cookie)).await
+    response(
+        session,
+        status,
+        &[(header::LOCATION, location), (header::SET_COOKIE, cookie)],
+    )
+    .await
 }
\ No newline at end of file
@@ -0,0 +1,1291 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backends that static files can be served from
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{info, warn};
+use pingora::proxy::Session;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Read, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::StreamReader;
+
+#[cfg(target_os = "linux")]
+use crate::beneath::RootDir;
+use crate::compression_algorithm::CompressionAlgorithm;
+use crate::configuration::SymlinkPolicy;
+use crate::path::{decode_uri, relative_path_to_uri, resolve_uri};
+
+/// Kind of a storage entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file that can be served
+    File,
+    /// A directory, can be listed or searched for index files
+    Directory,
+    /// Anything else (devices, sockets etc.), never served
+    Other,
+}
+
+/// Information about a storage entry as returned by [`Storage::stat`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryStat {
+    /// Entry kind
+    pub kind: EntryKind,
+    /// Entry size in bytes
+    pub size: u64,
+    /// Last modified time of the entry if known
+    pub modified: Option<SystemTime>,
+    /// Entity tag provided by the backend, without quotes and prefixed with `W/` if weak. If
+    /// `None`, an ETag will be derived from last modified time and size.
+    pub etag: Option<String>,
+}
+
+impl From<std::fs::Metadata> for EntryStat {
+    fn from(meta: std::fs::Metadata) -> Self {
+        let kind = if meta.is_file() {
+            EntryKind::File
+        } else if meta.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::Other
+        };
+
+        Self {
+            kind,
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            etag: None,
+        }
+    }
+}
+
+/// Reader producing the requested byte range of a storage entry
+pub type RangeReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Size of the chunks passed on by [`blocking_reader`]
+const BLOCKING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Adapts a blocking reader, e.g. a decompressor, into a [`RangeReader`]. The reader is created
+/// and read on Tokio's blocking thread pool, reading stops once the [`RangeReader`] is dropped.
+pub fn blocking_reader<R: Read>(
+    open: impl FnOnce() -> Result<R, Error> + Send + 'static,
+) -> RangeReader {
+    let (sender, receiver) = mpsc::channel(2);
+    tokio::task::spawn_blocking(move || {
+        let mut reader = match open() {
+            Ok(reader) => reader,
+            Err(err) => {
+                let _ = sender.blocking_send(Err(err));
+                return;
+            }
+        };
+
+        let mut buf = vec![0; BLOCKING_CHUNK_SIZE];
+        loop {
+            let chunk = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => Ok(Bytes::copy_from_slice(&buf[..len])),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = chunk.is_err();
+            if sender.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+    Box::new(StreamReader::new(ReceiverStream::new(receiver)))
+}
+
+/// Produces a reader for the given byte range of a local file, both `start` and `end` are
+/// inclusive.
+async fn file_range(file: File, start: u64, end: u64) -> Result<RangeReader, Error> {
+    let mut file = tokio::fs::File::from_std(file);
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(Box::new(file.take(end - start + 1)))
+}
+
+/// A storage entry opened for reading as returned by [`Storage::open`]. For local files, both the
+/// entry information and the contents come from the same file handle, so these stay consistent
+/// even if the file is replaced meanwhile.
+#[derive(Debug)]
+pub struct OpenEntry {
+    /// Information about the entry at the time it was opened
+    pub stat: EntryStat,
+    path: PathBuf,
+    file: Option<File>,
+    if_match: Option<String>,
+}
+
+impl OpenEntry {
+    /// Creates an entry for backends without file handles, contents will be read via
+    /// [`Storage::open_range`] using the given path.
+    pub fn new(stat: EntryStat, path: impl Into<PathBuf>) -> Self {
+        Self {
+            stat,
+            path: path.into(),
+            file: None,
+            if_match: None,
+        }
+    }
+
+    /// Makes reads of the entry fail if its contents no longer match the given backend-specific
+    /// entity tag, see [`Storage::open_range_if_match`].
+    pub fn with_if_match(mut self, etag: impl Into<String>) -> Self {
+        self.if_match = Some(etag.into());
+        self
+    }
+
+    /// Creates an entry for an open local file, entry information is retrieved from the handle.
+    pub fn from_file(file: File, path: impl Into<PathBuf>) -> Result<Self, Error> {
+        Ok(Self {
+            stat: file.metadata()?.into(),
+            path: path.into(),
+            file: Some(file),
+            if_match: None,
+        })
+    }
+
+    /// Storage path of the entry
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads up to `len` bytes from the start of the entry without consuming it, e.g. for content
+    /// sniffing. `storage` has to be the storage that opened the entry.
+    pub async fn read_head(&mut self, storage: &dyn Storage, len: u64) -> Result<Vec<u8>, Error> {
+        let len = len.min(self.stat.size);
+        let mut data = Vec::new();
+        if len == 0 {
+            return Ok(data);
+        }
+
+        let reader = if let Some(file) = &self.file {
+            // The clone shares the file position, read_range() will seek to the start again
+            file_range(file.try_clone()?, 0, len - 1).await?
+        } else {
+            storage
+                .open_range_if_match(&self.path, self.if_match.as_deref(), 0, len - 1)
+                .await?
+        };
+        reader.take(len).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Produces a reader for the given byte range, both `start` and `end` are inclusive.
+    /// `storage` has to be the storage that opened the entry.
+    pub async fn read_range(
+        self,
+        storage: &dyn Storage,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        if end < start {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        if let Some(file) = self.file {
+            file_range(file, start, end).await
+        } else {
+            storage
+                .open_range_if_match(&self.path, self.if_match.as_deref(), start, end)
+                .await
+        }
+    }
+}
+
+/// Abstraction of the storage that static files are served from.
+///
+/// Paths used by this trait are storage-specific: for the local file system these are absolute
+/// file paths, other backends will typically use paths relative to a virtual root `/`. The only
+/// requirement is that paths produced by [`Storage::resolve`] are accepted by the other methods
+/// and that [`Path::join`] can be used to address entries within a directory.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Root path of the storage, all paths produced by [`Storage::resolve`] start with it.
+    fn root(&self) -> &Path;
+
+    /// Produces the view of the storage to be used for the current request. Backends serving
+    /// different content depending on the request return a new storage here, all further
+    /// operations for this request will use it. `None` means that the storage itself is used.
+    async fn request_view(&self, _session: &Session) -> Result<Option<Arc<dyn Storage>>, Error> {
+        Ok(None)
+    }
+
+    /// Resolves the path from a URI into a storage path.
+    ///
+    /// The error kinds should match those of [`resolve_uri`]: [`ErrorKind::InvalidInput`] for
+    /// invalid URI paths, [`ErrorKind::InvalidData`] for paths outside the root,
+    /// [`ErrorKind::NotFound`] for missing entries.
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error>;
+
+    /// Retrieves the information about a storage entry.
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error>;
+
+    /// Opens a storage entry, producing its information along with a handle for reading its
+    /// contents. Backends with immutable contents can rely on the default implementation.
+    async fn open(&self, path: &Path) -> Result<OpenEntry, Error> {
+        Ok(OpenEntry::new(self.stat(path).await?, path))
+    }
+
+    /// Opens a storage entry for reading the given byte range, both `start` and `end` are
+    /// inclusive.
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error>;
+
+    /// Opens a storage entry for reading the given byte range like [`Storage::open_range`]. If
+    /// `etag` is given, reading fails unless the entry still matches this entity tag as
+    /// recorded by [`Storage::open`]. Backends that don't set one can rely on the default
+    /// implementation.
+    async fn open_range_if_match(
+        &self,
+        path: &Path,
+        _etag: Option<&str>,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        self.open_range(path, start, end).await
+    }
+
+    /// Lists the names of the entries within a directory.
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error>;
+
+    /// Determines the path of a storage entry relative to the root, as it appears in URIs.
+    /// Returns `None` for paths outside the root.
+    fn relative_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(self.root()).ok()
+    }
+
+    /// Calculates the canonical URI path of a storage path, `None` for paths outside the root.
+    fn path_to_uri(&self, path: &Path, is_dir: bool) -> Option<String> {
+        let rel_path = self.relative_path(path)?;
+        Some(relative_path_to_uri(rel_path, is_dir))
+    }
+
+    /// Checks whether the given storage path points to a regular file.
+    async fn is_file(&self, path: &Path) -> bool {
+        self.stat(path)
+            .await
+            .is_ok_and(|stat| stat.kind == EntryKind::File)
+    }
+
+    /// Returns the storages combined by this storage, empty for storages without layers.
+    fn layers(&self) -> &[Arc<dyn Storage>] {
+        &[]
+    }
+}
+
+/// Storage backend serving files from a local directory.
+///
+/// On Linux, files are opened relative to a handle of the root directory via `openat2()` with
+/// `RESOLVE_BENEATH`, so that replacing path components with symbolic links after the path has
+/// been resolved cannot escape the root. Unless all symbolic links are followed, `openat2()` also
+/// rejects them via `RESOLVE_NO_SYMLINKS` and only the path they resolve to after checking the
+/// symbolic link policy is opened. Where `openat2()` isn’t available, paths are checked against
+/// the canonicalized root and the symbolic link policy before opening instead.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+    #[cfg(target_os = "linux")]
+    root_dir: Option<Arc<RootDir>>,
+    symlinks: SymlinkPolicy,
+}
+
+impl LocalStorage {
+    /// Creates a new local storage for the given root directory. The root path will be
+    /// canonicalized.
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, Error> {
+        let root = root.as_ref().canonicalize()?;
+        Ok(Self {
+            #[cfg(target_os = "linux")]
+            root_dir: RootDir::open(&root)?.map(Arc::new),
+            root,
+            symlinks: SymlinkPolicy::Follow,
+        })
+    }
+
+    /// Sets the policy determining which symbolic links within the root directory are followed.
+    /// Allowed link targets should be absolute paths.
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = match policy {
+            SymlinkPolicy::AllowTargets(targets) => SymlinkPolicy::AllowTargets(
+                targets
+                    .into_iter()
+                    .map(|target| target.canonicalize().unwrap_or(target))
+                    .collect(),
+            ),
+            policy => policy,
+        };
+        self
+    }
+
+    /// Checks all symbolic links encountered when resolving a path relative to the root against
+    /// the symbolic link policy, produces the path with the links resolved. Will result in
+    /// [`ErrorKind::PermissionDenied`] if a link isn’t allowed.
+    fn check_symlinks(&self, rel_path: &Path) -> Result<PathBuf, Error> {
+        if self.symlinks == SymlinkPolicy::Follow {
+            return Ok(self.root.join(rel_path));
+        }
+
+        let mut current = self.root.clone();
+        for component in rel_path.components() {
+            match component {
+                Component::Normal(name) => {
+                    current.push(name);
+                    let meta = current.symlink_metadata()?;
+                    if meta.file_type().is_symlink() {
+                        let target = current.canonicalize()?;
+                        if !self.symlink_allowed(&meta, &target)? {
+                            info!(
+                                "denying access via symbolic link {current:?} pointing to \
+                                 {target:?}, symlinks policy: {}",
+                                self.symlinks
+                            );
+                            return Err(ErrorKind::PermissionDenied.into());
+                        }
+                        current = target;
+                    }
+                }
+                Component::ParentDir => {
+                    current.pop();
+                }
+                _ => {}
+            }
+        }
+        Ok(current)
+    }
+
+    /// Checks whether opening files enforces the symbolic link policy, otherwise paths have to be
+    /// checked before opening.
+    fn opening_checks_symlinks(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.root_dir.is_some();
+
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    fn symlink_allowed(&self, link_meta: &Metadata, target: &Path) -> Result<bool, Error> {
+        Ok(match &self.symlinks {
+            SymlinkPolicy::Follow => true,
+            SymlinkPolicy::Deny => false,
+            #[cfg(unix)]
+            SymlinkPolicy::OwnerMatch => {
+                use std::os::unix::fs::MetadataExt;
+
+                target.metadata()?.uid() == link_meta.uid()
+            }
+            #[cfg(not(unix))]
+            SymlinkPolicy::OwnerMatch => false,
+            SymlinkPolicy::AllowTargets(targets) => {
+                targets.iter().any(|allowed| target.starts_with(allowed))
+            }
+        })
+    }
+
+    /// Opens a file or directory for metadata access.
+    fn open_path(&self, path: &Path) -> Result<File, Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(root_dir) = &self.root_dir {
+            return self.open_beneath(path, |rel_path, follow| root_dir.open_path(rel_path, follow));
+        }
+
+        File::open(path)
+    }
+
+    /// Opens a file for reading.
+    fn open_read(&self, path: &Path) -> Result<File, Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(root_dir) = &self.root_dir {
+            return self.open_beneath(path, |rel_path, follow| root_dir.open_read(rel_path, follow));
+        }
+
+        File::open(path)
+    }
+
+    /// Opens a path via the root directory handle. `RESOLVE_BENEATH` rejects absolute symbolic
+    /// links even if they point into the root, so paths rejected this way are canonicalized and
+    /// retried. The canonical path has to be within the root, and the kernel still rejects
+    /// symbolic links if the path is changed meanwhile.
+    ///
+    /// Unless all symbolic links are followed, the kernel rejects them. The links are then checked
+    /// against the policy and the path they resolve to is opened, still rejecting symbolic links.
+    /// Links changed after the check make opening fail rather than bypass the policy.
+    #[cfg(target_os = "linux")]
+    fn open_beneath(
+        &self,
+        path: &Path,
+        open: impl Fn(&Path, bool) -> Result<File, Error>,
+    ) -> Result<File, Error> {
+        if self.symlinks != SymlinkPolicy::Follow {
+            return match open(self.relative(path)?, false) {
+                Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+                    let path = self.check_symlinks(self.relative(path)?)?;
+                    open(self.relative(&path)?, false).map_err(|err| {
+                        if err.raw_os_error() == Some(libc::ELOOP) {
+                            ErrorKind::PermissionDenied.into()
+                        } else {
+                            err
+                        }
+                    })
+                }
+                result => result,
+            };
+        }
+
+        match open(self.relative(path)?, true) {
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                let path = path.canonicalize()?;
+                open(self.relative(&path)?, true)
+            }
+            result => result,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn relative<'a>(&self, path: &'a Path) -> Result<&'a Path, Error> {
+        Ok(path
+            .strip_prefix(&self.root)
+            .map_err(|_| ErrorKind::InvalidData)?)
+    }
+}
+
+impl PartialEq for LocalStorage {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.symlinks == other.symlinks
+    }
+}
+
+impl Eq for LocalStorage {}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let path = resolve_uri(uri_path, &self.root)?;
+        let rel_path = decode_uri(uri_path)?;
+        if self.opening_checks_symlinks() {
+            // The resolved path has no symbolic links left, open the requested one to check them
+            // and to make sure that the path is still within the root when actually accessed
+            self.open_path(&self.root.join(rel_path))?;
+        } else {
+            self.check_symlinks(&rel_path)?;
+        }
+        Ok(path)
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        Ok(self.open_path(path)?.metadata()?.into())
+    }
+
+    async fn open(&self, path: &Path) -> Result<OpenEntry, Error> {
+        // Index files and pre-compressed variants don't go through resolve(), check them here
+        if let Ok(rel_path) = path.strip_prefix(&self.root) {
+            if !self.opening_checks_symlinks() {
+                self.check_symlinks(rel_path)?;
+            }
+        }
+
+        // Only regular files are opened, opening special files could block
+        let stat = self.stat(path).await?;
+        if stat.kind != EntryKind::File {
+            return Ok(OpenEntry::new(stat, path));
+        }
+
+        OpenEntry::from_file(self.open_read(path)?, path)
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        if end < start {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        file_range(self.open_read(path)?, start, end).await
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        // Only verifies that the directory is within the root, entry names are read by path
+        self.open_path(path)?;
+
+        let mut result = Vec::new();
+        for entry in path.read_dir()? {
+            result.push(entry?.file_name());
+        }
+        result.sort();
+        Ok(result)
+    }
+}
+
+/// Local storage for a root path that is a symbolic link switched on deployments, e.g.
+/// `/srv/site/current` pointing to the current release directory.
+///
+/// The link is re-resolved at most once per refresh interval, a zero interval means on every
+/// request. Each request gets a view of the release the link pointed to when the request started,
+/// so all files of a request come from the same release even if the link is switched meanwhile.
+#[derive(Debug)]
+pub struct SymlinkRootStorage {
+    link: PathBuf,
+    interval: Duration,
+    symlinks: SymlinkPolicy,
+    current: Mutex<(Instant, Arc<LocalStorage>)>,
+}
+
+impl SymlinkRootStorage {
+    /// Creates a new storage for the given root link, re-resolving it at most once per
+    /// `interval`.
+    pub fn new(link: impl AsRef<Path>, interval: Duration) -> Result<Self, Error> {
+        let link = link.as_ref().to_path_buf();
+        let storage = LocalStorage::new(&link)?;
+        Ok(Self {
+            link,
+            interval,
+            symlinks: SymlinkPolicy::Follow,
+            current: Mutex::new((Instant::now(), Arc::new(storage))),
+        })
+    }
+
+    /// Sets the policy determining which symbolic links within the release directories are
+    /// followed, see [`LocalStorage::with_symlink_policy`].
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        let current = self.current.get_mut().unwrap_or_else(PoisonError::into_inner);
+        current.1 = Arc::new((*current.1).clone().with_symlink_policy(policy.clone()));
+        self.symlinks = policy;
+        self
+    }
+
+    /// Returns the storage for the release the link currently points to.
+    pub fn current(&self) -> Arc<LocalStorage> {
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        if current.0.elapsed() >= self.interval {
+            match self.link.canonicalize() {
+                Ok(target) if target != current.1.root => {
+                    info!("root {:?} now points to {target:?}", self.link);
+                    let storage = LocalStorage::new(&target)
+                        .map(|storage| storage.with_symlink_policy(self.symlinks.clone()));
+                    match storage {
+                        Ok(storage) => current.1 = Arc::new(storage),
+                        Err(err) => warn!("failed accessing new root {target:?}: {err}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(
+                        "failed re-resolving root {:?}, keeping {:?}: {err}",
+                        self.link, current.1.root
+                    );
+                }
+            }
+            current.0 = Instant::now();
+        }
+        current.1.clone()
+    }
+}
+
+#[async_trait]
+impl Storage for SymlinkRootStorage {
+    fn root(&self) -> &Path {
+        &self.link
+    }
+
+    async fn request_view(&self, _session: &Session) -> Result<Option<Arc<dyn Storage>>, Error> {
+        Ok(Some(self.current()))
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        self.current().resolve(uri_path).await
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        self.current().stat(path).await
+    }
+
+    async fn open(&self, path: &Path) -> Result<OpenEntry, Error> {
+        self.current().open(path).await
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        self.current().open_range(path, start, end).await
+    }
+
+    async fn open_range_if_match(
+        &self,
+        path: &Path,
+        etag: Option<&str>,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        self.current()
+            .open_range_if_match(path, etag, start, end)
+            .await
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        self.current().list_dir(path).await
+    }
+
+    fn relative_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        self.current().relative_path(path)
+    }
+
+    fn path_to_uri(&self, path: &Path, is_dir: bool) -> Option<String> {
+        self.current().path_to_uri(path, is_dir)
+    }
+}
+
+/// Storage combining multiple storages as layers, the first layer containing a file wins.
+///
+/// Each layer resolves URIs on its own, so that e.g. the [`LocalStorage`] check for paths outside
+/// the root is applied per layer. Resolution stops at the first layer producing an error other
+/// than [`ErrorKind::NotFound`], a denied symbolic link doesn’t expose a lower layer.
+///
+/// Paths used by this storage start with the index of the layer they were resolved in, e.g.
+/// `/1/dir/file.txt`, and are accessed in that layer. Directories are merged across layers, so
+/// entries missing from that layer, e.g. index files joined to a directory path, are looked up in
+/// the other layers. Pre-compressed variants like `file.txt.gz` are only taken from the layer
+/// containing the original file however, so that overriding a file in a higher layer doesn’t
+/// expose stale compressed variants from a lower layer.
+#[derive(Debug, Clone)]
+pub struct LayeredStorage {
+    layers: Vec<Arc<dyn Storage>>,
+    root: PathBuf,
+}
+
+impl LayeredStorage {
+    /// Creates a new layered storage, layers are searched in the order given.
+    pub fn new(layers: Vec<Arc<dyn Storage>>) -> Self {
+        Self {
+            layers,
+            root: PathBuf::from("/"),
+        }
+    }
+
+    /// Splits a path of this storage into the layer index and the path relative to the layer
+    /// root.
+    fn split_path<'a>(&self, path: &'a Path) -> Result<(usize, &'a Path), Error> {
+        let rel_path = path
+            .strip_prefix(&self.root)
+            .map_err(|_| ErrorKind::InvalidData)?;
+        let mut components = rel_path.components();
+        let index = components
+            .next()
+            .and_then(|component| component.as_os_str().to_str())
+            .and_then(|index| index.parse().ok())
+            .filter(|index| *index < self.layers.len())
+            .ok_or(ErrorKind::InvalidData)?;
+        Ok((index, components.as_path()))
+    }
+
+    /// Finds the layer containing the given path: the layer it was resolved in, otherwise the
+    /// first layer containing it.
+    async fn locate(&self, path: &Path) -> Result<(&dyn Storage, PathBuf, EntryStat), Error> {
+        let (index, rel_path) = self.split_path(path)?;
+        let layer = self.layers[index].as_ref();
+        let layer_path = layer.root().join(rel_path);
+        match layer.stat(&layer_path).await {
+            Ok(stat) => return Ok((layer, layer_path, stat)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let original = rel_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(CompressionAlgorithm::from_ext)
+            .map(|_| rel_path.with_extension(""));
+
+        let mut result = Err(ErrorKind::NotFound.into());
+        let others = (0..self.layers.len()).filter(|i| *i != index);
+        for i in std::iter::once(index).chain(others) {
+            let layer = self.layers[i].as_ref();
+            let layer_path = layer.root().join(rel_path);
+            if let Some(original) = &original {
+                // Pre-compressed variant has to come from the layer of the original file
+                if layer.stat(&layer.root().join(original)).await.is_ok() {
+                    if i == index {
+                        return Err(ErrorKind::NotFound.into());
+                    }
+                    return layer
+                        .stat(&layer_path)
+                        .await
+                        .map(|stat| (layer, layer_path, stat));
+                }
+            }
+            if i == index {
+                continue;
+            }
+
+            match layer.stat(&layer_path).await {
+                Ok(stat) => {
+                    if result.is_err() {
+                        result = Ok((layer, layer_path, stat));
+                    }
+                    if original.is_none() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for LayeredStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    async fn request_view(&self, session: &Session) -> Result<Option<Arc<dyn Storage>>, Error> {
+        let mut changed = false;
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            if let Some(view) = layer.request_view(session).await? {
+                changed = true;
+                layers.push(view);
+            } else {
+                layers.push(layer.clone());
+            }
+        }
+
+        Ok(if changed {
+            Some(Arc::new(Self::new(layers)))
+        } else {
+            None
+        })
+    }
+
+    async fn resolve(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        for (index, layer) in self.layers.iter().enumerate() {
+            match layer.resolve(uri_path).await {
+                Ok(path) => {
+                    let rel_path = path
+                        .strip_prefix(layer.root())
+                        .map_err(|_| ErrorKind::InvalidData)?;
+                    return Ok(self.root.join(index.to_string()).join(rel_path));
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(ErrorKind::NotFound.into())
+    }
+
+    async fn stat(&self, path: &Path) -> Result<EntryStat, Error> {
+        Ok(self.locate(path).await?.2)
+    }
+
+    async fn open(&self, path: &Path) -> Result<OpenEntry, Error> {
+        let (layer, layer_path, _) = self.locate(path).await?;
+        let mut entry = layer.open(&layer_path).await?;
+
+        // Without a file handle, contents will be read via this storage
+        entry.path = path.to_path_buf();
+        Ok(entry)
+    }
+
+    async fn open_range(&self, path: &Path, start: u64, end: u64) -> Result<RangeReader, Error> {
+        let (layer, layer_path, _) = self.locate(path).await?;
+        layer.open_range(&layer_path, start, end).await
+    }
+
+    async fn open_range_if_match(
+        &self,
+        path: &Path,
+        etag: Option<&str>,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeReader, Error> {
+        let (layer, layer_path, _) = self.locate(path).await?;
+        layer
+            .open_range_if_match(&layer_path, etag, start, end)
+            .await
+    }
+
+    async fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        let (_, rel_path) = self.split_path(path)?;
+        let mut found = false;
+        let mut result = Vec::new();
+        for layer in &self.layers {
+            let layer_path = layer.root().join(rel_path);
+            match layer.list_dir(&layer_path).await {
+                Ok(entries) => {
+                    found = true;
+                    result.extend(entries);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if !found {
+            return Err(ErrorKind::NotFound.into());
+        }
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    fn relative_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        self.split_path(path).ok().map(|(_, rel_path)| rel_path)
+    }
+
+    fn layers(&self) -> &[Arc<dyn Storage>] {
+        &self.layers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn local_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("root/dir")).unwrap();
+        fs::write(dir.path().join("root/dir/file.txt"), "0123456789").unwrap();
+        fs::write(dir.path().join("root/a b.txt"), "").unwrap();
+        fs::write(dir.path().join("outside.txt"), "outside").unwrap();
+
+        let storage = LocalStorage::new(dir.path().join("root")).unwrap();
+        let root = storage.root().to_path_buf();
+        assert_eq!(root, dir.path().join("root").canonicalize().unwrap());
+
+        let path = storage.resolve("/dir/file.txt").await.unwrap();
+        assert_eq!(path, root.join("dir/file.txt"));
+        assert_eq!(
+            storage.resolve("/dir/../dir/file%2Etxt").await.unwrap(),
+            path
+        );
+        assert_eq!(storage.resolve("/").await.unwrap(), root);
+
+        let stat = storage.stat(&path).await.unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 10);
+        assert!(stat.modified.is_some());
+        assert_eq!(
+            storage.stat(&root.join("dir")).await.unwrap().kind,
+            EntryKind::Directory
+        );
+        assert_eq!(
+            storage
+                .stat(&root.join("missing"))
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+
+        let read = |start, end| {
+            let storage = storage.clone();
+            let path = path.clone();
+            async move {
+                let mut data = String::new();
+                storage
+                    .open_range(&path, start, end)
+                    .await?
+                    .read_to_string(&mut data)
+                    .await?;
+                Ok::<_, Error>(data)
+            }
+        };
+        assert_eq!(read(0, 9).await.unwrap(), "0123456789");
+        assert_eq!(read(2, 5).await.unwrap(), "2345");
+        assert_eq!(read(9, 9).await.unwrap(), "9");
+        assert_eq!(
+            read(5, 2).await.unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        assert_eq!(storage.path_to_uri(&path, false).unwrap(), "/dir/file.txt");
+        assert_eq!(
+            storage.path_to_uri(&root.join("dir"), true).unwrap(),
+            "/dir/"
+        );
+        assert_eq!(storage.path_to_uri(&root, true).unwrap(), "/");
+        assert_eq!(
+            storage.path_to_uri(&root.join("a b.txt"), false).unwrap(),
+            "/a%20b.txt"
+        );
+
+        // Paths outside the root
+        assert_eq!(
+            storage.resolve("/../outside.txt").await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            storage.resolve("dir/file.txt").await.unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            storage.resolve("/missing").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        let outside = dir.path().canonicalize().unwrap().join("outside.txt");
+        assert_eq!(storage.path_to_uri(&outside, false), None);
+        #[cfg(target_os = "linux")]
+        if storage.root_dir.is_some() {
+            assert_eq!(
+                storage.stat(&outside).await.unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+            assert_eq!(
+                storage
+                    .open_range(&outside, 0, 6)
+                    .await
+                    .err()
+                    .unwrap()
+                    .kind(),
+                ErrorKind::InvalidData
+            );
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("../outside.txt", root.join("link.txt")).unwrap();
+            assert_eq!(
+                storage.resolve("/link.txt").await.unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+            std::os::unix::fs::symlink(&outside, root.join("abs_link.txt")).unwrap();
+            assert_eq!(
+                storage.resolve("/abs_link.txt").await.unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+
+            // Absolute links within the root are fine, also for index files and pre-compressed
+            // variants which don't go through resolve()
+            std::os::unix::fs::symlink(&path, root.join("dir/index.txt")).unwrap();
+            let link_path = root.join("dir/index.txt");
+            assert_eq!(storage.resolve("/dir/index.txt").await.unwrap(), path);
+            assert_eq!(storage.stat(&link_path).await.unwrap().size, 10);
+            let mut entry = storage.open(&link_path).await.unwrap();
+            assert_eq!(entry.stat.size, 10);
+            assert_eq!(
+                entry.read_head(&storage, 4).await.unwrap(),
+                b"0123".to_vec()
+            );
+            let mut data = String::new();
+            storage
+                .open_range(&link_path, 2, 5)
+                .await
+                .unwrap()
+                .read_to_string(&mut data)
+                .await
+                .unwrap();
+            assert_eq!(data, "2345");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn layering() {
+        let base = tempfile::tempdir().unwrap();
+        fs::create_dir(base.path().join("dir")).unwrap();
+        fs::write(base.path().join("dir/index.html"), "base index").unwrap();
+        fs::write(base.path().join("style.css"), "base style").unwrap();
+        fs::write(base.path().join("style.css.gz"), "base style compressed").unwrap();
+        fs::write(base.path().join("logo.png"), "base logo").unwrap();
+
+        let custom = tempfile::tempdir().unwrap();
+        fs::create_dir(custom.path().join("dir")).unwrap();
+        fs::write(custom.path().join("dir/extra.txt"), "custom extra").unwrap();
+        fs::write(custom.path().join("style.css"), "custom style").unwrap();
+
+        let storage = LayeredStorage::new(vec![
+            Arc::new(LocalStorage::new(custom.path()).unwrap()),
+            Arc::new(LocalStorage::new(base.path()).unwrap()),
+        ]);
+
+        let path = storage.resolve("/style.css").await.unwrap();
+        assert_eq!(path, PathBuf::from("/0/style.css"));
+        assert_eq!(storage.stat(&path).await.unwrap().size, 12);
+        assert_eq!(storage.path_to_uri(&path, false).unwrap(), "/style.css");
+        assert_eq!(
+            storage.relative_path(&path).unwrap(),
+            Path::new("style.css")
+        );
+
+        // Compressed variant from the base layer is stale and shouldn’t be used
+        assert!(!storage.is_file(&path.with_extension("css.gz")).await);
+
+        // Unless it is requested explicitly
+        let path = storage.resolve("/style.css.gz").await.unwrap();
+        assert_eq!(path, PathBuf::from("/1/style.css.gz"));
+        assert_eq!(storage.stat(&path).await.unwrap().size, 21);
+
+        let path = storage.resolve("/logo.png").await.unwrap();
+        assert_eq!(path, PathBuf::from("/1/logo.png"));
+        assert_eq!(storage.stat(&path).await.unwrap().size, 9);
+
+        let path = storage.resolve("/dir").await.unwrap();
+        assert_eq!(storage.path_to_uri(&path, true).unwrap(), "/dir/");
+        // Index file comes from the other layer, directories are merged
+        assert!(storage.is_file(&path.join("index.html")).await);
+        assert_eq!(
+            storage.list_dir(&path).await.unwrap(),
+            vec![OsString::from("extra.txt"), OsString::from("index.html")]
+        );
+
+        assert_eq!(
+            storage.resolve("/missing").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            storage.stat(Path::new("/2/logo.png")).await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[cfg(unix)]
+    #[test(tokio::test)]
+    async fn layer_identity() {
+        use std::os::unix::fs::symlink;
+
+        let base = tempfile::tempdir().unwrap();
+        fs::create_dir(base.path().join("real")).unwrap();
+        fs::write(base.path().join("real/file.txt"), "base").unwrap();
+        symlink("real", base.path().join("link")).unwrap();
+        fs::create_dir(base.path().join("shared")).unwrap();
+        fs::write(base.path().join("shared/file.txt"), "base shared").unwrap();
+
+        let custom = tempfile::tempdir().unwrap();
+        fs::create_dir(custom.path().join("real")).unwrap();
+        fs::write(custom.path().join("real/file.txt"), "custom").unwrap();
+        fs::create_dir(custom.path().join("private")).unwrap();
+        fs::write(custom.path().join("private/file.txt"), "secret").unwrap();
+        symlink("private", custom.path().join("shared")).unwrap();
+
+        let storage = LayeredStorage::new(vec![
+            Arc::new(
+                LocalStorage::new(custom.path())
+                    .unwrap()
+                    .with_symlink_policy(SymlinkPolicy::Deny),
+            ),
+            Arc::new(LocalStorage::new(base.path()).unwrap()),
+        ]);
+
+        // Resolved via a link in the base layer, the entry is still taken from that layer
+        let path = storage.resolve("/link/file.txt").await.unwrap();
+        assert_eq!(path, PathBuf::from("/1/real/file.txt"));
+        assert_eq!(storage.stat(&path).await.unwrap().size, 4);
+        let mut data = String::new();
+        storage
+            .open_range(&path, 0, 3)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "base");
+
+        // Link denied in the custom layer doesn’t fall through to the base layer
+        assert_eq!(
+            storage.resolve("/shared/file.txt").await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[cfg(unix)]
+    #[test(tokio::test)]
+    async fn symlinked_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("release1")).unwrap();
+        fs::write(dir.path().join("release1/file.txt"), "first").unwrap();
+        fs::create_dir(dir.path().join("release2")).unwrap();
+        fs::write(dir.path().join("release2/file.txt"), "second!").unwrap();
+        symlink("release1", dir.path().join("current")).unwrap();
+
+        let storage = SymlinkRootStorage::new(dir.path().join("current"), Duration::ZERO).unwrap();
+        let old_view = storage.current();
+        let path = old_view.resolve("/file.txt").await.unwrap();
+        assert_eq!(old_view.stat(&path).await.unwrap().size, 5);
+
+        fs::remove_file(dir.path().join("current")).unwrap();
+        symlink("release2", dir.path().join("current")).unwrap();
+
+        let new_view = storage.current();
+        let new_path = new_view.resolve("/file.txt").await.unwrap();
+        assert_eq!(new_view.stat(&new_path).await.unwrap().size, 7);
+
+        // View from before the switch keeps serving the old release
+        assert_eq!(old_view.resolve("/file.txt").await.unwrap(), path);
+        assert_eq!(old_view.stat(&path).await.unwrap().size, 5);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test(tokio::test)]
+    async fn swapped_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("root/subdir")).unwrap();
+        fs::write(dir.path().join("root/subdir/file.txt"), "Hi!").unwrap();
+        fs::create_dir(dir.path().join("outside")).unwrap();
+        fs::write(dir.path().join("outside/file.txt"), "Secret").unwrap();
+
+        let storage = LocalStorage::new(dir.path().join("root")).unwrap();
+        if storage.root_dir.is_none() {
+            // openat2() unavailable in this environment
+            return;
+        }
+
+        let path = storage.resolve("/subdir/file.txt").await.unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap().size, 3);
+
+        // Directory replaced by a link pointing outside the root after the path was resolved
+        fs::remove_dir_all(dir.path().join("root/subdir")).unwrap();
+        symlink("../outside", dir.path().join("root/subdir")).unwrap();
+
+        assert_eq!(
+            storage.stat(&path).await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            storage.open_range(&path, 0, 2).await.err().unwrap().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn open_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "Hi there!").unwrap();
+
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let path = storage.resolve("/file.txt").await.unwrap();
+        let mut entry = storage.open(&path).await.unwrap();
+        assert_eq!(entry.stat.size, 9);
+        assert_eq!(entry.read_head(&storage, 2).await.unwrap(), b"Hi");
+
+        // File replaced after opening, the handle still refers to the original contents
+        fs::write(dir.path().join("new.txt"), "Bye").unwrap();
+        fs::rename(dir.path().join("new.txt"), dir.path().join("file.txt")).unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap().size, 3);
+
+        let mut data = String::new();
+        entry
+            .read_range(&storage, 0, 8)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "Hi there!");
+    }
+
+    #[cfg(unix)]
+    #[test(tokio::test)]
+    async fn symlink_policies() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("shared")).unwrap();
+        fs::create_dir_all(root.join("private")).unwrap();
+        fs::write(root.join("shared/file.txt"), "Hi!").unwrap();
+        fs::write(root.join("private/file.txt"), "Secret").unwrap();
+        symlink("shared", root.join("public")).unwrap();
+        symlink("private", root.join("other")).unwrap();
+        fs::write(root.join("index.html"), "Index").unwrap();
+        symlink("index.html", root.join("default.html")).unwrap();
+
+        let storage = LocalStorage::new(&root).unwrap();
+        assert!(storage.resolve("/public/file.txt").await.is_ok());
+
+        let storage = LocalStorage::new(&root)
+            .unwrap()
+            .with_symlink_policy(SymlinkPolicy::Deny);
+        assert!(storage.resolve("/shared/file.txt").await.is_ok());
+        assert_eq!(
+            storage.resolve("/public/file.txt").await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        // Links not going through resolve() are checked when opening
+        let path = storage.resolve("/").await.unwrap().join("default.html");
+        assert_eq!(
+            storage.open(&path).await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        let storage = LocalStorage::new(&root)
+            .unwrap()
+            .with_symlink_policy(SymlinkPolicy::AllowTargets(vec![root.join("shared")]));
+        assert!(storage.resolve("/public/file.txt").await.is_ok());
+        assert_eq!(
+            storage.resolve("/other/file.txt").await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        // All files created by the same user
+        let storage = LocalStorage::new(&root)
+            .unwrap()
+            .with_symlink_policy(SymlinkPolicy::OwnerMatch);
+        assert!(storage.resolve("/other/file.txt").await.is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test(tokio::test)]
+    async fn swapped_denied_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("public")).unwrap();
+        fs::create_dir_all(root.join("private")).unwrap();
+        fs::write(root.join("public/file.txt"), "Hi!").unwrap();
+        fs::write(root.join("private/file.txt"), "Secret").unwrap();
+
+        let storage = LocalStorage::new(&root)
+            .unwrap()
+            .with_symlink_policy(SymlinkPolicy::Deny);
+        if storage.root_dir.is_none() {
+            // openat2() unavailable in this environment
+            return;
+        }
+
+        let path = storage.resolve("/public/file.txt").await.unwrap();
+        assert_eq!(storage.stat(&path).await.unwrap().size, 3);
+
+        // Directory replaced by a link within the root after the path was resolved
+        fs::remove_dir_all(root.join("public")).unwrap();
+        symlink("private", root.join("public")).unwrap();
+
+        assert_eq!(
+            storage.stat(&path).await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            storage.open(&path).await.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+}
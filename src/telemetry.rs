@@ -0,0 +1,224 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing spans for the stages of request processing
+//!
+//! With the `otel` feature, spans are created via the `tracing` crate and can be exported to an
+//! OpenTelemetry collector, see [`init_otlp`]. The parent trace is taken from the W3C
+//! `traceparent` request header. Without the feature, all operations are no-ops.
+
+use pingora::proxy::Session;
+use std::fmt::Display;
+use std::future::Future;
+
+/// A span for the request or one of its processing stages
+#[derive(Debug, Clone)]
+pub(crate) struct Stage {
+    #[cfg(feature = "otel")]
+    span: tracing::Span,
+}
+
+#[cfg(feature = "otel")]
+impl Stage {
+    /// Creates a span for the request, continuing the trace from the `traceparent` header if
+    /// present.
+    pub(crate) fn request(session: &Session) -> Self {
+        let req = session.req_header();
+        Self::request_from_headers(req.method.as_str(), req.uri.path(), &req.headers)
+    }
+
+    fn request_from_headers(method: &str, uri_path: &str, headers: &http::HeaderMap) -> Self {
+        use opentelemetry::propagation::Extractor;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+        impl Extractor for HeaderExtractor<'_> {
+            fn get(&self, key: &str) -> Option<&str> {
+                self.0.get(key).and_then(|value| value.to_str().ok())
+            }
+
+            fn keys(&self) -> Vec<&str> {
+                self.0.keys().map(|key| key.as_str()).collect()
+            }
+        }
+
+        let span = tracing::info_span!(
+            "static_files.request",
+            http.request.method = method,
+            url.path = uri_path,
+            path = tracing::field::Empty,
+            size = tracing::field::Empty,
+            encoding = tracing::field::Empty,
+            http.response.status_code = tracing::field::Empty,
+        );
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+        Self { span }
+    }
+
+    /// Creates a span for a processing stage. It will be a child of the span the current future
+    /// is running in.
+    pub(crate) fn new(name: &'static str) -> Self {
+        let span = tracing::info_span!(
+            "static_files.stage",
+            otel.name = name,
+            path = tracing::field::Empty,
+            size = tracing::field::Empty,
+            encoding = tracing::field::Empty,
+        );
+        Self { span }
+    }
+
+    /// Records an attribute: `path`, `size`, `encoding` (also
+    /// `http.response.status_code` for the request span).
+    pub(crate) fn record(&self, field: &'static str, value: impl Display) {
+        self.span.record(field, tracing::field::display(value));
+    }
+
+    /// Runs a future within the span.
+    pub(crate) async fn run<F: Future>(&self, future: F) -> F::Output {
+        use tracing::Instrument;
+
+        future.instrument(self.span.clone()).await
+    }
+
+    /// Runs a function within the span.
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.span.in_scope(f)
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl Stage {
+    pub(crate) fn request(_session: &Session) -> Self {
+        Self {}
+    }
+
+    pub(crate) fn new(_name: &'static str) -> Self {
+        Self {}
+    }
+
+    pub(crate) fn record(&self, _field: &'static str, _value: impl Display) {}
+
+    pub(crate) async fn run<F: Future>(&self, future: F) -> F::Output {
+        future.await
+    }
+
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+}
+
+/// Sets up export of the handler’s spans to an OpenTelemetry collector via OTLP/gRPC, e.g.
+/// `http://localhost:4317`. This installs a global `tracing` subscriber and the W3C trace
+/// context propagator. It has to be called from within a Tokio runtime.
+///
+/// The returned provider should be shut down before the application exits to flush spans.
+#[cfg(feature = "otel")]
+pub fn init_otlp(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<opentelemetry_sdk::trace::TracerProvider, Box<dyn std::error::Error + Send + Sync>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", service_name.to_owned()),
+        ]))
+        .build();
+
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+    let tracer = provider.tracer("static-files");
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(provider)
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+
+    use opentelemetry::trace::{TraceId, TracerProvider as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use test_log::test;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test(tokio::test)]
+    async fn spans() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        // In-process collector
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut headers = http::HeaderMap::new();
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        headers.insert("traceparent", traceparent.parse().unwrap());
+        let request = Stage::request_from_headers("GET", "/file.txt", &headers);
+        request
+            .run(async {
+                {
+                    let stage = Stage::new("metadata");
+                    stage
+                        .run(async {
+                            stage.record("path", "/var/www/file.txt");
+                            stage.record("size", 1234);
+                        })
+                        .await;
+                }
+                Stage::new("conditional").in_scope(|| ());
+            })
+            .await;
+        request.record("http.response.status_code", 200);
+        drop(request);
+
+        provider.force_flush();
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<_> = spans.iter().map(|span| span.name.to_string()).collect();
+        assert_eq!(names, ["metadata", "conditional", "static_files.request"]);
+
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        assert!(spans
+            .iter()
+            .all(|span| span.span_context.trace_id() == trace_id));
+
+        let metadata = &spans[0];
+        assert_eq!(metadata.parent_span_id, spans[2].span_context.span_id());
+        assert!(metadata
+            .attributes
+            .iter()
+            .any(|attr| attr.key.as_str() == "size" && attr.value.as_str() == "1234"));
+    }
+}
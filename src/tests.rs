@@ -12,29 +12,136 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::configuration::StaticFilesConf;
 use crate::handler::StaticFilesHandler;
 use crate::metadata::Metadata;
+use crate::request_filter::{RequestFilter, RequestFilterResult};
+use crate::standard_response::response_text;
 
-use compression_module::CompressionHandler;
 use const_format::{concatcp, str_repeat};
 use http::status::StatusCode;
-use pandora_module_utils::pingora::{
-    create_test_session, ErrorType, RequestHeader, Session, SessionWrapper,
-};
-use pandora_module_utils::standard_response::response_text;
-use pandora_module_utils::{FromYaml, RequestFilter};
-use rewrite_module::RewriteHandler;
-use startup_module::{AppResult, DefaultApp};
+use pingora::http::RequestHeader;
+use pingora::modules::http::compression::{ResponseCompression, ResponseCompressionBuilder};
+use pingora::modules::http::HttpModules;
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType};
+use std::collections::BTreeMap;
+use std::io::Cursor;
 use std::path::PathBuf;
 use test_log::test;
 
-#[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
-struct Handler {
-    compression: CompressionHandler,
-    rewrite: RewriteHandler,
-    static_files: StaticFilesHandler,
+/// Runs requests through the handler the way Pingora would, with dynamic compression available.
+struct App {
+    handler: StaticFilesHandler,
+    compression_level: u32,
 }
 
+impl App {
+    fn new(handler: StaticFilesHandler) -> Self {
+        Self {
+            handler,
+            compression_level: 0,
+        }
+    }
+
+    async fn handle_request(&mut self, mut session: Session) -> AppResult {
+        let compression = session
+            .downstream_modules_ctx
+            .get_mut::<ResponseCompression>()
+            .unwrap();
+        compression.adjust_level(self.compression_level);
+        let mut header = session.req_header().clone();
+        session
+            .downstream_modules_ctx
+            .request_header_filter(&mut header)
+            .await
+            .unwrap();
+
+        let mut ctx = StaticFilesHandler::new_ctx();
+        let mut result = self
+            .handler
+            .early_request_filter(&mut session, &mut ctx)
+            .await;
+        if result.is_ok() {
+            result = match self.handler.request_filter(&mut session, &mut ctx).await {
+                Ok(RequestFilterResult::ResponseSent) => Ok(()),
+                Ok(_) => Err(Error::new(ErrorType::HTTPStatus(404))),
+                Err(err) => Err(err),
+            };
+        }
+        self.handler
+            .logging(
+                &mut session,
+                result.as_ref().err().map(|err| &**err),
+                &mut ctx,
+            )
+            .await;
+
+        let body = response_body(&session);
+        AppResult {
+            session,
+            err: result.err(),
+            body,
+        }
+    }
+}
+
+struct AppResult {
+    session: Session,
+    err: Option<Box<Error>>,
+    body: String,
+}
+
+impl AppResult {
+    fn session(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    fn err(&self) -> &Option<Box<Error>> {
+        &self.err
+    }
+
+    fn body_str(&self) -> &str {
+        &self.body
+    }
+}
+
+/// Extracts the response body from the data written to the session's stream.
+fn response_body(session: &Session) -> String {
+    let Some(stream) = session.as_downstream().stream() else {
+        return String::new();
+    };
+    let cursor = stream.as_any().downcast_ref::<Cursor<Vec<u8>>>().unwrap();
+    let written = &cursor.get_ref()[REQUEST.len()..];
+    let Some(start) = written.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return String::new();
+    };
+    let mut body = &written[start + 4..];
+
+    let chunked = session
+        .response_written()
+        .and_then(|header| header.headers.get("Transfer-Encoding"))
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"chunked"));
+    if !chunked {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+
+    let mut data = Vec::new();
+    while let Some(end) = body.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&body[..end]).unwrap();
+        let size = usize::from_str_radix(size.trim(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        data.extend_from_slice(&body[end + 2..end + 2 + size]);
+        body = &body[end + 2 + size + 2..];
+    }
+    String::from_utf8_lossy(&data).into_owned()
+}
+
+/// Placeholder request read by the session, the actual request header is set afterwards.
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+
 fn root_path(filename: &str) -> PathBuf {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("testdata");
@@ -56,19 +163,91 @@ fn extended_conf(conf_str: impl AsRef<str>) -> String {
     format!("{}\n{}", default_conf(), conf_str.as_ref())
 }
 
-fn make_app(conf_str: impl AsRef<str>) -> DefaultApp<Handler> {
-    DefaultApp::new(
-        <Handler as RequestFilter>::Conf::from_yaml(conf_str)
-            .unwrap()
-            .try_into()
-            .unwrap(),
-    )
+/// Parses the YAML configuration, returning the dynamic compression level separately.
+fn parse_conf(conf_str: impl AsRef<str>) -> (StaticFilesConf, u32) {
+    macro_rules! set_fields {
+        ($conf:ident, $name:ident, $value:ident, $($field:ident),*) => {
+            match $name.as_str() {
+                $(stringify!($field) => $conf.$field = serde_yaml::from_value($value).unwrap(),)*
+                _ => panic!("unknown setting {}", $name),
+            }
+        };
+    }
+
+    let mut conf = StaticFilesConf::default();
+    let mut compression_level = 0;
+    let values: BTreeMap<String, serde_yaml::Value> =
+        serde_yaml::from_str(conf_str.as_ref()).unwrap();
+    for (name, value) in values {
+        if value.is_null() {
+            continue;
+        }
+        if name == "compression_level_gzip" {
+            compression_level = serde_yaml::from_value(value).unwrap();
+            continue;
+        }
+        set_fields!(
+            conf,
+            name,
+            value,
+            root,
+            canonicalize_uri,
+            index_file,
+            page_404,
+            precompressed,
+            declare_charset,
+            declare_charset_types,
+            git_ref,
+            git_ref_header,
+            git_ref_domain,
+            root_refresh_ms,
+            symlinks,
+            hidden,
+            deny,
+            try_files,
+            redirects,
+            netlify_redirects,
+            directory_overrides,
+            mime_types,
+            mime_types_file,
+            default_type,
+            sniff_content,
+            content_disposition,
+            download_query,
+            metrics_path,
+            access_log,
+            access_log_format,
+            basic_auth,
+            jwt_auth,
+            signed_urls,
+            ip_access,
+            trusted_proxies,
+            forwarded_header
+        );
+    }
+    (conf, compression_level)
 }
 
-async fn make_session(method: &str, path: &str) -> Session {
-    let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+fn make_handler(conf_str: impl AsRef<str>) -> StaticFilesHandler {
+    parse_conf(conf_str).0.try_into().unwrap()
+}
+
+fn make_app(conf_str: impl AsRef<str>) -> App {
+    let (conf, compression_level) = parse_conf(conf_str);
+    App {
+        handler: conf.try_into().unwrap(),
+        compression_level,
+    }
+}
 
-    create_test_session(header).await
+async fn make_session(method: &str, path: &str) -> Session {
+    let mut modules = HttpModules::new();
+    modules.add_module(ResponseCompressionBuilder::enable(0));
+    let mut session =
+        Session::new_h1_with_modules(Box::new(Cursor::new(REQUEST.to_vec())), &modules);
+    assert!(session.read_request().await.unwrap());
+    *session.req_header_mut() = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+    session
 }
 
 fn assert_status(result: &mut AppResult, expected: u16) {
@@ -108,6 +287,15 @@ fn assert_body(result: &AppResult, expected: &str) {
     assert_eq!(result.body_str(), expected);
 }
 
+fn response_header(result: &mut AppResult, name: &str) -> Option<String> {
+    result
+        .session()
+        .response_written()?
+        .headers
+        .get(name)
+        .map(|value| value.to_str().unwrap().to_owned())
+}
+
 #[test(tokio::test)]
 async fn unconfigured() {
     let mut app = make_app("root:");
@@ -139,6 +327,7 @@ async fn text_file() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -156,6 +345,7 @@ async fn text_file() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, concatcp!(str_repeat!("0123456789", 10000), "\n"));
@@ -178,6 +368,7 @@ async fn dir_index() {
             ("Content-Type", "text/html;charset=utf-8"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "<html>Hi!</html>\n");
@@ -219,25 +410,6 @@ async fn no_trailing_slash() {
     );
     assert_body(&result, &text);
 
-    // Scenario where prefix has been stripped from URI
-    let mut app = make_app(extended_conf(
-        "rewrite_rules: {from: /static/*, to: '${tail}${query}'}",
-    ));
-
-    let session = make_session("GET", "/static/subdir?xyz").await;
-    let mut result = app.handle_request(session).await;
-    assert!(result.err().is_none());
-    assert_status(&mut result, 308);
-    assert_headers(
-        &mut result,
-        vec![
-            ("Content-Length", &text.len().to_string()),
-            ("Content-Type", "text/html;charset=utf-8"),
-            ("location", "/static/subdir/?xyz"),
-        ],
-    );
-    assert_body(&result, &text);
-
     // Without canonicalize_uri this should just produce the response
     // (Forbidden because no index file).
     let mut app = make_app(extended_conf("canonicalize_uri: false"));
@@ -276,24 +448,6 @@ async fn unnecessary_percent_encoding() {
     );
     assert_body(&result, &text);
 
-    // Scenario where prefix has been stripped from URI
-    let mut app = make_app(extended_conf(
-        "rewrite_rules: {from: /static/*, to: '${tail}'}",
-    ));
-
-    let session = make_session("GET", "/static/file%2Etxt").await;
-    let mut result = app.handle_request(session).await;
-    assert!(result.err().is_none());
-    assert_status(&mut result, 308);
-    assert_headers(
-        &mut result,
-        vec![
-            ("Content-Length", &text.len().to_string()),
-            ("Content-Type", "text/html;charset=utf-8"),
-            ("location", "/static/file.txt"),
-        ],
-    );
-    assert_body(&result, &text);
 }
 
 #[test(tokio::test)]
@@ -337,6 +491,7 @@ async fn utf8_path() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -379,6 +534,7 @@ async fn no_file_with_page_404() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -458,6 +614,7 @@ async fn head_request() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "");
@@ -492,12 +649,32 @@ async fn head_request() {
     assert_body(&result, "");
 }
 
+#[test(tokio::test)]
+async fn file_trailing_slash() {
+    let mut app = make_app(default_conf());
+    let text = response_text(StatusCode::PERMANENT_REDIRECT);
+
+    let session = make_session("GET", "/file.txt/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &text.len().to_string()),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("location", "/file.txt"),
+        ],
+    );
+    assert_body(&result, &text);
+}
+
 #[test(tokio::test)]
 async fn bad_request() {
     let mut app = make_app(default_conf());
     let text = response_text(StatusCode::BAD_REQUEST);
 
-    let session = make_session("GET", ".").await;
+    let session = make_session("GET", "*").await;
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
     assert_status(&mut result, 400);
@@ -617,6 +794,7 @@ async fn if_none_match() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -663,6 +841,7 @@ async fn if_match() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -683,6 +862,7 @@ async fn if_match() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -703,6 +883,7 @@ async fn if_match() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -727,6 +908,7 @@ async fn if_match() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -807,6 +989,7 @@ async fn if_modified_since() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -831,6 +1014,7 @@ async fn if_modified_since() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -877,6 +1061,7 @@ async fn if_unmodified_since() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "Hi!\n");
@@ -965,6 +1150,7 @@ async fn ranged_request() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "2345");
@@ -985,6 +1171,7 @@ async fn ranged_request() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "9\n");
@@ -1005,6 +1192,7 @@ async fn ranged_request() {
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
     assert_body(&result, "6789\n");
@@ -1071,9 +1259,10 @@ async fn dynamic_compression() {
             ("Content-Encoding", "gzip"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
-            ("etag", &meta.etag),
+            ("etag", &format!("W/{}", meta.etag)),
             ("Transfer-Encoding", "chunked"),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1095,6 +1284,7 @@ async fn dynamic_compression() {
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1118,9 +1308,10 @@ async fn dynamic_compression() {
             ("content-range", "bytes 0-10000/100001"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
-            ("etag", &meta.etag),
+            ("etag", &format!("W/{}", meta.etag)),
             ("Transfer-Encoding", "chunked"),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 }
@@ -1153,6 +1344,7 @@ async fn static_compression() {
             ("etag", &meta_compressed.etag),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1177,6 +1369,7 @@ async fn static_compression() {
             ("etag", &meta_compressed.etag),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1200,6 +1393,7 @@ async fn static_compression() {
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1231,6 +1425,7 @@ async fn static_compression() {
             ("etag", &meta_compressed.etag),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 }
@@ -1255,6 +1450,7 @@ async fn charset() {
             ("Content-Type", "application/gzip"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1276,6 +1472,7 @@ async fn charset() {
             ("Content-Type", "application/gzip;charset=windows-1251"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
         ],
     );
 
@@ -1295,6 +1492,589 @@ async fn charset() {
             ("Content-Type", "application/gzip;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
+        ],
+    );
+}
+
+#[test(tokio::test)]
+async fn content_disposition() {
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
+
+    let mut app = make_app(extended_conf(
+        "canonicalize_uri: false\ncontent_disposition:\n- match: /large.*\n  disposition: attachment",
+    ));
+    let session = make_session("GET", "/subdir/../large.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Disposition", "attachment; filename=\"large.txt\""),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
+        ],
+    );
+
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "4"),
+            ("content-range", "bytes 2-5/100001"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Disposition", "attachment; filename=\"large.txt\""),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("x-content-type-options", "nosniff"),
+        ],
+    );
+    assert_body(&result, "2345");
+}
+
+#[test(tokio::test)]
+async fn sniff_precompressed() {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("page"), "<!DOCTYPE html><p>Hello</p>").unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"<!DOCTYPE html><p>Hello</p>").unwrap();
+    std::fs::write(dir.path().join("page.gz"), encoder.finish().unwrap()).unwrap();
+
+    let mut app = make_app(format!(
+        "root: {}\nsniff_content: true\nprecompressed: [gz]",
+        dir.path().display()
+    ));
+
+    // The compressed variant is served with the type of the original file
+    let mut session = make_session("GET", "/page").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Encoding").as_deref(),
+        Some("gzip")
+    );
+    assert!(response_header(&mut result, "Content-Type")
+        .unwrap()
+        .starts_with("text/html"));
+
+    let session = make_session("GET", "/page").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(response_header(&mut result, "Content-Encoding"), None);
+    assert!(response_header(&mut result, "Content-Type")
+        .unwrap()
+        .starts_with("text/html"));
+}
+
+#[test(tokio::test)]
+async fn reload_redirects() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("_redirects"), "/old /new 301").unwrap();
+
+    let static_files = make_handler(format!(
+        "root: {}\nnetlify_redirects: true",
+        dir.path().display()
+    ));
+    let mut app = App::new(static_files.clone());
+
+    let session = make_session("GET", "/old").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 301);
+    assert_eq!(
+        response_header(&mut result, "Location").as_deref(),
+        Some("/new")
+    );
+
+    // Changed redirects file takes effect after a reload
+    std::fs::write(dir.path().join("_redirects"), "/old /newer 301").unwrap();
+    static_files.reload().unwrap();
+
+    let session = make_session("GET", "/old").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 301);
+    assert_eq!(
+        response_header(&mut result, "Location").as_deref(),
+        Some("/newer")
+    );
+}
+
+/// Creates a root directory with a data file per tenant, the JWKS file is placed next to it.
+fn tenants_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for tenant in ["acme", "other"] {
+        let tenant_dir = dir.path().join("root/tenants").join(tenant);
+        std::fs::create_dir_all(&tenant_dir).unwrap();
+        std::fs::write(tenant_dir.join("data.txt"), tenant).unwrap();
+    }
+    std::fs::write(
+        dir.path().join("jwks.json"),
+        r#"{"keys": [{"kty": "oct", "kid": "current", "k": "c2VjcmV0"}]}"#,
+    )
+    .unwrap();
+    dir
+}
+
+fn tenants_conf(dir: &tempfile::TempDir, conf_str: &str) -> String {
+    format!(
+        "root: {}\njwt_auth:\n- match: /tenants/**\n  keys: {}\n  cookie: token\n  claims:\n    tenant: $2\n{conf_str}",
+        dir.path().join("root").display(),
+        dir.path().join("jwks.json").display(),
+    )
+}
+
+fn jwt_token(tenant: &str, secret: &[u8]) -> String {
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+    let exp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 60;
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some("current".to_owned());
+    let claims = serde_json::json!({"sub": "alice", "tenant": tenant, "exp": exp});
+    encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+}
+
+async fn make_session_with(path: &str, headers: &[(&str, &str)]) -> Session {
+    let mut session = make_session("GET", path).await;
+    for (name, value) in headers {
+        session
+            .req_header_mut()
+            .insert_header(name.to_string(), *value)
+            .unwrap();
+    }
+    session
+}
+
+#[test(tokio::test)]
+async fn jwt_auth() {
+    let dir = tenants_dir();
+    let mut app = make_app(tenants_conf(&dir, ""));
+    let acme = format!("Bearer {}", jwt_token("acme", b"secret"));
+
+    let session = make_session("GET", "/tenants/acme/data.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+    assert_eq!(
+        response_header(&mut result, "WWW-Authenticate").as_deref(),
+        Some("Bearer")
+    );
+
+    let forged = format!("Bearer {}", jwt_token("acme", b"forged"));
+    let session = make_session_with("/tenants/acme/data.txt", &[("Authorization", &forged)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+    assert_eq!(
+        response_header(&mut result, "WWW-Authenticate").as_deref(),
+        Some("Bearer error=\"invalid_token\"")
+    );
+
+    let session = make_session_with("/tenants/acme/data.txt", &[("Authorization", &acme)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "acme");
+
+    // Valid token with claims not matching the path is forbidden rather than unauthorized
+    let session = make_session_with("/tenants/other/data.txt", &[("Authorization", &acme)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+    assert_eq!(response_header(&mut result, "WWW-Authenticate"), None);
+}
+
+#[test(tokio::test)]
+async fn jwt_auth_cookie() {
+    let dir = tenants_dir();
+    let mut app = make_app(tenants_conf(&dir, ""));
+    let cookie = format!("theme=dark; token={}", jwt_token("acme", b"secret"));
+
+    let session = make_session_with("/tenants/acme/data.txt", &[("Cookie", &cookie)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "acme");
+
+    let session = make_session_with("/tenants/other/data.txt", &[("Cookie", &cookie)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    // Only the configured cookie is considered
+    let cookie = format!("session={}", jwt_token("acme", b"secret"));
+    let session = make_session_with("/tenants/acme/data.txt", &[("Cookie", &cookie)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+}
+
+#[test(tokio::test)]
+async fn jwt_auth_try_files() {
+    let dir = tenants_dir();
+    let mut app = make_app(tenants_conf(
+        &dir,
+        "try_files:\n- match: /app/**\n  try: [$uri, /tenants/other/data.txt]",
+    ));
+
+    // The request path isn't protected, the file it resolves to is
+    let session = make_session("GET", "/app/page").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    let acme = format!("Bearer {}", jwt_token("acme", b"secret"));
+    let session = make_session_with("/app/page", &[("Authorization", &acme)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    let other = format!("Bearer {}", jwt_token("other", b"secret"));
+    let session = make_session_with("/app/page", &[("Authorization", &other)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "other");
+}
+
+#[test(tokio::test)]
+async fn jwt_auth_ordering() {
+    let dir = tenants_dir();
+    std::fs::write(dir.path().join("root/tenants/acme/.secret"), "").unwrap();
+    let mut app = make_app(tenants_conf(
+        &dir,
+        "hidden: 404\nredirects:\n- from: /tenants/acme/old.txt\n  to: /tenants/acme/data.txt",
+    ));
+
+    // Access rules apply before authorization
+    let session = make_session("GET", "/tenants/acme/.secret").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+
+    // Redirects only apply after authorization
+    let session = make_session("GET", "/tenants/acme/old.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    let acme = format!("Bearer {}", jwt_token("acme", b"secret"));
+    let session = make_session_with("/tenants/acme/old.txt", &[("Authorization", &acme)]).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 301);
+    assert_eq!(
+        response_header(&mut result, "Location").as_deref(),
+        Some("/tenants/acme/data.txt")
+    );
+}
+
+/// Creates a root directory with an internal document, the htpasswd file is placed next to it.
+fn internal_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("root/internal")).unwrap();
+    std::fs::write(dir.path().join("root/internal/doc.txt"), "internal").unwrap();
+    std::fs::write(
+        dir.path().join("htpasswd"),
+        "apr:$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0\n",
+    )
+    .unwrap();
+    dir
+}
+
+fn internal_conf(dir: &tempfile::TempDir, conf_str: &str) -> String {
+    format!(
+        "root: {}\nbasic_auth:\n- match: /internal/**\n  htpasswd: {}\n  realm: Internal\n{conf_str}",
+        dir.path().join("root").display(),
+        dir.path().join("htpasswd").display(),
+    )
+}
+
+#[test(tokio::test)]
+async fn basic_auth() {
+    let dir = internal_dir();
+    let mut app = make_app(internal_conf(
+        &dir,
+        "redirects:\n- from: /internal/old.txt\n  to: /internal/doc.txt",
+    ));
+
+    let session = make_session("GET", "/internal/doc.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+    assert_eq!(
+        response_header(&mut result, "WWW-Authenticate").as_deref(),
+        Some("Basic realm=\"Internal\", charset=\"UTF-8\"")
+    );
+
+    let wrong = [("Authorization", "Basic YXByOndyb25n")];
+    let session = make_session_with("/internal/doc.txt", &wrong).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    let valid = [("Authorization", "Basic YXByOnNlY3JldA==")];
+    let session = make_session_with("/internal/doc.txt", &valid).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "internal");
+
+    // Paths normalizing to a protected path are protected as well
+    let session = make_session("GET", "/public/../%69nternal/doc.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    // Redirects only apply after authentication
+    let session = make_session("GET", "/internal/old.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    let session = make_session_with("/internal/old.txt", &valid).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 301);
+}
+
+#[test(tokio::test)]
+async fn basic_auth_try_files() {
+    let dir = internal_dir();
+    let mut app = make_app(internal_conf(
+        &dir,
+        "try_files:\n- match: /app/**\n  try: [$uri, /internal/doc.txt]",
+    ));
+
+    // The request path isn't protected, the file it resolves to is
+    let session = make_session("GET", "/app/page").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 401);
+
+    let valid = [("Authorization", "Basic YXByOnNlY3JldA==")];
+    let session = make_session_with("/app/page", &valid).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "internal");
+}
+
+/// Creates a root directory with an export file.
+fn exports_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("exports")).unwrap();
+    std::fs::write(dir.path().join("exports/report.csv"), "a,b").unwrap();
+    dir
+}
+
+fn exports_conf(dir: &tempfile::TempDir, conf_str: &str) -> String {
+    format!(
+        "root: {}\nsigned_urls:\n- match: /exports/**\n  keys: [new, old]\n{conf_str}",
+        dir.path().display(),
+    )
+}
+
+#[test(tokio::test)]
+async fn signed_urls() {
+    use crate::sign_url;
+    use std::time::{Duration, SystemTime};
+
+    let dir = exports_dir();
+    let mut app = make_app(exports_conf(
+        &dir,
+        "redirects:\n- from: /exports/old.csv\n  to: /exports/report.csv",
+    ));
+    let future = SystemTime::now() + Duration::from_secs(60);
+    let past = SystemTime::now() - Duration::from_secs(60);
+
+    let session = make_session("GET", "/exports/report.csv").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    let url = sign_url("/exports/report.csv", "new", future, None);
+    let session = make_session("GET", &url).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "a,b");
+
+    let url = sign_url("/exports/report.csv", "old", future, None);
+    let session = make_session("GET", &format!("{url}&download")).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+
+    let url = sign_url("/exports/report.csv", "new", past, None);
+    let session = make_session("GET", &url).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 410);
+
+    // Signature of a different path doesn't transfer
+    let url = sign_url("/exports/other.csv", "new", future, None);
+    let session = make_session("GET", &url.replace("other.csv", "report.csv")).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    // Redirects only apply to signed requests
+    let session = make_session("GET", "/exports/old.csv").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    let url = sign_url("/exports/old.csv", "new", future, None);
+    let session = make_session("GET", &url).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 301);
+}
+
+#[test(tokio::test)]
+async fn signed_urls_try_files() {
+    use crate::sign_url;
+    use std::time::{Duration, SystemTime};
+
+    let dir = exports_dir();
+    let mut app = make_app(exports_conf(
+        &dir,
+        "try_files:\n- match: /app/**\n  try: [$uri, /exports/report.csv]",
+    ));
+
+    // The request path isn't protected but the file it resolves to is
+    let session = make_session("GET", "/app/page").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+
+    // The signature covers the request path
+    let future = SystemTime::now() + Duration::from_secs(60);
+    let url = sign_url("/app/page", "new", future, None);
+    let session = make_session("GET", &url).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "a,b");
+}
+
+#[test(tokio::test)]
+async fn archive_root() {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("bundle.zip");
+    let mut zip = ZipWriter::new(std::fs::File::create(&archive).unwrap());
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("file.txt", options).unwrap();
+    zip.write_all(b"Hello, archive!").unwrap();
+    zip.finish().unwrap();
+
+    // Deflated entries are only served compressed if gzip is configured
+    for (conf_str, encoding) in [("", None), ("\nprecompressed: [gz]", Some("gzip"))] {
+        let mut app = make_app(format!("root: {}{conf_str}", archive.display()));
+        let mut session = make_session("GET", "/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("Accept-Encoding", "gzip")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(
+            response_header(&mut result, "Content-Encoding").as_deref(),
+            encoding
+        );
+        if encoding.is_none() {
+            assert_body(&result, "Hello, archive!");
+        }
+    }
+}
+
+#[test(tokio::test)]
+async fn embedded_storage() {
+    use crate::embedded::{EmbeddedFile, EmbeddedStorage};
+
+    static FILES: &[EmbeddedFile] = &[EmbeddedFile {
+        path: "/file.txt",
+        data: b"0123456789",
+        modified: 1700000000,
+        hash: "fedcba9876543210",
+    }];
+
+    // Storage replaced below, the configured root is never accessed
+    let handler = make_handler(default_conf()).with_storage(EmbeddedStorage::new(FILES));
+    let mut app = App::new(handler);
+
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "10"),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", "Tue, 14 Nov 2023 22:13:20 GMT"),
+            ("etag", "\"fedcba9876543210\""),
+            ("x-content-type-options", "nosniff"),
         ],
     );
+    assert_body(&result, "0123456789");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_eq!(
+        response_header(&mut result, "Content-Range").as_deref(),
+        Some("bytes 2-5/10")
+    );
+    assert_eq!(
+        response_header(&mut result, "ETag").as_deref(),
+        Some("\"fedcba9876543210\"")
+    );
+    assert_body(&result, "2345");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", "\"fedcba9876543210\"")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
 }
@@ -0,0 +1,178 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! nginx-style `try_files` resolution chains
+
+use glob::PatternError;
+use http::status::StatusCode;
+use log::debug;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::configuration::{TryFilesEntry, TryFilesRule};
+use crate::path_rules::PathRules;
+use crate::storage::{EntryKind, Storage};
+
+/// Outcome of resolving a request path
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Resolved {
+    /// Storage path found. The flag indicates whether this is the request path itself, so that
+    /// canonicalization applies.
+    Path(PathBuf, bool),
+    /// Respond with the given status code
+    Status(StatusCode),
+}
+
+/// `try_files` rules with patterns compiled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TryFilesRules {
+    rules: PathRules<TryFiles>,
+}
+
+impl TryFilesRules {
+    /// Compiles the rules from the configuration.
+    pub(crate) fn new(rules: impl IntoIterator<Item = TryFilesRule>) -> Result<Self, PatternError> {
+        let rules = PathRules::new(rules.into_iter().map(|rule| {
+            let candidates = TryFiles {
+                candidates: rule.candidates.into(),
+            };
+            (rule.pattern, candidates)
+        }))?;
+        Ok(Self { rules })
+    }
+
+    /// Finds the rule applying to a request path, `None` if the path is resolved directly.
+    pub(crate) fn find(&self, uri_path: &str) -> Option<&TryFiles> {
+        self.rules.find(uri_path)
+    }
+}
+
+/// Candidates of a `try_files` rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TryFiles {
+    candidates: Vec<TryFilesEntry>,
+}
+
+impl TryFiles {
+    /// Tries the candidates in order. The last candidate is a fallback: it is used if it exists
+    /// regardless of its type, if it doesn’t the result is a [`ErrorKind::NotFound`] error.
+    pub(crate) async fn resolve(
+        &self,
+        storage: &dyn Storage,
+        uri_path: &str,
+    ) -> Result<Resolved, Error> {
+        for (index, candidate) in self.candidates.iter().enumerate() {
+            let template = match candidate {
+                TryFilesEntry::Status(status) => {
+                    let status =
+                        StatusCode::from_u16(*status).map_err(|_| ErrorKind::InvalidData)?;
+                    return Ok(Resolved::Status(status));
+                }
+                TryFilesEntry::Path(template) => template,
+            };
+
+            let candidate_uri = template.replace("$uri", uri_path);
+            let path = match storage.resolve(&candidate_uri).await {
+                Ok(path) => path,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            let is_last = index + 1 == self.candidates.len();
+            let kind = storage.stat(&path).await?.kind;
+            let expected = if template.ends_with('/') {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            };
+            if is_last || kind == expected {
+                debug!("try_files candidate {template} matched: {path:?}");
+                return Ok(Resolved::Path(path, candidate.is_request_uri()));
+            }
+        }
+
+        Err(ErrorKind::NotFound.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use test_log::test;
+
+    use crate::storage::LocalStorage;
+
+    fn rule(pattern: Option<&str>, candidates: &[&str]) -> TryFilesRules {
+        TryFilesRules::new([TryFilesRule {
+            pattern: pattern.map(|pattern| pattern.to_owned()),
+            candidates: candidates
+                .iter()
+                .map(|candidate| candidate.parse().unwrap())
+                .collect::<Vec<_>>()
+                .into(),
+        }])
+        .unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn resolution() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("about.html"), "About").unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/index.html"), "Docs").unwrap();
+        fs::write(dir.path().join("fallback.html"), "Fallback").unwrap();
+        let storage = LocalStorage::new(dir.path()).unwrap();
+        let root = storage.root().to_path_buf();
+
+        let clean_urls = rule(None, &["$uri", "$uri.html", "$uri/", "=404"]);
+        let clean_urls = clean_urls.find("/anything").unwrap();
+        assert_eq!(
+            clean_urls.resolve(&storage, "/about").await.unwrap(),
+            Resolved::Path(root.join("about.html"), false)
+        );
+        assert_eq!(
+            clean_urls.resolve(&storage, "/about.html").await.unwrap(),
+            Resolved::Path(root.join("about.html"), true)
+        );
+        assert_eq!(
+            clean_urls.resolve(&storage, "/docs").await.unwrap(),
+            Resolved::Path(root.join("docs"), true)
+        );
+        assert_eq!(
+            clean_urls.resolve(&storage, "/missing").await.unwrap(),
+            Resolved::Status(StatusCode::NOT_FOUND)
+        );
+
+        let spa = rule(Some("/app/**"), &["$uri", "/fallback.html"]);
+        assert!(spa.find("/app/route/x").is_some());
+        assert!(spa.find("/app").is_some());
+        assert!(spa.find("/about/../app/route").is_some());
+        assert!(spa.find("/%61pp/route").is_some());
+        assert!(spa.find("/about").is_none());
+        let spa = spa.find("/app/route").unwrap();
+        assert_eq!(
+            spa.resolve(&storage, "/app/route").await.unwrap(),
+            Resolved::Path(root.join("fallback.html"), false)
+        );
+
+        let no_fallback = rule(None, &["$uri.html"]);
+        let no_fallback = no_fallback.find("/missing").unwrap();
+        assert_eq!(
+            no_fallback.resolve(&storage, "/missing").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}